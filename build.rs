@@ -16,6 +16,9 @@ const TRACERS_BPF_HEADER: &str = "./src/bpf/tracers.h";
 const TRACERS_BPF_SOURCE: &str = "./src/bpf/tracers.bpf.c";
 const TRACERS_SKELETON: &str = "./src/bpf/tracers_skel.rs";
 
+const RUNQUEUE_BPF_SOURCE: &str = "./src/bpf/runqueue.bpf.c";
+const RUNQUEUE_SKELETON: &str = "./src/bpf/runqueue_skel.rs";
+
 #[derive(Debug)]
 struct CustomParseCallbacks;
 
@@ -86,4 +89,16 @@ fn main() {
         ])
         .build_and_generate(skel)
         .expect("run skeleton builder");
+
+    let skel = Path::new(RUNQUEUE_SKELETON);
+    SkeletonBuilder::new()
+        .source(RUNQUEUE_BPF_SOURCE)
+        .clang_args([
+            "-Wextra",
+            "-Wall",
+            "-Werror",
+            "-Wno-unused-command-line-argument",
+        ])
+        .build_and_generate(skel)
+        .expect("run skeleton builder");
 }
@@ -75,12 +75,14 @@ fn tracefs_mount_detected() -> bool {
     Path::new(TRACEFS_PATH).exists()
 }
 
-fn get_trace_sched_event_id(trace_event: &str) -> Result<u32> {
+/// Returns the id of the given tracepoint, which is used to open perf events for it.
+/// Fails if the tracepoint doesn't exist.
+pub fn get_trace_event_id(category: &str, trace_event: &str) -> Result<u32> {
     if !tracefs_mount_detected() {
         return Err(SystemInfoError::ErrorTracefsNotMounted.into());
     }
 
-    let event_id_path = format!("{TRACEFS_PATH}/events/sched/{trace_event}/id");
+    let event_id_path = format!("{TRACEFS_PATH}/events/{category}/{trace_event}/id");
     let path = Path::new(&event_id_path);
     if !path.exists() {
         return Err(SystemInfoError::ErrorOpeningFile(event_id_path).into());
@@ -98,6 +100,23 @@ fn get_trace_sched_event_id(trace_event: &str) -> Result<u32> {
         })
 }
 
+/// Whether the kernel function can be traced with a kprobe.
+pub fn kprobe_function_exists(function: &str) -> Result<bool> {
+    if !tracefs_mount_detected() {
+        return Err(SystemInfoError::ErrorTracefsNotMounted.into());
+    }
+
+    let functions_path = format!("{TRACEFS_PATH}/available_filter_functions");
+    let functions = read_to_string(&functions_path)
+        .map_err(|_| SystemInfoError::ErrorOpeningFile(functions_path))?;
+
+    // Functions in modules are followed by the module name, e.g. `nf_hook_slow [nf_tables]`.
+    Ok(functions
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|name| name == function))
+}
+
 fn software_perfevents_detected() -> bool {
     let mut attrs: perf_event_attr = perf_event_open_sys::bindings::perf_event_attr {
         size: std::mem::size_of::<sys::bindings::perf_event_attr>() as u32,
@@ -136,7 +155,7 @@ fn tracepoints_detected() -> bool {
         ..sys::bindings::perf_event_attr::default()
     };
 
-    match get_trace_sched_event_id("sched_process_exec") {
+    match get_trace_event_id("sched", "sched_process_exec") {
         Ok(event_id) => attrs.config = event_id as u64,
         Err(err) => {
             error!("{}", err);
@@ -327,4 +346,26 @@ mod tests {
         assert!(bpf_features.has_map_of_maps);
         assert!(bpf_features.has_batch_map_operations);
     }
+
+    #[test]
+    fn test_trace_events() {
+        // Tracefs isn't available everywhere, such as in some containers.
+        if !tracefs_mount_detected() {
+            return;
+        }
+
+        assert!(get_trace_event_id("sched", "sched_switch").is_ok());
+        assert!(get_trace_event_id("sched", "sched_does_not_exist").is_err());
+
+        // Which functions can be probed depends on the kernel, so use one it lists.
+        let functions =
+            read_to_string(format!("{TRACEFS_PATH}/available_filter_functions")).unwrap();
+        if let Some(function) = functions
+            .lines()
+            .find_map(|line| line.split_whitespace().next())
+        {
+            assert!(kprobe_function_exists(function).unwrap());
+        }
+        assert!(!kprobe_function_exists("this_function_does_not_exist").unwrap());
+    }
 }
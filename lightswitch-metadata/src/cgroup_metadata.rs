@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::libc;
+
+use crate::types::MetadataLabel;
+
+const CGROUP_V2_ROOT: &str = "/sys/fs/cgroup";
+/// `FILEID_KERNFS`, the type of the file handles of cgroupfs.
+const FILEID_KERNFS: libc::c_int = 0xfe;
+/// How often the cgroup hierarchy can be rescanned when cgroups can't be opened by id.
+const MIN_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `struct file_handle` of cgroupfs, which holds the cgroup id.
+#[repr(C)]
+struct CgroupFileHandle {
+    handle_bytes: libc::c_uint,
+    handle_type: libc::c_int,
+    cgroup_id: u64,
+}
+
+impl CgroupFileHandle {
+    fn new(cgroup_id: u64) -> Self {
+        Self {
+            handle_bytes: std::mem::size_of::<u64>() as libc::c_uint,
+            handle_type: FILEID_KERNFS,
+            cgroup_id,
+        }
+    }
+}
+
+/// Opens the cgroup with the given id, which must be in the hierarchy of `root_dir`.
+/// Requires `CAP_DAC_READ_SEARCH`.
+fn open_by_id(root_dir: &File, cgroup_id: u64) -> Option<OwnedFd> {
+    let mut handle = CgroupFileHandle::new(cgroup_id);
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_by_handle_at,
+            root_dir.as_raw_fd(),
+            &mut handle as *mut CgroupFileHandle,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+    Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Opens the cgroup root if cgroups can be opened by id, which is the case for
+/// cgroup v2 hierarchies, whose file handles are the cgroup ids.
+fn open_root_by_id(root: &Path) -> Option<File> {
+    let root_dir = File::open(root).ok()?;
+    let mut handle = CgroupFileHandle::new(0);
+    let mut mount_id: libc::c_int = 0;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            root_dir.as_raw_fd(),
+            c"".as_ptr(),
+            &mut handle as *mut CgroupFileHandle,
+            &mut mount_id as *mut libc::c_int,
+            libc::AT_EMPTY_PATH,
+        )
+    };
+    if ret != 0 || handle.handle_type != FILEID_KERNFS {
+        return None;
+    }
+
+    open_by_id(&root_dir, handle.cgroup_id)?;
+    Some(root_dir)
+}
+
+/// Resolves cgroup v2 ids, which are the inode numbers of the cgroup directories,
+/// to their paths relative to the cgroup root.
+pub struct CgroupMetadata {
+    root: PathBuf,
+    /// Set when cgroups can be opened by id, so that they are resolved without
+    /// scanning the hierarchy.
+    root_dir: Option<File>,
+    id_to_path: HashMap<u64, String>,
+    last_scan: Option<Instant>,
+}
+
+impl Default for CgroupMetadata {
+    fn default() -> Self {
+        Self::new(Path::new(CGROUP_V2_ROOT))
+    }
+}
+
+impl CgroupMetadata {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            root_dir: open_root_by_id(root),
+            id_to_path: HashMap::new(),
+            last_scan: None,
+        }
+    }
+
+    /// Returns the `cgroup.path` label for the given cgroup id, if it exists.
+    pub fn get_metadata(&mut self, cgroup_id: u64) -> Vec<MetadataLabel> {
+        if !self.id_to_path.contains_key(&cgroup_id) {
+            if let Some(root_dir) = &self.root_dir {
+                if let Some(path) = self.resolve(root_dir, cgroup_id) {
+                    self.id_to_path.insert(cgroup_id, path);
+                }
+            } else if self
+                .last_scan
+                .is_none_or(|last_scan| last_scan.elapsed() >= MIN_SCAN_INTERVAL)
+            {
+                // The cgroup might have been created after the last scan.
+                self.id_to_path.clear();
+                let root = self.root.clone();
+                self.scan(&root);
+                self.last_scan = Some(Instant::now());
+            }
+        }
+
+        match self.id_to_path.get(&cgroup_id) {
+            Some(path) => vec![MetadataLabel::from_string_value(
+                "cgroup.path".into(),
+                path.clone(),
+            )],
+            None => vec![],
+        }
+    }
+
+    fn resolve(&self, root_dir: &File, cgroup_id: u64) -> Option<String> {
+        let cgroup = open_by_id(root_dir, cgroup_id)?;
+        let path = fs::read_link(format!("/proc/self/fd/{}", cgroup.as_raw_fd())).ok()?;
+        let relative_path = path.strip_prefix(&self.root).ok()?;
+        Some(format!("/{}", relative_path.display()))
+    }
+
+    fn scan(&mut self, dir: &Path) {
+        let Ok(metadata) = fs::metadata(dir) else {
+            return;
+        };
+        let relative_path = dir.strip_prefix(&self.root).unwrap_or(dir);
+        self.id_to_path
+            .insert(metadata.ino(), format!("/{}", relative_path.display()));
+
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                self.scan(&entry.path());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MetadataLabelValue;
+
+    #[test]
+    fn test_cgroup_path_resolution() {
+        // Given
+        let root = std::env::temp_dir().join(format!("cgroup-metadata-{}", std::process::id()));
+        let child = root.join("system.slice").join("my.service");
+        fs::create_dir_all(&child).unwrap();
+        let mut cgroup_metadata = CgroupMetadata::new(&root);
+
+        // When
+        let labels = cgroup_metadata.get_metadata(fs::metadata(&child).unwrap().ino());
+        let root_labels = cgroup_metadata.get_metadata(fs::metadata(&root).unwrap().ino());
+
+        // Then
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[0].key, "cgroup.path");
+        assert_eq!(
+            labels[0].value,
+            MetadataLabelValue::String("/system.slice/my.service".into())
+        );
+        assert_eq!(root_labels[0].value, MetadataLabelValue::String("/".into()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_own_cgroup_path_resolution() {
+        // Given
+        let mounts = fs::read_to_string("/proc/self/mounts").unwrap();
+        let Some(root) = mounts
+            .lines()
+            .map(|mount| mount.split(' ').collect::<Vec<_>>())
+            .find(|fields| fields.get(2) == Some(&"cgroup2"))
+            .map(|fields| PathBuf::from(fields[1]))
+        else {
+            return;
+        };
+        let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap();
+        let path = cgroups
+            .lines()
+            .find_map(|cgroup| cgroup.strip_prefix("0::"))
+            .unwrap();
+        let cgroup_id = fs::metadata(root.join(path.trim_start_matches('/')))
+            .unwrap()
+            .ino();
+        let mut cgroup_metadata = CgroupMetadata::new(&root);
+
+        // When
+        let labels = cgroup_metadata.get_metadata(cgroup_id);
+
+        // Then
+        assert_eq!(labels[0].value, MetadataLabelValue::String(path.into()));
+    }
+}
@@ -1,3 +1,4 @@
+pub mod cgroup_metadata;
 pub mod metadata_provider;
 pub mod system_metadata;
 pub mod task_metadata;
@@ -1,3 +1,4 @@
+use crate::cgroup_metadata::CgroupMetadata;
 use crate::system_metadata::SystemMetadata;
 use crate::task_metadata::TaskMetadata;
 use crate::types::{MetadataLabel, SystemMetadataProvider, TaskKey, TaskMetadataProvider};
@@ -11,6 +12,7 @@ pub struct GlobalMetadataProvider {
     process_label_cache: LruCache<TaskKey, Vec<MetadataLabel>>,
     default_task_metadata: TaskMetadata,
     default_system_metadata: SystemMetadata,
+    cgroup_metadata: CgroupMetadata,
     custom_system_metadata_providers: Vec<Box<dyn SystemMetadataProvider + Send>>,
     custom_task_metadata_providers: Vec<Box<dyn TaskMetadataProvider + Send>>,
 }
@@ -33,6 +35,7 @@ impl GlobalMetadataProvider {
             process_label_cache: LruCache::new(metadata_cache_size),
             default_task_metadata: TaskMetadata {},
             default_system_metadata: SystemMetadata {},
+            cgroup_metadata: CgroupMetadata::default(),
             custom_system_metadata_providers: system_metadata_providers,
             custom_task_metadata_providers: task_metadata_providers,
         }
@@ -104,6 +107,11 @@ impl GlobalMetadataProvider {
         }
     }
 
+    /// Labels for the cgroup v2 with the given id, such as its path.
+    pub fn get_cgroup_metadata(&mut self, cgroup_id: u64) -> Vec<MetadataLabel> {
+        self.cgroup_metadata.get_metadata(cgroup_id)
+    }
+
     pub fn register_task(&mut self, task_key: TaskKey) {
         if !self.process_label_cache.contains(&task_key) {
             let labels = self.get_labels(task_key);
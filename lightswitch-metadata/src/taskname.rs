@@ -25,6 +25,14 @@ impl TaskName {
             current_thread: thread_name,
         })
     }
+
+    /// `comm` of a kernel thread. The idle task, which has no entry in procfs, is always task 0.
+    pub fn for_kernel_task(task_id: i32) -> Result<String, anyhow::Error> {
+        if task_id == 0 {
+            return Ok("swapper".into());
+        }
+        Ok(procfs::process::Process::new(task_id)?.stat()?.comm)
+    }
 }
 
 #[cfg(test)]
@@ -51,6 +59,11 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_kernel_task_name() {
+        assert_eq!(TaskName::for_kernel_task(0).unwrap(), "swapper");
+    }
+
     #[test]
     fn test_errored() {
         // Given
@@ -1,12 +1,16 @@
 mod buildid;
 pub mod kernel;
 mod object;
+mod usdt;
 
 pub use object::ElfLoad;
 pub use object::ObjectFile;
 pub use object::Runtime;
 pub use object::StopUnwindingFrames;
 pub use object::code_hash;
+pub use usdt::UsdtNote;
+pub use usdt::UsdtProbe;
+pub use usdt::parse_stapsdt_notes;
 
 pub use buildid::BuildId;
 pub use buildid::ExecutableId;
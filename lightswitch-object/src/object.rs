@@ -17,6 +17,7 @@ use object::elf::{FileHeader32, FileHeader64, PF_X, PT_LOAD};
 use object::read::elf::FileHeader;
 use object::read::elf::ProgramHeader;
 
+use crate::usdt::{UsdtProbe, parse_stapsdt_notes};
 use crate::{BuildId, ExecutableId};
 
 /// Elf load segments used during address normalization to find the segment
@@ -204,6 +205,77 @@ impl ObjectFile {
         r
     }
 
+    /// Returns the file offset of the function defined with the given name, which is
+    /// what uprobes are attached to. Dynamic symbols are looked up first as they are
+    /// present even if the executable has been stripped.
+    pub fn symbol_file_offset(&self, name: &str) -> Option<u64> {
+        let symbol = self
+            .object
+            .dynamic_symbols()
+            .chain(self.object.symbols())
+            .find(|symbol| symbol.is_definition() && symbol.name() == Ok(name))?;
+
+        self.address_to_file_offset(symbol.address())
+    }
+
+    /// Returns the USDT probes found in the `.note.stapsdt` section, if any.
+    pub fn usdt_probes(&self) -> Result<Vec<UsdtProbe>> {
+        let Some(section) = self.object.section_by_name(".note.stapsdt") else {
+            return Ok(Vec::new());
+        };
+        let endian = if self.object.is_little_endian() {
+            Endianness::Little
+        } else {
+            Endianness::Big
+        };
+        let notes = parse_stapsdt_notes(section.data()?, endian, self.object.is_64())?;
+        let stapsdt_base = self
+            .object
+            .section_by_name(".stapsdt.base")
+            .map(|section| section.address());
+
+        let mut probes = Vec::new();
+        for note in notes {
+            let location = match stapsdt_base {
+                Some(stapsdt_base) if note.base != 0 => note
+                    .location
+                    .wrapping_add(stapsdt_base)
+                    .wrapping_sub(note.base),
+                _ => note.location,
+            };
+            let Some(file_offset) = self.address_to_file_offset(location) else {
+                return Err(anyhow!(
+                    "probe {}:{} is not in any section",
+                    note.provider,
+                    note.name
+                ));
+            };
+            let semaphore_file_offset = if note.semaphore == 0 {
+                None
+            } else {
+                self.address_to_file_offset(note.semaphore)
+            };
+
+            probes.push(UsdtProbe {
+                provider: note.provider,
+                name: note.name,
+                file_offset,
+                semaphore_file_offset,
+            });
+        }
+
+        Ok(probes)
+    }
+
+    /// Converts a virtual address to an offset in the file, using the section it falls into.
+    fn address_to_file_offset(&self, address: u64) -> Option<u64> {
+        self.object.sections().find_map(|section| {
+            let (file_offset, _) = section.file_range()?;
+            (address >= section.address() && address < section.address() + section.size())
+                .then(|| file_offset + address - section.address())
+        })
+    }
+
     /// Retrieves the executable load segments. These are used to convert
     /// virtual addresses to offsets in an executable during unwinding
     /// and symbolization.
@@ -0,0 +1,142 @@
+use anyhow::{Result, anyhow};
+use object::Endianness;
+use object::elf::FileHeader32;
+use object::read::elf::NoteIterator;
+
+const STAPSDT_NOTE_NAME: &[u8] = b"stapsdt";
+const STAPSDT_NOTE_TYPE: u32 = 3;
+
+/// A USDT probe as described by a `.note.stapsdt` ELF note. See
+/// <https://sourceware.org/systemtap/wiki/UserSpaceProbeImplementation>.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsdtNote {
+    pub provider: String,
+    pub name: String,
+    /// Link time address of the probe location.
+    pub location: u64,
+    /// Link time address of the `.stapsdt.base` section. If the executable has been
+    /// prelinked, the location has to be adjusted by the difference with the actual one.
+    pub base: u64,
+    /// Address of the counter that enables the probe when non-zero. Zero if the
+    /// probe is always enabled.
+    pub semaphore: u64,
+    /// Description of the probe arguments, such as `-4@%edi 8@%rsi`.
+    pub arguments: String,
+}
+
+/// A USDT probe with the file offsets needed to attach a uprobe to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsdtProbe {
+    pub provider: String,
+    pub name: String,
+    pub file_offset: u64,
+    /// File offset of the semaphore, which the kernel bumps while the uprobe is attached.
+    pub semaphore_file_offset: Option<u64>,
+}
+
+/// Parses the contents of the `.note.stapsdt` section.
+pub fn parse_stapsdt_notes(data: &[u8], endian: Endianness, is_64: bool) -> Result<Vec<UsdtNote>> {
+    let address_size = if is_64 { 8 } else { 4 };
+    let read_address = |desc: &[u8], index: usize| -> Result<u64> {
+        let bytes = desc
+            .get(index * address_size..(index + 1) * address_size)
+            .ok_or(anyhow!("stapsdt note too short"))?;
+        Ok(match (is_64, endian) {
+            (true, Endianness::Little) => u64::from_le_bytes(bytes.try_into()?),
+            (true, Endianness::Big) => u64::from_be_bytes(bytes.try_into()?),
+            (false, Endianness::Little) => u32::from_le_bytes(bytes.try_into()?) as u64,
+            (false, Endianness::Big) => u32::from_be_bytes(bytes.try_into()?) as u64,
+        })
+    };
+
+    let notes: NoteIterator<'_, FileHeader32<Endianness>> = NoteIterator::new(endian, 4, data)?;
+    let mut usdt_notes = Vec::new();
+
+    for note in notes {
+        let note = note?;
+        if note.name() != STAPSDT_NOTE_NAME || note.n_type(endian) != STAPSDT_NOTE_TYPE {
+            continue;
+        }
+
+        let desc = note.desc();
+        let location = read_address(desc, 0)?;
+        let base = read_address(desc, 1)?;
+        let semaphore = read_address(desc, 2)?;
+
+        let mut strings = desc[3 * address_size..]
+            .split(|&byte| byte == 0)
+            .map(|string| String::from_utf8_lossy(string).to_string());
+        let (Some(provider), Some(name)) = (strings.next(), strings.next()) else {
+            return Err(anyhow!("stapsdt note without provider or name"));
+        };
+
+        usdt_notes.push(UsdtNote {
+            provider,
+            name,
+            location,
+            base,
+            semaphore,
+            arguments: strings.next().unwrap_or_default(),
+        });
+    }
+
+    Ok(usdt_notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stapsdt_note(location: u64, semaphore: u64, strings: &[u8]) -> Vec<u8> {
+        let mut desc = Vec::new();
+        desc.extend(location.to_le_bytes());
+        desc.extend(0x1000_u64.to_le_bytes());
+        desc.extend(semaphore.to_le_bytes());
+        desc.extend(strings);
+
+        let mut note = Vec::new();
+        note.extend(8_u32.to_le_bytes());
+        note.extend((desc.len() as u32).to_le_bytes());
+        note.extend(STAPSDT_NOTE_TYPE.to_le_bytes());
+        note.extend(b"stapsdt\0");
+        note.extend(&desc);
+        note.resize(note.len().next_multiple_of(4), 0);
+        note
+    }
+
+    #[test]
+    fn test_parse_stapsdt_notes() {
+        let mut data = stapsdt_note(0x1139, 0, b"myapp\0request_start\0-4@%edi\0");
+        data.extend(stapsdt_note(0x1150, 0x4010, b"myapp\0request_end\0\0"));
+
+        assert_eq!(
+            parse_stapsdt_notes(&data, Endianness::Little, true).unwrap(),
+            vec![
+                UsdtNote {
+                    provider: "myapp".into(),
+                    name: "request_start".into(),
+                    location: 0x1139,
+                    base: 0x1000,
+                    semaphore: 0,
+                    arguments: "-4@%edi".into(),
+                },
+                UsdtNote {
+                    provider: "myapp".into(),
+                    name: "request_end".into(),
+                    location: 0x1150,
+                    base: 0x1000,
+                    semaphore: 0x4010,
+                    arguments: "".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_stapsdt_notes_too_short() {
+        let data = stapsdt_note(0x1139, 0, b"");
+        let len = data.len();
+
+        assert!(parse_stapsdt_notes(&data[..len - 8], Endianness::Little, true).is_err());
+    }
+}
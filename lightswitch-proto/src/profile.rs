@@ -14,6 +14,11 @@ pub struct PprofBuilder {
     time_nanos: i64,
     duration: Duration,
     freq_in_hz: i64,
+    /// Type and unit of the second sample value, as well as the profile's period.
+    period_type: (String, String),
+    period: i64,
+    /// Type and unit of every sample value, when they aren't the count and the period.
+    sample_types: Option<Vec<(String, String)>>,
 
     known_mappings: HashMap<u64, u64>,
     mappings: Vec<pprof::Mapping>,
@@ -29,6 +34,8 @@ pub struct PprofBuilder {
     pub functions: Vec<pprof::Function>,
 
     samples: Vec<pprof::Sample>,
+
+    comments: Vec<String>,
 }
 
 pub enum LabelStringOrNumber {
@@ -70,6 +77,9 @@ impl PprofBuilder {
                 .as_nanos() as i64,
             duration,
             freq_in_hz: freq_in_hz as i64,
+            period_type: ("cpu".to_string(), "nanoseconds".to_string()),
+            period: 1_000_000_000 / freq_in_hz as i64,
+            sample_types: None,
 
             known_mappings: HashMap::new(),
             mappings: Vec::new(),
@@ -84,6 +94,8 @@ impl PprofBuilder {
             functions: Vec::new(),
 
             samples: Vec::new(),
+
+            comments: Vec::new(),
         }
     }
 
@@ -288,6 +300,63 @@ impl PprofBuilder {
         self.samples.push(sample);
     }
 
+    /// Overrides the type and unit of the second value of every sample, which
+    /// defaults to the CPU time derived from the sampling frequency.
+    pub fn set_period_type(&mut self, r#type: &str, unit: &str, period: i64) {
+        self.period_type = (r#type.to_string(), unit.to_string());
+        self.period = period;
+    }
+
+    /// Adds a sample whose second value is the given weight rather than one derived
+    /// from the sampling frequency.
+    pub fn add_weighted_sample(
+        &mut self,
+        location_ids: Vec<u64>,
+        count: i64,
+        weight: i64,
+        labels: &[pprof::Label],
+    ) {
+        let sample = pprof::Sample {
+            location_id: location_ids, // from the source code: `The leaf is at location_id\[0\].`
+            value: vec![count, weight],
+            label: labels.to_vec(),
+        };
+
+        self.samples.push(sample);
+    }
+
+    /// Overrides the type and unit of every sample value, for profiles with more
+    /// than two values per sample. Samples must be added with [`Self::add_sample_values`].
+    pub fn set_sample_types(&mut self, sample_types: &[(&str, &str)]) {
+        self.sample_types = Some(
+            sample_types
+                .iter()
+                .map(|(r#type, unit)| (r#type.to_string(), unit.to_string()))
+                .collect(),
+        );
+    }
+
+    /// Adds a sample with one value per sample type.
+    pub fn add_sample_values(
+        &mut self,
+        location_ids: Vec<u64>,
+        values: Vec<i64>,
+        labels: &[pprof::Label],
+    ) {
+        let sample = pprof::Sample {
+            location_id: location_ids, // from the source code: `The leaf is at location_id\[0\].`
+            value: values,
+            label: labels.to_vec(),
+        };
+
+        self.samples.push(sample);
+    }
+
+    /// Adds a free-form note, shown by `pprof -comments`.
+    pub fn add_comment(&mut self, comment: &str) {
+        self.comments.push(comment.to_string());
+    }
+
     pub fn new_label(&mut self, key: &str, value: LabelStringOrNumber) -> pprof::Label {
         let mut label = pprof::Label {
             key: self.get_or_insert_string(key),
@@ -313,18 +382,33 @@ impl PprofBuilder {
             unit: self.get_or_insert_string("count"),
         };
 
+        let (period_type, period_unit) = self.period_type.clone();
         let period_type = pprof::ValueType {
-            r#type: self.get_or_insert_string("cpu"),
-            unit: self.get_or_insert_string("nanoseconds"),
+            r#type: self.get_or_insert_string(&period_type),
+            unit: self.get_or_insert_string(&period_unit),
+        };
+
+        let sample_types = match self.sample_types.take() {
+            Some(sample_types) => sample_types
+                .iter()
+                .map(|(r#type, unit)| pprof::ValueType {
+                    r#type: self.get_or_insert_string(r#type),
+                    unit: self.get_or_insert_string(unit),
+                })
+                .collect(),
+            None => vec![sample_type, period_type],
         };
 
         // Used to identify profiles generated by lightswitch.
         // This is useful because the mapping ID is used in a non-standard way
         // which should not be interpreted like this by other pprof sources.
-        let comments = vec![self.get_or_insert_string("lightswitch")];
+        let mut comments = vec![self.get_or_insert_string("lightswitch")];
+        for comment in std::mem::take(&mut self.comments) {
+            comments.push(self.get_or_insert_string(&comment));
+        }
 
         pprof::Profile {
-            sample_type: vec![sample_type, period_type],
+            sample_type: sample_types,
             sample: self.samples,
             mapping: self.mappings,
             location: self.locations,
@@ -335,7 +419,7 @@ impl PprofBuilder {
             time_nanos: self.time_nanos,
             duration_nanos: self.duration.as_nanos() as i64,
             period_type: Some(period_type),
-            period: 1_000_000_000 / self.freq_in_hz,
+            period: self.period,
             comment: comments,
             default_sample_type: 0,
         }
@@ -445,6 +529,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_weighted_sample() {
+        let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 27);
+        pprof.set_period_type("off_cpu", "nanoseconds", 1);
+        pprof.add_weighted_sample(vec![1, 2, 3], 2, 5_000, &[]);
+
+        assert_eq!(pprof.samples[0].value, vec![2, 5_000]);
+
+        let profile = pprof.build();
+        let period_type = profile.period_type.unwrap();
+        assert_eq!(profile.string_table[period_type.r#type as usize], "off_cpu");
+        assert_eq!(
+            profile.string_table[period_type.unit as usize],
+            "nanoseconds"
+        );
+        assert_eq!(profile.sample_type[1], period_type);
+        assert_eq!(profile.period, 1);
+    }
+
+    #[test]
+    fn test_sample_types() {
+        let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 27);
+        pprof.set_period_type("space", "bytes", 1);
+        pprof.set_sample_types(&[("alloc_space", "bytes"), ("inuse_space", "bytes")]);
+        pprof.add_sample_values(vec![1, 2, 3], vec![0, 4096], &[]);
+
+        let profile = pprof.build();
+        let sample_types: Vec<_> = profile
+            .sample_type
+            .iter()
+            .map(|value_type| {
+                (
+                    profile.string_table[value_type.r#type as usize].as_str(),
+                    profile.string_table[value_type.unit as usize].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            sample_types,
+            vec![("alloc_space", "bytes"), ("inuse_space", "bytes")]
+        );
+        assert_eq!(profile.sample[0].value, vec![0, 4096]);
+        let period_type = profile.period_type.unwrap();
+        assert_eq!(profile.string_table[period_type.r#type as usize], "space");
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 27);
+        pprof.add_comment("sample_freq_hz=27");
+
+        let profile = pprof.build();
+        let comments: Vec<_> = profile
+            .comment
+            .iter()
+            .map(|id| profile.string_table[*id as usize].as_str())
+            .collect();
+        assert_eq!(comments, vec!["lightswitch", "sample_freq_hz=27"]);
+    }
+
     #[test]
     fn test_profile() {
         let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 27);
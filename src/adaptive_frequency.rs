@@ -0,0 +1,289 @@
+//! Adjusts the sampling frequency at runtime to keep the overhead of the profiler
+//! within a CPU budget.
+
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use primal::is_prime;
+
+/// Bounds and budget within which the sampling frequency is adjusted. The bounds
+/// must be prime, like any sampling frequency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveFrequencyConfig {
+    pub min_sample_freq: u64,
+    pub max_sample_freq: u64,
+    /// CPU time the profiler may use, as a percentage of a single CPU.
+    pub cpu_budget_pct: f64,
+}
+
+/// Overhead of the profiler during the last session.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Overhead {
+    /// CPU time used by the profiler, as a percentage of a single CPU.
+    pub cpu_usage_pct: f64,
+    /// Samples dropped because the ring or perf buffers were full.
+    pub lost_samples: u64,
+    /// Stacks the BPF unwinder worked on per second.
+    pub unwinds_per_second: f64,
+}
+
+/// Turns the cumulative counters of the profiler into the overhead since the
+/// previous measurement.
+pub struct OverheadMeter {
+    measured_at: Instant,
+    cpu_time: Duration,
+    unwinds: u64,
+    lost_samples: u64,
+}
+
+impl OverheadMeter {
+    pub fn new(now: Instant, cpu_time: Duration, unwinds: u64, lost_samples: u64) -> Self {
+        Self {
+            measured_at: now,
+            cpu_time,
+            unwinds,
+            lost_samples,
+        }
+    }
+
+    pub fn measure(
+        &mut self,
+        now: Instant,
+        cpu_time: Duration,
+        unwinds: u64,
+        lost_samples: u64,
+    ) -> Overhead {
+        let elapsed = now.duration_since(self.measured_at).as_secs_f64();
+        let overhead = if elapsed == 0.0 {
+            Overhead::default()
+        } else {
+            Overhead {
+                cpu_usage_pct: 100.0 * cpu_time.saturating_sub(self.cpu_time).as_secs_f64()
+                    / elapsed,
+                lost_samples: lost_samples.saturating_sub(self.lost_samples),
+                unwinds_per_second: unwinds.saturating_sub(self.unwinds) as f64 / elapsed,
+            }
+        };
+        *self = Self::new(now, cpu_time, unwinds, lost_samples);
+        overhead
+    }
+}
+
+/// CPU time used by the profiler so far, across all its threads. This doesn't
+/// include its BPF programs, which run on behalf of the tasks they sample, see
+/// [`bpf_run_time`].
+pub fn profiler_cpu_time() -> Result<Duration, procfs::ProcError> {
+    let stat = procfs::process::Process::myself()?.stat()?;
+    let ticks = stat.utime + stat.stime;
+    Ok(Duration::from_secs_f64(
+        ticks as f64 / procfs::ticks_per_second() as f64,
+    ))
+}
+
+/// Keeps the kernel accounting the time spent in BPF programs for as long as
+/// it's alive, as it has a cost and is disabled by default.
+pub struct BpfStats {
+    _fd: OwnedFd,
+}
+
+impl BpfStats {
+    pub fn enable() -> Result<Self, io::Error> {
+        let fd = unsafe { libbpf_sys::bpf_enable_stats(libbpf_sys::BPF_STATS_RUN_TIME) };
+        if fd < 0 {
+            return Err(io::Error::from_raw_os_error(-fd));
+        }
+        Ok(Self {
+            _fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+}
+
+/// Time spent running the given BPF programs while the stats were enabled with
+/// [`BpfStats`]. Programs whose information can't be read are skipped.
+pub fn bpf_run_time<'a>(prog_fds: impl IntoIterator<Item = BorrowedFd<'a>>) -> Duration {
+    let mut run_time_ns = 0;
+    for prog_fd in prog_fds {
+        let mut info = libbpf_sys::bpf_prog_info::default();
+        let mut info_len = std::mem::size_of::<libbpf_sys::bpf_prog_info>() as u32;
+        let ret = unsafe {
+            libbpf_sys::bpf_prog_get_info_by_fd(prog_fd.as_raw_fd(), &mut info, &mut info_len)
+        };
+        if ret == 0 {
+            run_time_ns += info.run_time_ns;
+        }
+    }
+    Duration::from_nanos(run_time_ns)
+}
+
+pub struct FrequencyController {
+    config: AdaptiveFrequencyConfig,
+    sample_freq: u64,
+}
+
+impl FrequencyController {
+    pub fn new(config: AdaptiveFrequencyConfig, sample_freq: u64) -> Self {
+        let sample_freq = nearest_prime(sample_freq, &config);
+        Self {
+            config,
+            sample_freq,
+        }
+    }
+
+    pub fn sample_freq(&self) -> u64 {
+        self.sample_freq
+    }
+
+    /// Returns the frequency to sample at from now on, given the overhead since the
+    /// last update.
+    ///
+    /// The cost of the profiler grows roughly linearly with the number of stacks it
+    /// unwinds, so the frequency is scaled by how far the CPU usage is from the budget.
+    /// Changes are at most 2x per update to avoid overreacting to a noisy session.
+    pub fn update(&mut self, overhead: &Overhead) -> u64 {
+        let budget = self.config.cpu_budget_pct;
+        let current = self.sample_freq as f64;
+
+        let target = if overhead.lost_samples > 0 {
+            // Userspace can't keep up, back off regardless of the CPU usage.
+            current / 2.0
+        } else if overhead.unwinds_per_second == 0.0 {
+            // Nothing was sampled so the CPU usage says nothing about the cost of sampling.
+            current
+        } else if overhead.cpu_usage_pct > budget || overhead.cpu_usage_pct < budget / 2.0 {
+            // Aim a bit below the budget to leave some headroom.
+            (current * 0.8 * budget / overhead.cpu_usage_pct).clamp(current / 2.0, current * 2.0)
+        } else {
+            current
+        };
+
+        self.sample_freq = nearest_prime(target.round() as u64, &self.config);
+        self.sample_freq
+    }
+}
+
+/// Returns the prime closest to `sample_freq` within the bounds of the config,
+/// picking the lower one on ties. Prime frequencies avoid sampling in lockstep
+/// with periodic activity, which would skew the profiles.
+fn nearest_prime(sample_freq: u64, config: &AdaptiveFrequencyConfig) -> u64 {
+    let sample_freq = sample_freq.clamp(config.min_sample_freq, config.max_sample_freq);
+    for distance in 0..=(config.max_sample_freq - config.min_sample_freq) {
+        let lower = sample_freq.saturating_sub(distance);
+        if lower >= config.min_sample_freq && is_prime(lower) {
+            return lower;
+        }
+        let higher = sample_freq + distance;
+        if higher <= config.max_sample_freq && is_prime(higher) {
+            return higher;
+        }
+    }
+    sample_freq
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(sample_freq: u64) -> FrequencyController {
+        FrequencyController::new(
+            AdaptiveFrequencyConfig {
+                min_sample_freq: 11,
+                max_sample_freq: 997,
+                cpu_budget_pct: 3.0,
+            },
+            sample_freq,
+        )
+    }
+
+    #[test]
+    fn test_overhead_meter() {
+        let start = Instant::now();
+        let mut meter = OverheadMeter::new(start, Duration::from_millis(100), 1000, 5);
+
+        let overhead = meter.measure(
+            start + Duration::from_secs(2),
+            Duration::from_millis(200),
+            3000,
+            7,
+        );
+
+        assert!((overhead.cpu_usage_pct - 5.0).abs() < 1e-9);
+        assert_eq!(overhead.lost_samples, 2);
+        assert!((overhead.unwinds_per_second - 1000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_frequency_is_prime() {
+        assert_eq!(controller(100).sample_freq(), 101);
+        assert_eq!(controller(97).sample_freq(), 97);
+        assert_eq!(controller(1).sample_freq(), 11);
+        assert_eq!(controller(5000).sample_freq(), 997);
+    }
+
+    #[test]
+    fn test_frequency_within_budget_is_kept() {
+        let mut controller = controller(101);
+        let overhead = Overhead {
+            cpu_usage_pct: 2.0,
+            lost_samples: 0,
+            unwinds_per_second: 500.0,
+        };
+        assert_eq!(controller.update(&overhead), 101);
+    }
+
+    #[test]
+    fn test_frequency_over_budget_is_lowered() {
+        let mut controller = controller(101);
+        let overhead = Overhead {
+            cpu_usage_pct: 4.0,
+            lost_samples: 0,
+            unwinds_per_second: 500.0,
+        };
+        assert_eq!(controller.update(&overhead), 61);
+
+        let overhead = Overhead {
+            cpu_usage_pct: 100.0,
+            ..overhead
+        };
+        assert_eq!(controller.update(&overhead), 31);
+    }
+
+    #[test]
+    fn test_frequency_under_budget_is_raised() {
+        let mut controller = controller(101);
+        let overhead = Overhead {
+            cpu_usage_pct: 1.0,
+            lost_samples: 0,
+            unwinds_per_second: 500.0,
+        };
+        assert_eq!(controller.update(&overhead), 199);
+
+        controller = FrequencyController::new(controller.config.clone(), 797);
+        assert_eq!(controller.update(&overhead), 997);
+    }
+
+    #[test]
+    fn test_frequency_lowered_on_lost_samples() {
+        let mut controller = controller(101);
+        let overhead = Overhead {
+            cpu_usage_pct: 0.5,
+            lost_samples: 10,
+            unwinds_per_second: 500.0,
+        };
+        assert_eq!(controller.update(&overhead), 53);
+
+        controller = FrequencyController::new(controller.config.clone(), 17);
+        assert_eq!(controller.update(&overhead), 11);
+    }
+
+    #[test]
+    fn test_frequency_kept_without_samples() {
+        let mut controller = controller(101);
+        let overhead = Overhead {
+            cpu_usage_pct: 0.1,
+            lost_samples: 0,
+            unwinds_per_second: 0.0,
+        };
+        assert_eq!(controller.update(&overhead), 101);
+    }
+}
@@ -3,7 +3,8 @@ use std::{collections::HashMap, hash::Hash, hash::Hasher};
 
 use tracing::warn;
 
-use crate::profile::{RawAggregatedProfile, RawAggregatedSample, RawSample};
+use crate::process::Pid;
+use crate::profile::{RawAggregatedProfile, RawAggregatedSample, RawSample, SampleKind};
 
 #[derive(Default)]
 pub struct Aggregator {}
@@ -16,7 +17,8 @@ impl Aggregator {
 
         let mut sample_hash_to_aggregated: HashMap<u64, RawAggregatedSample> = HashMap::new();
         for sample in raw_samples {
-            if sample.ustack.is_empty() && sample.kstack.is_empty() {
+            if sample.ustack.is_empty() && sample.kstack.is_empty() && sample.unwind_error.is_none()
+            {
                 warn!(
                     "No stack present in provided sample={}, skipping...",
                     sample
@@ -30,34 +32,124 @@ impl Aggregator {
 
             sample_hash_to_aggregated
                 .entry(sample_hash)
-                .and_modify(|aggregated_sample| aggregated_sample.count += 1)
-                .or_insert(RawAggregatedSample { sample, count: 1 });
+                .and_modify(|aggregated_sample| {
+                    aggregated_sample.count += 1;
+                    aggregated_sample.weight += sample.weight;
+                })
+                .or_insert(RawAggregatedSample {
+                    weight: sample.weight,
+                    sample,
+                    count: 1,
+                });
         }
         sample_hash_to_aggregated.into_values().collect()
     }
 }
 
+/// Maximum number of allocations tracked at once. Each of them keeps its stack
+/// around, so programs that never free their memory would otherwise use an
+/// unbounded amount of it.
+const MAX_LIVE_ALLOCATIONS: usize = 100_000;
+
+/// Allocations that haven't been freed yet, keyed by process and address. They are
+/// kept across profiling sessions as memory can be freed at any point in the future.
+pub struct LiveAllocations {
+    allocations: HashMap<(Pid, u64), RawSample>,
+    max_allocations: usize,
+    /// Allocations that weren't tracked as there were too many.
+    untracked: u64,
+}
+
+impl Default for LiveAllocations {
+    fn default() -> Self {
+        Self::new(MAX_LIVE_ALLOCATIONS)
+    }
+}
+
+impl LiveAllocations {
+    pub fn new(max_allocations: usize) -> Self {
+        Self {
+            allocations: HashMap::new(),
+            max_allocations,
+            untracked: 0,
+        }
+    }
+
+    /// Applies the allocations and frees in the order they happened and returns the
+    /// samples that carry a stack, that is, everything but the frees.
+    pub fn update(&mut self, mut raw_samples: Vec<RawSample>) -> Vec<RawSample> {
+        // Perf buffers are per CPU so samples might not be received in order.
+        raw_samples.sort_by_key(|sample| sample.collected_at);
+        raw_samples.retain(|sample| match sample.kind {
+            SampleKind::Allocation => {
+                let key = (sample.pid, sample.address);
+                if self.allocations.len() < self.max_allocations
+                    || self.allocations.contains_key(&key)
+                {
+                    self.allocations.insert(key, sample.clone());
+                } else {
+                    if self.untracked == 0 {
+                        warn!(
+                            "tracking the maximum of {} live allocations, the memory in use will be underreported",
+                            self.max_allocations
+                        );
+                    }
+                    self.untracked += 1;
+                }
+                true
+            }
+            SampleKind::Free => {
+                self.allocations.remove(&(sample.pid, sample.address));
+                false
+            }
+            SampleKind::OnCpu
+            | SampleKind::OffCpu
+            | SampleKind::InUse
+            | SampleKind::Contention
+            | SampleKind::RunQueue => true,
+        });
+        raw_samples
+    }
+
+    /// Returns a sample for every allocation that's still in use.
+    pub fn in_use(&self) -> Vec<RawSample> {
+        self.allocations
+            .values()
+            .map(|sample| RawSample {
+                kind: SampleKind::InUse,
+                ..sample.clone()
+            })
+            .collect()
+    }
+
+    /// Forgets the allocations of a process that has exited.
+    pub fn remove_process(&mut self, pid: Pid) {
+        self.allocations
+            .retain(|(allocation_pid, _), _| *allocation_pid != pid);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::aggregator::Aggregator;
-    use crate::profile::RawSample;
+    use crate::aggregator::{Aggregator, LiveAllocations};
+    use crate::profile::{ExecutionContext, RawSample, SampleKind};
 
     #[test]
     fn test_aggregate_raw_samples() {
         let raw_sample_1 = RawSample {
             pid: 1234,
             tid: 1235,
-            collected_at: 1748865070,
             ustack: vec![0xffff, 0xffff],
             kstack: vec![0xffff, 0xdddd, 0xaaaa, 0xeeee, 0xaaae],
+            ..Default::default()
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1235,
-            collected_at: 1748865070,
             ustack: vec![0xdddd, 0xfeedbee, 0xddddef, 0xbeefdad],
             kstack: vec![],
+            ..Default::default()
         };
 
         let raw_samples = vec![
@@ -90,17 +182,17 @@ mod tests {
         let raw_sample_1 = RawSample {
             pid: 1234,
             tid: 1235,
-            collected_at: 1748865070,
             ustack: vec![0xffff, 0xdeadbeef],
             kstack: vec![0xffff, 0xdddd, 0xaaaa, 0xeeee, 0xaaae],
+            ..Default::default()
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1235,
-            collected_at: 1748865070,
             ustack: raw_sample_1.ustack.clone(),
             kstack: vec![],
+            ..Default::default()
         };
 
         let raw_samples = vec![
@@ -130,17 +222,17 @@ mod tests {
         let raw_sample_1 = RawSample {
             pid: 1234,
             tid: 1235,
-            collected_at: 1748865070,
             ustack: vec![0xffff, 0xdeadbeef],
             kstack: vec![0xffff, 0xdddd, 0xaaaa, 0xeeee, 0xaaae],
+            ..Default::default()
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1235,
-            collected_at: 1748865070,
             ustack: vec![0xdddd, 0xfeedbee, 0xddddef, 0xbeefdad],
             kstack: raw_sample_1.kstack.clone(),
+            ..Default::default()
         };
 
         let raw_samples = vec![
@@ -175,25 +267,112 @@ mod tests {
         let raw_sample_1 = RawSample {
             pid: 1234,
             tid: 1235,
-            collected_at: 1748865070,
             ustack: ustack.clone(),
             kstack: kstack.clone(),
+            ..Default::default()
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1236,
-            collected_at: 1748865070,
             ustack: ustack.clone(),
             kstack: kstack.clone(),
+            ..Default::default()
         };
 
         let raw_sample_3 = RawSample {
             pid: 123,
             tid: 124,
-            collected_at: 1748865070,
             ustack: ustack.clone(),
             kstack: kstack.clone(),
+            ..Default::default()
+        };
+
+        let raw_samples = vec![raw_sample_1, raw_sample_2, raw_sample_3];
+
+        let aggregator = Aggregator::default();
+
+        // When
+        let raw_aggregated_profile = aggregator.aggregate(raw_samples);
+
+        // Then
+        assert_eq!(raw_aggregated_profile.len(), 3);
+    }
+
+    #[test]
+    fn test_aggregate_raw_samples_sums_weights() {
+        let raw_sample_1 = RawSample {
+            pid: 1234,
+            tid: 1235,
+            weight: 1000,
+            ustack: vec![0xffff, 0xdeadbeef],
+            kstack: vec![],
+            ..Default::default()
+        };
+
+        let raw_sample_2 = RawSample {
+            collected_at: 1748865170,
+            weight: 500,
+            ..raw_sample_1.clone()
+        };
+
+        let raw_samples = vec![raw_sample_1, raw_sample_2];
+
+        let aggregator = Aggregator::default();
+
+        // When
+        let raw_aggregated_profile = aggregator.aggregate(raw_samples);
+
+        // Then
+        assert_eq!(raw_aggregated_profile.len(), 1);
+        assert_eq!(raw_aggregated_profile[0].count, 2);
+        assert_eq!(raw_aggregated_profile[0].weight, 1500);
+    }
+
+    #[test]
+    fn test_aggregate_same_stack_traces_different_kind() {
+        let raw_sample_1 = RawSample {
+            pid: 1234,
+            tid: 1235,
+            ustack: vec![0xffff, 0xdeadbeef],
+            kstack: vec![],
+            ..Default::default()
+        };
+
+        let raw_sample_2 = RawSample {
+            kind: SampleKind::OffCpu,
+            ..raw_sample_1.clone()
+        };
+
+        let raw_samples = vec![raw_sample_1, raw_sample_2];
+
+        let aggregator = Aggregator::default();
+
+        // When
+        let raw_aggregated_profile = aggregator.aggregate(raw_samples);
+
+        // Then
+        assert_eq!(raw_aggregated_profile.len(), 2);
+    }
+
+    #[test]
+    fn test_aggregate_same_stack_traces_different_cpu_and_context() {
+        let raw_sample_1 = RawSample {
+            pid: 1234,
+            tid: 1235,
+            ustack: vec![],
+            kstack: vec![0xffff, 0xdeadbeef],
+            ..Default::default()
+        };
+
+        let raw_sample_2 = RawSample {
+            cpu: 1,
+            ..raw_sample_1.clone()
+        };
+
+        let raw_sample_3 = RawSample {
+            context: ExecutionContext::SoftIrq,
+            ..raw_sample_1.clone()
         };
 
         let raw_samples = vec![raw_sample_1, raw_sample_2, raw_sample_3];
@@ -206,4 +385,134 @@ mod tests {
         // Then
         assert_eq!(raw_aggregated_profile.len(), 3);
     }
+
+    #[test]
+    fn test_aggregate_contention_samples_per_lock() {
+        let raw_sample_1 = RawSample {
+            pid: 1234,
+            tid: 1235,
+            weight: 1000,
+            address: 0xa000,
+            kind: SampleKind::Contention,
+            ustack: vec![0xffff, 0xdeadbeef],
+            kstack: vec![],
+            ..Default::default()
+        };
+
+        let raw_sample_2 = RawSample {
+            collected_at: 1748865170,
+            weight: 500,
+            ..raw_sample_1.clone()
+        };
+
+        let raw_sample_3 = RawSample {
+            address: 0xb000,
+            ..raw_sample_1.clone()
+        };
+
+        let raw_samples = vec![raw_sample_1, raw_sample_2, raw_sample_3];
+
+        let aggregator = Aggregator::default();
+
+        // When
+        let raw_aggregated_profile = aggregator.aggregate(raw_samples);
+
+        // Then
+        assert_eq!(raw_aggregated_profile.len(), 2);
+        for sample in raw_aggregated_profile {
+            if sample.sample.address == 0xa000 {
+                assert_eq!(sample.weight, 1500);
+            } else {
+                assert_eq!(sample.weight, 1000);
+            }
+        }
+    }
+
+    #[test]
+    fn test_live_allocations() {
+        let allocation = RawSample {
+            pid: 1234,
+            tid: 1235,
+            collected_at: 100,
+            weight: 64,
+            address: 0xa000,
+            kind: SampleKind::Allocation,
+            ustack: vec![0xffff, 0xdeadbeef],
+            kstack: vec![],
+            ..Default::default()
+        };
+        let freed_allocation = RawSample {
+            collected_at: 200,
+            weight: 32,
+            address: 0xb000,
+            ..allocation.clone()
+        };
+        let free = RawSample {
+            collected_at: 300,
+            weight: 0,
+            address: 0xb000,
+            kind: SampleKind::Free,
+            ustack: vec![],
+            ..allocation.clone()
+        };
+        let mut live_allocations = LiveAllocations::default();
+
+        // When, with the free received before the allocation it releases
+        let raw_samples = live_allocations.update(vec![
+            allocation.clone(),
+            free.clone(),
+            freed_allocation.clone(),
+        ]);
+
+        // Then
+        assert_eq!(raw_samples, vec![allocation.clone(), freed_allocation]);
+        assert_eq!(
+            live_allocations.in_use(),
+            vec![RawSample {
+                kind: SampleKind::InUse,
+                ..allocation.clone()
+            }]
+        );
+
+        // When
+        live_allocations.remove_process(allocation.pid);
+
+        // Then
+        assert!(live_allocations.in_use().is_empty());
+    }
+
+    #[test]
+    fn test_live_allocations_limit() {
+        let allocation = RawSample {
+            pid: 1234,
+            tid: 1235,
+            collected_at: 100,
+            weight: 64,
+            address: 0xa000,
+            kind: SampleKind::Allocation,
+            ustack: vec![0xffff, 0xdeadbeef],
+            kstack: vec![],
+            ..Default::default()
+        };
+        let other_allocation = RawSample {
+            collected_at: 200,
+            address: 0xb000,
+            ..allocation.clone()
+        };
+        let mut live_allocations = LiveAllocations::new(1);
+
+        // When
+        let raw_samples =
+            live_allocations.update(vec![allocation.clone(), other_allocation.clone()]);
+
+        // Then, every allocation is reported but only the first one is tracked
+        assert_eq!(raw_samples, vec![allocation.clone(), other_allocation]);
+        assert_eq!(
+            live_allocations.in_use(),
+            vec![RawSample {
+                kind: SampleKind::InUse,
+                ..allocation
+            }]
+        );
+    }
 }
@@ -1,4 +1,5 @@
 pub mod profiler_bindings;
 pub mod profiler_skel;
+pub mod runqueue_skel;
 pub mod tracers_bindings;
 pub mod tracers_skel;
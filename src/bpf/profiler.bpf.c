@@ -7,6 +7,7 @@
 #include "profiler.h"
 #include "shared_maps.h"
 #include "shared_helpers.h"
+#include "runqueue.h"
 
 #include <bpf/bpf_core_read.h>
 #include <bpf/bpf_endian.h>
@@ -48,6 +49,31 @@ struct {
   __type(value, u32);
 } programs SEC(".maps");
 
+// Tail calls can only happen between programs of the same type, so the unwinder
+// used from tracepoints lives in its own program array.
+struct {
+  __uint(type, BPF_MAP_TYPE_PROG_ARRAY);
+  __uint(max_entries, 5);
+  __type(key, u32);
+  __type(value, u32);
+} tracepoint_programs SEC(".maps");
+
+// Same as above, for the unwinder used from uprobes.
+struct {
+  __uint(type, BPF_MAP_TYPE_PROG_ARRAY);
+  __uint(max_entries, 5);
+  __type(key, u32);
+  __type(value, u32);
+} uprobe_programs SEC(".maps");
+
+// Same as above, for the unwinder used from kprobe perf events.
+struct {
+  __uint(type, BPF_MAP_TYPE_PROG_ARRAY);
+  __uint(max_entries, 5);
+  __type(key, u32);
+  __type(value, u32);
+} kprobe_programs SEC(".maps");
+
 struct {
   __uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
   __uint(key_size, sizeof(u32));
@@ -74,6 +100,43 @@ struct {
   __type(value, bool);
 } rate_limits SEC(".maps");
 
+// cgroup v2 whose subtree is profiled, if `filter_by_cgroup` is set.
+struct {
+  __uint(type, BPF_MAP_TYPE_CGROUP_ARRAY);
+  __uint(max_entries, 1);
+  __type(key, u32);
+  __type(value, u32);
+} cgroup_filter SEC(".maps");
+
+// Samples of tasks that have been switched out, or that are waiting on a futex,
+// keyed by thread id. They are sent once the task runs again and we know for how
+// long it was waiting. Uses an LRU as threads that exit while waiting won't ever
+// be switched in. The values are `sample_t`, shrunk at load time to fit only
+// `max_stack_depth` frames, see `deferred_sample_size`.
+struct {
+  __uint(type, BPF_MAP_TYPE_LRU_HASH);
+  __uint(max_entries, MAX_OFF_CPU_SAMPLES);
+  __uint(key_size, sizeof(u32));
+  __uint(value_size, sizeof(sample_t));
+} off_cpu_samples SEC(".maps");
+
+// Compact bytecode of the DWARF expressions that compute the CFA, indexed by the
+// offset of the `CFA_TYPE_EXPRESSION` unwind rows.
+struct {
+  __uint(type, BPF_MAP_TYPE_ARRAY);
+  __uint(max_entries, MAX_CFA_EXPRESSIONS);
+  __type(key, u32);
+  __type(value, cfa_expression_t);
+} cfa_expressions SEC(".maps");
+
+// Allocator calls that haven't returned yet, keyed by pid_tgid.
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(max_entries, MAX_INFLIGHT_ALLOCATIONS);
+  __type(key, u64);
+  __type(value, inflight_allocations_t);
+} inflight_allocations SEC(".maps");
+
 
 // Binary search the unwind table to find the row index containing the unwind
 // information for a given program counter (pc) relative to the object file.
@@ -138,7 +201,11 @@ find_page(mapping_t *mapping, u64 object_relative_pc, u64 *low_index, u64 *high_
   return NULL;
 }
 
-static __always_inline void send_event(Event *event, struct bpf_perf_event_data *ctx) {
+static __always_inline void send_event(Event *event, void *ctx) {
+  if (event->type == EVENT_NEW_PROCESS && process_is_denied(event->pid)) {
+    return;
+  }
+
   bool *is_rate_limited = bpf_map_lookup_elem(&rate_limits, event);
   if (is_rate_limited != NULL && *is_rate_limited) {
     LOG("[debug] send_event was rate limited");
@@ -193,6 +260,154 @@ static __always_inline u64 remove_pac(u64 addr) {
 }
 #endif
 
+// Evaluates the expression with the given id, returning zero if it's not valid
+// or if any memory read fails. The expression stack is kept in variables, rather
+// than in an array, so the verifier doesn't have to track a variable offset.
+static __always_inline u64 evaluate_cfa_expression(u16 id, unwind_state_t *unwind_state) {
+  u32 key = id;
+  cfa_expression_t *expression = bpf_map_lookup_elem(&cfa_expressions, &key);
+  if (expression == NULL) {
+    return 0;
+  }
+
+  u64 top = 0;
+  u64 second = 0;
+  u64 third = 0;
+  u32 depth = 0;
+
+  for (int i = 0; i < MAX_CFA_EXPRESSION_OPS; i++) {
+    if (i >= expression->len) {
+      break;
+    }
+
+    u8 opcode = expression->ops[i].opcode;
+    s32 operand = expression->ops[i].operand;
+    u64 pushed = 0;
+    bool push = true;
+
+    if (opcode == CFA_EXPRESSION_OP_BREG_SP) {
+      pushed = unwind_state->sp + operand;
+    } else if (opcode == CFA_EXPRESSION_OP_BREG_FP) {
+      pushed = unwind_state->bp + operand;
+    } else if (opcode == CFA_EXPRESSION_OP_BREG_IP) {
+      pushed = unwind_state->ip + operand;
+    } else if (opcode == CFA_EXPRESSION_OP_LIT) {
+      pushed = operand;
+    } else {
+      push = false;
+    }
+
+    if (push) {
+      if (depth >= MAX_CFA_EXPRESSION_STACK) {
+        return 0;
+      }
+      third = second;
+      second = top;
+      top = pushed;
+      depth++;
+      continue;
+    }
+
+    // Unary operations.
+    if (depth < 1) {
+      return 0;
+    }
+
+    if (opcode == CFA_EXPRESSION_OP_DEREF) {
+      u64 address = top;
+      if (bpf_probe_read_user(&top, 8, (void *)address) < 0) {
+        LOG("[error] reading expression value @ %llx failed", address);
+        return 0;
+      }
+      continue;
+    }
+
+    if (opcode == CFA_EXPRESSION_OP_PLUS_UCONST) {
+      top += (u32)operand;
+      continue;
+    }
+
+    // Binary operations.
+    if (depth < 2) {
+      return 0;
+    }
+
+    if (opcode == CFA_EXPRESSION_OP_AND) {
+      top = second & top;
+    } else if (opcode == CFA_EXPRESSION_OP_SHL) {
+      top = second << (top & 63);
+    } else {
+      LOG("[error] unknown expression opcode %d", opcode);
+      return 0;
+    }
+    second = third;
+    third = 0;
+    depth--;
+  }
+
+  if (depth != 1) {
+    return 0;
+  }
+  return top;
+}
+
+#define FRAME_POINTER_STEP_OK 0
+#define FRAME_POINTER_STEP_BOTTOM 1
+#define FRAME_POINTER_STEP_ERROR 2
+
+// Adds the current frame to the stack and unwinds it by following the frame
+// pointer. This is used for code without unwind information, such as the code
+// emitted by the V8, JVM and .NET JITs, which keep frame pointers. Both in x86_64
+// and arm64 the frame pointer points to the caller's frame pointer, followed by
+// the return address.
+static __always_inline int frame_pointer_step(unwind_state_t *unwind_state) {
+  u32 ulen = unwind_state->sample.stack.ulen;
+  // Appease the verifier.
+  if (ulen < MAX_STACK_DEPTH) {
+    unwind_state->sample.stack.addresses[ulen] = unwind_state->ip;
+    unwind_state->sample.stack.ulen++;
+  }
+  unwind_state->used_frame_pointers = true;
+
+  if (unwind_state->bp == 0) {
+    LOG("[info] frame pointer is zero, end of stack");
+    return FRAME_POINTER_STEP_BOTTOM;
+  }
+
+  u64 frame_record[2] = {0};
+  int err = bpf_probe_read_user(&frame_record, sizeof(frame_record), (void *)unwind_state->bp);
+  if (err < 0) {
+    LOG("[error] reading frame record @ %llx failed with %d", unwind_state->bp, err);
+    bump_unwind_error_frame_pointer();
+    return FRAME_POINTER_STEP_ERROR;
+  }
+
+  u64 previous_bp = frame_record[0];
+  u64 previous_rip = frame_record[1];
+  if (previous_rip == 0) {
+    LOG("[info] null return address, end of stack");
+    return FRAME_POINTER_STEP_BOTTOM;
+  }
+
+  // The callers' frames are at higher addresses. Anything else means that the
+  // register isn't used as a frame pointer, or that the stack is corrupted.
+  if (previous_bp != 0 && previous_bp <= unwind_state->bp) {
+    LOG("[error] previous frame pointer %llx is not above %llx", previous_bp, unwind_state->bp);
+    bump_unwind_error_frame_pointer();
+    return FRAME_POINTER_STEP_ERROR;
+  }
+
+  LOG("\tprevious ip: %llx (frame pointer)", previous_rip);
+  unwind_state->ip = previous_instruction_addr(remove_pac(previous_rip));
+  // The caller's stack pointer, right past the frame record. This is exact for
+  // x86_64 and a best effort for arm64, where the frame record can be anywhere
+  // within the frame, but DWARF unwinding there is mostly based on the frame
+  // pointer.
+  unwind_state->sp = unwind_state->bp + sizeof(frame_record);
+  unwind_state->bp = previous_bp;
+  return FRAME_POINTER_STEP_OK;
+}
+
 // Kernel addresses have the top bits set.
 static __always_inline bool in_kernel(u64 ip) { return ip & (1UL << 63); }
 
@@ -216,6 +431,51 @@ static __always_inline bool is_kthread() {
   return mm == NULL;
 }
 
+#ifdef __TARGET_ARCH_x86
+// The preempt count lived in `pcpu_hot` between 6.2 and 6.14, and in the
+// `__preempt_count` per-CPU variable before and after.
+extern const int __preempt_count __ksym __weak;
+struct pcpu_hot___local {
+  int preempt_count;
+} __attribute__((preserve_access_index));
+extern const struct pcpu_hot___local pcpu_hot __ksym __weak;
+#endif
+
+static __always_inline int get_preempt_count() {
+#ifdef __TARGET_ARCH_x86
+  if (bpf_ksym_exists(&__preempt_count)) {
+    return *(int *)bpf_this_cpu_ptr(&__preempt_count);
+  }
+  if (bpf_core_field_exists(((struct pcpu_hot___local *)0)->preempt_count)) {
+    return ((struct pcpu_hot___local *)bpf_this_cpu_ptr(&pcpu_hot))->preempt_count;
+  }
+  return 0;
+#elif __TARGET_ARCH_arm64
+  struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+  return task->thread_info.preempt.count;
+#endif
+}
+
+// Context that was executing when the program fired. Clock perf events are
+// delivered from the timer interrupt, so that interrupt level is discounted.
+static __always_inline u32 current_context(bool from_timer_interrupt) {
+  int count = get_preempt_count();
+  if (from_timer_interrupt && (count & HARDIRQ_MASK)) {
+    count -= HARDIRQ_OFFSET;
+  }
+
+  if (count & NMI_MASK) {
+    return SAMPLE_CONTEXT_NMI;
+  }
+  if (count & HARDIRQ_MASK) {
+    return SAMPLE_CONTEXT_HARDIRQ;
+  }
+  if (count & SOFTIRQ_OFFSET) {
+    return SAMPLE_CONTEXT_SOFTIRQ;
+  }
+  return SAMPLE_CONTEXT_TASK;
+}
+
 // avoid R0 invalid mem access 'scalar'
 // Port of `task_pt_regs` in BPF.
 static __always_inline bool retrieve_task_registers(u64 *ip, u64 *sp, u64 *bp, u64 *lr) {
@@ -258,43 +518,24 @@ static __always_inline bool retrieve_task_registers(u64 *ip, u64 *sp, u64 *bp, u
   return true;
 }
 
-static __always_inline void add_stack(struct bpf_perf_event_data *ctx,
-unwind_state_t *unwind_state) {
-  // Unwind and copy kernel stack.
-  u32 ulen = unwind_state->sample.stack.ulen;
-  if (ulen < MAX_STACK_DEPTH) {
-    int ret = bpf_get_stack(ctx, &unwind_state->sample.stack.addresses[ulen], MAX_STACK_DEPTH * sizeof(u64), 0);
-    if (ret > 0) {
-      unwind_state->sample.stack.klen = ret / sizeof(u64);
-    }
-  }
-
-  struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
-  unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
-  int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
-  int per_thread_id = BPF_CORE_READ(task, thread_pid, numbers[level].nr);
-
-
-  unwind_state->sample.pid = per_process_id;
-  unwind_state->sample.tid = per_thread_id;
-  unwind_state->sample.collected_at = bpf_ktime_get_boot_ns();
-
+// Sends a sample, stored in memory that's `max_sample_size` bytes long.
+static __always_inline void send_sample(void *ctx, sample_t *sample, u32 max_sample_size) {
   u32 sample_size = sizeof(sample_t)
     // Remove the actual stack buffer which was doubled to appease the verifier.
     - 2 * MAX_STACK_DEPTH * sizeof(u64)
     // Add the actual stack size in bytes.
-    + (unwind_state->sample.stack.ulen + unwind_state->sample.stack.klen) * sizeof(u64);
+    + (sample->stack.ulen + sample->stack.klen) * sizeof(u64);
 
   // Appease the verifier.
-  if (sample_size > sizeof(sample_t)) {
+  if (sample_size > max_sample_size) {
     return;
   }
 
   int ret = 0;
   if (lightswitch_config.use_ring_buffers) {
-    ret = bpf_ringbuf_output(&stacks_rb, &(unwind_state->sample), sample_size, 0);
+    ret = bpf_ringbuf_output(&stacks_rb, sample, sample_size, 0);
   } else {
-    ret = bpf_perf_event_output(ctx, &stacks, BPF_F_CURRENT_CPU, &(unwind_state->sample), sample_size);
+    ret = bpf_perf_event_output(ctx, &stacks, BPF_F_CURRENT_CPU, sample, sample_size);
   }
 
   if (ret < 0) {
@@ -307,9 +548,44 @@ unwind_state_t *unwind_state) {
   }
 }
 
-// The unwinding machinery lives here.
-SEC("perf_event")
-int dwarf_unwind(struct bpf_perf_event_data *ctx) {
+static __always_inline void add_stack(void *ctx, unwind_state_t *unwind_state) {
+  // Unwind and copy kernel stack. Allocations happen in userspace, where the
+  // kernel stack would only show the uprobe machinery, and the kernel stack of
+  // futex waits would only show the syscall entry.
+  u32 ulen = unwind_state->sample.stack.ulen;
+  if (ulen < MAX_STACK_DEPTH && unwind_state->sample.kind != SAMPLE_KIND_ALLOCATION &&
+      unwind_state->sample.kind != SAMPLE_KIND_CONTENTION) {
+    int ret = bpf_get_stack(ctx, &unwind_state->sample.stack.addresses[ulen], lightswitch_config.max_stack_depth * sizeof(u64), 0);
+    if (ret > 0) {
+      unwind_state->sample.stack.klen = ret / sizeof(u64);
+    }
+  }
+
+  struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+  unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+  int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+  int per_thread_id = BPF_CORE_READ(task, thread_pid, numbers[level].nr);
+
+
+  unwind_state->sample.pid = per_process_id;
+  unwind_state->sample.tid = per_thread_id;
+  unwind_state->sample.collected_at = bpf_ktime_get_boot_ns();
+  unwind_state->sample.cgroup_id = bpf_get_current_cgroup_id();
+  unwind_state->sample.cpu = bpf_get_smp_processor_id();
+
+  if (unwind_state->defer_sample) {
+    // Keyed by the kernel's view of the thread id, which is what `sched_switch` reports.
+    u32 tid = bpf_get_current_pid_tgid();
+    bpf_map_update_elem(&off_cpu_samples, &tid, &unwind_state->sample, BPF_ANY);
+    return;
+  }
+
+  send_sample(ctx, &unwind_state->sample, sizeof(sample_t));
+}
+
+// The unwinding machinery lives here. The program array is passed in as tail
+// calls must target programs of the same type as the caller.
+static __always_inline int native_unwind(void *ctx, void *programs_array) {
 	struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
 	unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
 	int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
@@ -325,6 +601,9 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
   }
 
   for (int i = 0; i < MAX_STACK_DEPTH_PER_PROGRAM; i++) {
+    if (unwind_state->sample.stack.ulen >= lightswitch_config.max_stack_depth) {
+      break;
+    }
     // LOG("[debug] Within unwinding machinery loop");
     LOG("## frame: %d", unwind_state->sample.stack.ulen);
     LOG("\tcurrent pc: %llx", unwind_state->ip);
@@ -336,19 +615,30 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     if (mapping == NULL) {
       LOG("[error] no mapping found for pc %llx", unwind_state->ip);
       bump_unwind_error_mapping_not_found();
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_MAPPING_NOT_FOUND;
+      break;
     }
 
     if (unwind_state->ip < mapping->begin || unwind_state->ip >= mapping->end) {
       LOG("[error] pc %llx not contained within begin: %llx end: %llx", unwind_state->ip, mapping->begin, mapping->end);
       bump_unwind_error_mapping_does_not_contain_pc();
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_MAPPING_DOES_NOT_CONTAIN_PC;
+      break;
     }
 
     if (mapping->type == MAPPING_TYPE_ANON) {
-      LOG("JIT section, stopping");
+      LOG("JIT section, walking the frame pointers");
       bump_unwind_jit_encountered();
-      return 1;
+      int step = frame_pointer_step(unwind_state);
+      if (step == FRAME_POINTER_STEP_ERROR) {
+        unwind_state->sample.unwind_error = UNWIND_ERROR_FRAME_POINTER;
+        break;
+      }
+      if (step == FRAME_POINTER_STEP_BOTTOM) {
+        reached_bottom_of_stack = true;
+        break;
+      }
+      continue;
     }
 
     if (mapping->type == MAPPING_TYPE_VDSO) {
@@ -374,7 +664,20 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
           .address = unwind_state->ip & PAGE_MASK,
       };
       send_event(&event, ctx);
-      return 1;
+
+      // No unwind information, either because it's not loaded yet or because
+      // the object doesn't have any. Try with the frame pointers, the next
+      // frames will be unwound with DWARF again once a covered mapping is found.
+      int step = frame_pointer_step(unwind_state);
+      if (step == FRAME_POINTER_STEP_ERROR) {
+        unwind_state->sample.unwind_error = UNWIND_ERROR_FRAME_POINTER;
+        break;
+      }
+      if (step == FRAME_POINTER_STEP_BOTTOM) {
+        reached_bottom_of_stack = true;
+        break;
+      }
+      continue;
     }
 
     u64 table_idx = find_offset_for_pc(inner, object_relative_pc_low, low_index, high_index);
@@ -399,7 +702,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
         if (table_idx == BINARY_SEARCH_EXHAUSTED_ITERATIONS) {
           bump_unwind_error_binary_search_exhausted_iterations();
         }
-        return 1;
+        unwind_state->sample.unwind_error = UNWIND_ERROR_BINARY_SEARCH;
+        break;
       }
     }
 
@@ -408,7 +712,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
 
     stack_unwind_row_t *row = bpf_map_lookup_elem(inner, &table_idx);
     if (row == NULL) {
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_SHOULD_NEVER_HAPPEN;
+      break;
     }
 
     u64 found_pc = object_relative_pc_high + row->pc_low;
@@ -421,7 +726,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
 
     if (found_cfa_type == CFA_TYPE_OFFSET_DID_NOT_FIT) {
       bump_unwind_error_cfa_offset_did_not_fit();
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_CFA_OFFSET_DID_NOT_FIT;
+      break;
     }
 
     if (found_cfa_type == CFA_TYPE_END_OF_FDE_MARKER) {
@@ -433,7 +739,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
 
     if (found_rbp_type == RBP_TYPE_OFFSET_DID_NOT_FIT) {
       bump_unwind_error_rbp_offset_did_not_fit();
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_RBP_OFFSET_DID_NOT_FIT;
+      break;
     }
 
     if (found_rbp_type == RBP_TYPE_UNDEFINED_RETURN_ADDRESS) {
@@ -455,7 +762,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
       LOG("\t[error] frame pointer is %d (register or exp), bailing out",
           found_rbp_type);
       bump_unwind_error_unsupported_frame_pointer_action();
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_UNSUPPORTED_FRAME_POINTER_ACTION;
+      break;
     }
 
     u64 previous_rsp = 0;
@@ -472,25 +780,38 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
       if (ret < 0) {
         LOG("[error] reading previous rsp failed with %d", ret);
         bump_unwind_error_previous_rsp_read();
+        unwind_state->sample.unwind_error = UNWIND_ERROR_PREVIOUS_RSP_READ;
+        break;
       }
       previous_rsp += addition;
+    } else if (found_cfa_type == CFA_TYPE_EXPRESSION) {
+      previous_rsp = evaluate_cfa_expression(row->cfa_offset, unwind_state);
+      if (previous_rsp == 0) {
+        LOG("[error] evaluating cfa expression %d failed", row->cfa_offset);
+        bump_unwind_error_unsupported_expression();
+        unwind_state->sample.unwind_error = UNWIND_ERROR_UNSUPPORTED_EXPRESSION;
+        break;
+      }
     } else if (found_cfa_type == CFA_TYPE_CFA_TYPE_UNSUP_EXP) {
         bump_unwind_error_unsupported_expression();
-        return 1;
+        unwind_state->sample.unwind_error = UNWIND_ERROR_UNSUPPORTED_EXPRESSION;
+        break;
     } else if (found_cfa_type == CFA_TYPE_PLT1 || found_cfa_type == CFA_TYPE_PLT2) {
       LOG("CFA expression found with id %d", found_cfa_offset);
       u64 threshold = 11 ? found_cfa_type == CFA_TYPE_PLT1 : 10;
 
       if (threshold == 0) {
         bump_unwind_error_should_never_happen();
-        return 1;
+        unwind_state->sample.unwind_error = UNWIND_ERROR_SHOULD_NEVER_HAPPEN;
+        break;
       }
       previous_rsp = unwind_state->sp + 8 +
                      ((((unwind_state->ip & 15) >= threshold)) << 3);
     } else {
       LOG("\t[unsup] cfa type %d not valid at ip: %llx", found_cfa_type, object_relative_pc);
       bump_unwind_error_unsupported_cfa_register();
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_UNSUPPORTED_CFA_REGISTER;
+      break;
     }
 
     // TODO(javierhonduco): A possible check could be to see whether this value
@@ -499,7 +820,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     if (previous_rsp == 0) {
       LOG("[error] previous_rsp should not be zero.");
       bump_unwind_error_previous_rsp_zero();
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_PREVIOUS_RSP_ZERO;
+      break;
     }
 
     // Set rbp register.
@@ -516,7 +838,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
       if (ret < 0) {
         LOG("[error] previous_rbp read failed with %d", ret);
         bump_unwind_error_previous_rbp_read();
-        return 1;
+        unwind_state->sample.unwind_error = UNWIND_ERROR_PREVIOUS_RBP_READ;
+        break;
       }
     }
 
@@ -551,7 +874,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
             err, previous_rip_addr);
         bump_unwind_error_previous_rip_zero();
       }
-      return 1;
+      unwind_state->sample.unwind_error = UNWIND_ERROR_PREVIOUS_RIP_ZERO;
+      break;
     }
 
 
@@ -588,35 +912,107 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     }
 
     LOG("======= reached bottom frame! =======");
-    add_stack(ctx, unwind_state);
-    bump_unwind_success_dwarf();
-    return 0;
+    if (unwind_state->used_frame_pointers) {
+      bump_unwind_success_frame_pointer();
+    } else {
+      bump_unwind_success_dwarf();
+    }
+  } else if (unwind_state->sample.unwind_error == UNWIND_ERROR_NONE) {
+    if (unwind_state->sample.stack.ulen < lightswitch_config.max_stack_depth &&
+        unwind_state->tail_calls < MAX_TAIL_CALLS) {
+      LOG("Continuing walking the stack in a tail call, current tail %d",
+          unwind_state->tail_calls);
+      unwind_state->tail_calls++;
+      bpf_tail_call(ctx, programs_array, PROGRAM_NATIVE_UNWINDER);
+    }
 
-  } else if (unwind_state->sample.stack.ulen < MAX_STACK_DEPTH &&
-             unwind_state->tail_calls < MAX_TAIL_CALLS) {
-    LOG("Continuing walking the stack in a tail call, current tail %d",
-        unwind_state->tail_calls);
-    unwind_state->tail_calls++;
-    bpf_tail_call(ctx, &programs, PROGRAM_NATIVE_UNWINDER);
+    // We couldn't get the whole stacktrace.
+    LOG("Truncated stack");
+    bump_unwind_error_truncated();
+    unwind_state->sample.unwind_error = UNWIND_ERROR_TRUNCATED;
   }
 
-  // We couldn't get the whole stacktrace.
-  LOG("Truncated stack, won't be sent");
-  bump_unwind_error_truncated();
+  // Stacks that couldn't be fully unwound are sent too, along with the reason,
+  // so the samples aren't lost and the profile isn't skewed towards the code
+  // that is easy to unwind.
+  if (unwind_state->sample.unwind_error != UNWIND_ERROR_NONE) {
+    LOG("[error] partial stack, unwind error: %d", unwind_state->sample.unwind_error);
+  }
+  add_stack(ctx, unwind_state);
   return 0;
 }
 
-// Set up the initial unwinding state.
-static __always_inline bool set_initial_state(unwind_state_t *unwind_state, bpf_user_pt_regs_t *regs) {
+SEC("perf_event")
+int dwarf_unwind(struct bpf_perf_event_data *ctx) {
+  return native_unwind(ctx, &programs);
+}
+
+SEC("tracepoint")
+int dwarf_unwind_tracepoint(void *ctx) {
+  return native_unwind(ctx, &tracepoint_programs);
+}
+
+SEC("uprobe")
+int dwarf_unwind_uprobe(struct pt_regs *ctx) {
+  return native_unwind(ctx, &uprobe_programs);
+}
+
+SEC("kprobe")
+int dwarf_unwind_kprobe(struct pt_regs *ctx) {
+  return native_unwind(ctx, &kprobe_programs);
+}
+
+static __always_inline void reset_unwind_state(unwind_state_t *unwind_state) {
  unwind_state->sample.stack.ulen = 0;
  unwind_state->sample.stack.klen = 0;
  unwind_state->tail_calls = 0;
+ unwind_state->defer_sample = false;
+ unwind_state->used_frame_pointers = false;
 
  unwind_state->sample.pid = 0;
  unwind_state->sample.tid = 0;
  unwind_state->sample.collected_at = 0;
+ unwind_state->sample.weight = 0;
+ unwind_state->sample.cgroup_id = 0;
+ unwind_state->sample.address = 0;
+ unwind_state->sample.kind = SAMPLE_KIND_ON_CPU;
+ unwind_state->sample.cpu = 0;
+ unwind_state->sample.context = SAMPLE_CONTEXT_TASK;
+ unwind_state->sample.unwind_error = UNWIND_ERROR_NONE;
+ unwind_state->sample.futex_wait = FUTEX_WAIT_NONE;
+ __builtin_memset(unwind_state->sample.comm, 0, sizeof(unwind_state->sample.comm));
+}
 
-  if (in_kernel(PT_REGS_IP(regs))) {
+// Reports memory released by the allocator so userspace can tell which
+// allocations are still in use.
+static __always_inline void send_free(void *ctx, u64 address) {
+  u32 zero = 0;
+  unwind_state_t *unwind_state = bpf_map_lookup_elem(&heap, &zero);
+  if (unwind_state == NULL) {
+    LOG("[error] profiler state should never be NULL");
+    return;
+  }
+  reset_unwind_state(unwind_state);
+
+  struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+  unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+  unwind_state->sample.pid = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+  unwind_state->sample.tid = BPF_CORE_READ(task, thread_pid, numbers[level].nr);
+  unwind_state->sample.collected_at = bpf_ktime_get_boot_ns();
+  unwind_state->sample.cgroup_id = bpf_get_current_cgroup_id();
+  unwind_state->sample.kind = SAMPLE_KIND_FREE;
+  unwind_state->sample.address = address;
+  unwind_state->sample.cpu = bpf_get_smp_processor_id();
+
+  send_sample(ctx, &unwind_state->sample, sizeof(sample_t));
+}
+
+// Set up the initial unwinding state. Without `regs`, the task is assumed to be
+// in kernel context.
+static __always_inline bool set_initial_state(unwind_state_t *unwind_state, bpf_user_pt_regs_t *regs) {
+  reset_unwind_state(unwind_state);
+
+  if (regs == NULL || in_kernel(PT_REGS_IP(regs))) {
     if (!retrieve_task_registers(&unwind_state->ip, &unwind_state->sp, &unwind_state->bp, &unwind_state->lr)) {
       // in kernelspace, but failed, probs a kworker
       // todo: bump counter
@@ -633,13 +1029,145 @@ static __always_inline bool set_initial_state(unwind_state_t *unwind_state, bpf_
   return true;
 }
 
+// Whether the current task belongs to the profiled cgroup subtree, if any.
+static __always_inline bool in_profiled_cgroup() {
+  if (!lightswitch_config.filter_by_cgroup) {
+    return true;
+  }
+
+  return bpf_current_task_under_cgroup(&cgroup_filter, 0) == 1;
+}
+
+// Sends the kernel stack of the idle task or of a kernel thread. As there's
+// no userspace to unwind, they are reported under the kernel's pid, 0.
+static __always_inline void send_kernel_sample(void *ctx, u64 weight) {
+  u32 zero = 0;
+  unwind_state_t *unwind_state = bpf_map_lookup_elem(&heap, &zero);
+  if (unwind_state == NULL) {
+    LOG("[error] profiler state should never be NULL");
+    return;
+  }
+  reset_unwind_state(unwind_state);
+
+  int ret = bpf_get_stack(ctx, unwind_state->sample.stack.addresses, lightswitch_config.max_stack_depth * sizeof(u64), 0);
+  if (ret <= 0) {
+    return;
+  }
+  unwind_state->sample.stack.klen = ret / sizeof(u64);
+
+  unwind_state->sample.tid = bpf_get_current_pid_tgid();
+  unwind_state->sample.collected_at = bpf_ktime_get_boot_ns();
+  unwind_state->sample.weight = weight;
+  unwind_state->sample.cgroup_id = bpf_get_current_cgroup_id();
+  unwind_state->sample.cpu = bpf_get_smp_processor_id();
+  unwind_state->sample.context = current_context(lightswitch_config.timer_perf_event);
+  bpf_get_current_comm(unwind_state->sample.comm, sizeof(unwind_state->sample.comm));
+  send_sample(ctx, &unwind_state->sample, lightswitch_config.deferred_sample_size);
+}
+
+// Samples the stack of the current task. `regs` are NULL for tracepoints and
+// kprobes, and `programs_array` must hold the unwinder of the same program type
+// as the caller.
+static __always_inline int sample_current_task(void *ctx, bpf_user_pt_regs_t *regs, u64 weight, void *programs_array) {
+	struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+	unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+	int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+  // The idle task has no userspace stack to unwind, so unless its samples are
+  // kept to account for idle time, there's no point in checking for it.
+  if (per_process_id == 0) {
+    if (lightswitch_config.keep_idle && in_profiled_cgroup()) {
+      send_kernel_sample(ctx, weight);
+    }
+    return 0;
+  }
+
+  // Discard kworkers and other kernel threads, unless asked to keep them.
+  if (is_kthread()) {
+    if (lightswitch_config.keep_kthreads && in_profiled_cgroup()) {
+      send_kernel_sample(ctx, weight);
+    }
+    return 0;
+  }
+
+  if (!in_profiled_cgroup()) {
+    return 0;
+  }
+
+  if (process_is_known(per_process_id)) {
+    bump_unwind_total();
+
+    u32 zero = 0;
+    unwind_state_t *profiler_state = bpf_map_lookup_elem(&heap, &zero);
+    if (profiler_state == NULL) {
+      LOG("[error] profiler state should never be NULL");
+      return 0;
+    }
+    if (!set_initial_state(profiler_state, regs)) {
+      return 0;
+    }
+    profiler_state->sample.context = current_context(lightswitch_config.timer_perf_event);
+    profiler_state->sample.weight = weight;
+
+    bpf_tail_call(ctx, programs_array, PROGRAM_NATIVE_UNWINDER);
+    return 0;
+  }
+
+  Event event = {
+      .type = EVENT_NEW_PROCESS,
+      .pid = per_process_id,
+  };
+  send_event(&event, ctx);
+  return 0;
+}
+
 SEC("perf_event")
 int on_event(struct bpf_perf_event_data *ctx) {
+  // In frequency mode the kernel keeps adjusting the period, so every sample
+  // is weighted by the period that triggered it.
+  return sample_current_task(ctx, &ctx->regs, ctx->sample_period, &programs);
+}
+
+// Tracepoint and kprobe perf events can only run programs of their own type.
+// They run on every hit regardless of the sample period, so each sample counts once.
+SEC("tracepoint")
+int on_tracepoint_event(void *ctx) {
+  return sample_current_task(ctx, NULL, 1, &tracepoint_programs);
+}
+
+SEC("kprobe")
+int on_kprobe_event(struct pt_regs *ctx) {
+  return sample_current_task(ctx, NULL, 1, &kprobe_programs);
+}
+
+SEC("tracepoint/sched/sched_switch")
+int on_sched_switch(struct trace_event_raw_sched_switch *ctx) {
+  // Send the sample of the task being switched in, if we recorded one when it
+  // was switched out, weighted by the time it spent off-CPU or, when measuring
+  // the run-queue latency, by the time it waited for a CPU.
+  u32 next_tid = ctx->next_pid;
+  sample_t *off_cpu_sample = bpf_map_lookup_elem(&off_cpu_samples, &next_tid);
+  if (lightswitch_config.runqueue_latency) {
+    u64 *enqueued_at = bpf_map_lookup_elem(&runqueue_enqueued_at, &next_tid);
+    if (off_cpu_sample != NULL && enqueued_at != NULL) {
+      off_cpu_sample->weight = bpf_ktime_get_boot_ns() - *enqueued_at;
+      send_sample(ctx, off_cpu_sample, lightswitch_config.deferred_sample_size);
+    }
+    bpf_map_delete_elem(&runqueue_enqueued_at, &next_tid);
+  } else if (off_cpu_sample != NULL) {
+    off_cpu_sample->weight = bpf_ktime_get_boot_ns() - off_cpu_sample->collected_at;
+    send_sample(ctx, off_cpu_sample, lightswitch_config.deferred_sample_size);
+  }
+  if (off_cpu_sample != NULL) {
+    bpf_map_delete_elem(&off_cpu_samples, &next_tid);
+  }
+
+  // The current task is the one being switched out.
 	struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
 	unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
 	int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
 
-  // There's no point in checking for the swapper process.
+  // The idle task is switched out every time there's work to do.
   if (per_process_id == 0) {
     return 0;
   }
@@ -649,6 +1177,98 @@ int on_event(struct bpf_perf_event_data *ctx) {
     return 0;
   }
 
+  if (!in_profiled_cgroup()) {
+    return 0;
+  }
+
+  if (process_is_known(per_process_id)) {
+    bump_unwind_total();
+
+    u32 zero = 0;
+    unwind_state_t *profiler_state = bpf_map_lookup_elem(&heap, &zero);
+    if (profiler_state == NULL) {
+      LOG("[error] profiler state should never be NULL");
+      return 0;
+    }
+
+    // We are always in kernel context here, so the userspace registers are read
+    // from the task.
+    reset_unwind_state(profiler_state);
+    if (!retrieve_task_registers(&profiler_state->ip, &profiler_state->sp, &profiler_state->bp, &profiler_state->lr)) {
+      return 0;
+    }
+    // The user stack of the task won't change until it's switched back in, so it's
+    // unwound now and the sample is sent then.
+    profiler_state->defer_sample = true;
+    profiler_state->sample.kind = lightswitch_config.runqueue_latency ? SAMPLE_KIND_RUNQUEUE : SAMPLE_KIND_OFF_CPU;
+
+    bpf_tail_call(ctx, &tracepoint_programs, PROGRAM_NATIVE_UNWINDER);
+    return 0;
+  }
+
+  Event event = {
+      .type = EVENT_NEW_PROCESS,
+      .pid = per_process_id,
+  };
+  send_event(&event, ctx);
+  return 0;
+}
+
+// Records the size requested by an allocator call. The stack is collected once it
+// returns, as that's when we know the address of the allocated memory.
+static __always_inline int on_allocator_entry(u64 size, u64 freed_address) {
+  u64 pid_tgid = bpf_get_current_pid_tgid();
+  inflight_allocations_t *inflight = bpf_map_lookup_elem(&inflight_allocations, &pid_tgid);
+  if (inflight != NULL) {
+    inflight->depth++;
+    return 0;
+  }
+
+  inflight_allocations_t allocations = {
+      .outermost = {.size = size, .freed_address = freed_address},
+      .depth = 1,
+  };
+  bpf_map_update_elem(&inflight_allocations, &pid_tgid, &allocations, BPF_ANY);
+  return 0;
+}
+
+SEC("uprobe")
+int on_malloc(struct pt_regs *ctx) {
+  return on_allocator_entry(PT_REGS_PARM1(ctx), 0);
+}
+
+SEC("uprobe")
+int on_calloc(struct pt_regs *ctx) {
+  return on_allocator_entry(PT_REGS_PARM1(ctx) * PT_REGS_PARM2(ctx), 0);
+}
+
+SEC("uprobe")
+int on_realloc(struct pt_regs *ctx) {
+  return on_allocator_entry(PT_REGS_PARM2(ctx), PT_REGS_PARM1(ctx));
+}
+
+// Reports the memory an allocator call returned, along with its stack, and the
+// memory `realloc` released.
+static __always_inline int report_allocation(struct pt_regs *ctx, allocation_t *allocation) {
+  u64 address = PT_REGS_RC(ctx);
+  // `realloc` only releases the previous memory if it succeeds or if the new
+  // size is zero.
+  if (allocation->freed_address != 0 && (address != 0 || allocation->size == 0)) {
+    send_free(ctx, allocation->freed_address);
+  }
+
+  if (address == 0) {
+    return 0;
+  }
+
+	struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+	unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+	int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+  if (!in_profiled_cgroup()) {
+    return 0;
+  }
+
   if (process_is_known(per_process_id)) {
     bump_unwind_total();
 
@@ -658,9 +1278,13 @@ int on_event(struct bpf_perf_event_data *ctx) {
       LOG("[error] profiler state should never be NULL");
       return 0;
     }
-    set_initial_state(profiler_state, &ctx->regs);
+    // We just returned to the caller of the allocator, so its stack is unwound.
+    set_initial_state(profiler_state, (bpf_user_pt_regs_t *)ctx);
+    profiler_state->sample.weight = allocation->size;
+    profiler_state->sample.kind = SAMPLE_KIND_ALLOCATION;
+    profiler_state->sample.address = address;
 
-    bpf_tail_call(ctx, &programs, PROGRAM_NATIVE_UNWINDER);
+    bpf_tail_call(ctx, &uprobe_programs, PROGRAM_NATIVE_UNWINDER);
     return 0;
   }
 
@@ -672,4 +1296,181 @@ int on_event(struct bpf_perf_event_data *ctx) {
   return 0;
 }
 
+SEC("uretprobe")
+int on_allocator_return(struct pt_regs *ctx) {
+  u64 pid_tgid = bpf_get_current_pid_tgid();
+  inflight_allocations_t *inflight = bpf_map_lookup_elem(&inflight_allocations, &pid_tgid);
+  if (inflight == NULL || inflight->depth == 0) {
+    return 0;
+  }
+  inflight->depth--;
+  if (inflight->depth > 0) {
+    return 0;
+  }
+
+  // The outermost call returned. Copy it before the entry is gone.
+  allocation_t allocation = inflight->outermost;
+  bpf_map_delete_elem(&inflight_allocations, &pid_tgid);
+  return report_allocation(ctx, &allocation);
+}
+
+SEC("uprobe")
+int on_free(struct pt_regs *ctx) {
+  u64 address = PT_REGS_PARM1(ctx);
+  if (address != 0) {
+    send_free(ctx, address);
+  }
+  return 0;
+}
+
+// Collects a stack every time a user-defined function or USDT probe is hit.
+SEC("uprobe")
+int on_user_probe(struct pt_regs *ctx) {
+	struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+	unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+	int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+  if (!in_profiled_cgroup()) {
+    return 0;
+  }
+
+  if (process_is_known(per_process_id)) {
+    bump_unwind_total();
+
+    u32 zero = 0;
+    unwind_state_t *profiler_state = bpf_map_lookup_elem(&heap, &zero);
+    if (profiler_state == NULL) {
+      LOG("[error] profiler state should never be NULL");
+      return 0;
+    }
+    set_initial_state(profiler_state, (bpf_user_pt_regs_t *)ctx);
+    profiler_state->sample.weight = 1;
+
+    bpf_tail_call(ctx, &uprobe_programs, PROGRAM_NATIVE_UNWINDER);
+    return 0;
+  }
+
+  Event event = {
+      .type = EVENT_NEW_PROCESS,
+      .pid = per_process_id,
+  };
+  send_event(&event, ctx);
+  return 0;
+}
+
+// Returns how a `futex(2)` operation waits, or `FUTEX_WAIT_NONE` for the
+// operations that don't.
+static __always_inline enum futex_wait futex_wait_for_op(int op) {
+  switch (op & FUTEX_CMD_MASK) {
+  case FUTEX_WAIT:
+    return FUTEX_WAIT_WAIT;
+  case FUTEX_WAIT_BITSET:
+    return FUTEX_WAIT_WAIT_BITSET;
+  case FUTEX_LOCK_PI:
+    return FUTEX_WAIT_LOCK_PI;
+  case FUTEX_WAIT_REQUEUE_PI:
+    return FUTEX_WAIT_WAIT_REQUEUE_PI;
+  case FUTEX_LOCK_PI2:
+    return FUTEX_WAIT_LOCK_PI2;
+  default:
+    return FUTEX_WAIT_NONE;
+  }
+}
+
+// Collects the stack of a task about to wait on the futex at `address`. The
+// sample is held until the syscall returns and we know for how long the task
+// waited.
+static __always_inline int collect_contention_sample(struct trace_event_raw_sys_enter *ctx, u64 address, enum futex_wait futex_wait) {
+  struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+  unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+  int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+  if (!in_profiled_cgroup()) {
+    return 0;
+  }
+
+  if (process_is_known(per_process_id)) {
+    bump_unwind_total();
+
+    u32 zero = 0;
+    unwind_state_t *profiler_state = bpf_map_lookup_elem(&heap, &zero);
+    if (profiler_state == NULL) {
+      LOG("[error] profiler state should never be NULL");
+      return 0;
+    }
+
+    // We are in the syscall, so the userspace registers are read from the task.
+    reset_unwind_state(profiler_state);
+    if (!retrieve_task_registers(&profiler_state->ip, &profiler_state->sp, &profiler_state->bp, &profiler_state->lr)) {
+      return 0;
+    }
+    profiler_state->defer_sample = true;
+    profiler_state->sample.kind = SAMPLE_KIND_CONTENTION;
+    profiler_state->sample.address = address;
+    profiler_state->sample.futex_wait = futex_wait;
+
+    bpf_tail_call(ctx, &tracepoint_programs, PROGRAM_NATIVE_UNWINDER);
+    return 0;
+  }
+
+  Event event = {
+      .type = EVENT_NEW_PROCESS,
+      .pid = per_process_id,
+  };
+  send_event(&event, ctx);
+  return 0;
+}
+
+// Sends the sample of a task that's done waiting on a futex, weighted by the
+// time it waited.
+static __always_inline int send_contention_sample(struct trace_event_raw_sys_exit *ctx) {
+  u32 tid = bpf_get_current_pid_tgid();
+  sample_t *contention_sample = bpf_map_lookup_elem(&off_cpu_samples, &tid);
+  if (contention_sample == NULL) {
+    return 0;
+  }
+
+  contention_sample->weight = bpf_ktime_get_boot_ns() - contention_sample->collected_at;
+  send_sample(ctx, contention_sample, lightswitch_config.deferred_sample_size);
+  bpf_map_delete_elem(&off_cpu_samples, &tid);
+  return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_futex")
+int on_futex_enter(struct trace_event_raw_sys_enter *ctx) {
+  enum futex_wait futex_wait = futex_wait_for_op(ctx->args[1]);
+  if (futex_wait == FUTEX_WAIT_NONE) {
+    return 0;
+  }
+
+  return collect_contention_sample(ctx, ctx->args[0], futex_wait);
+}
+
+SEC("tracepoint/syscalls/sys_exit_futex")
+int on_futex_exit(struct trace_event_raw_sys_exit *ctx) {
+  return send_contention_sample(ctx);
+}
+
+// `futex_waitv(2)` waits until any of the futexes it's passed is woken up. The
+// first one is used as the address of the sample.
+SEC("tracepoint/syscalls/sys_enter_futex_waitv")
+int on_futex_waitv_enter(struct trace_event_raw_sys_enter *ctx) {
+  struct futex_waitv_entry *waiters = (struct futex_waitv_entry *)ctx->args[0];
+  if (waiters == NULL || ctx->args[1] == 0) {
+    return 0;
+  }
+
+  u64 address = 0;
+  if (bpf_probe_read_user(&address, sizeof(address), &waiters->uaddr)) {
+    return 0;
+  }
+
+  return collect_contention_sample(ctx, address, FUTEX_WAIT_WAITV);
+}
+
+SEC("tracepoint/syscalls/sys_exit_futex_waitv")
+int on_futex_waitv_exit(struct trace_event_raw_sys_exit *ctx) {
+  return send_contention_sample(ctx);
+}
+
 char LICENSE[] SEC("license") = "Dual MIT/GPL";
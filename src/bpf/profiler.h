@@ -1,19 +1,31 @@
 #include "basic_types.h"
 
 // Number of frames to walk per tail call iteration.
-#define MAX_STACK_DEPTH_PER_PROGRAM 7
-// Number of BPF tail calls that will be attempted.
-#define MAX_TAIL_CALLS 19
-// Maximum number of frames.
-#define MAX_STACK_DEPTH 127
+#define MAX_STACK_DEPTH_PER_PROGRAM 17
+// Number of BPF tail calls that will be attempted. The kernel allows at most 33
+// per program invocation, one of them is used to start unwinding.
+#define MAX_TAIL_CALLS 31
+// Maximum number of frames. The actual limit is `max_stack_depth`, which can be
+// set at load time up to this value.
+#define MAX_STACK_DEPTH 512
+// Frames collected by default.
+#define DEFAULT_STACK_DEPTH 127
 _Static_assert(MAX_TAIL_CALLS *MAX_STACK_DEPTH_PER_PROGRAM >= MAX_STACK_DEPTH,
                "enough iterations to traverse the whole stack");
+_Static_assert(DEFAULT_STACK_DEPTH <= MAX_STACK_DEPTH, "default stack depth within bounds");
 // Number of unique stacks.
 #define MAX_STACK_TRACES_ENTRIES 64000
 // Number of items in the stack counts aggregation map.
 #define MAX_STACK_COUNTS_ENTRIES 10240
+// Length of the task names, `TASK_COMM_LEN` in the kernel.
+#define COMM_LEN 16
 // Maximum number of processes we are willing to track.
 #define MAX_PROCESSES 5000
+// Maximum number of threads that can be off-CPU, or waiting on a futex, with a pending sample.
+// Fewer are allowed with deep stacks, see `OFF_CPU_SAMPLES_MAX_BYTES`.
+#define MAX_OFF_CPU_SAMPLES 8192
+// Maximum number of threads that can be within an allocator call at once.
+#define MAX_INFLIGHT_ALLOCATIONS 8192
 // Maximum number of memory mappings entries we can store in the LPM trie. This is shared across all
 // the processes and assumes an average of 200 entries per process. These are LPM entries that in most
 // cases will be higher than the number of mappings.
@@ -59,6 +71,26 @@ typedef struct {
 #define CFA_TYPE_END_OF_FDE_MARKER      7
 #define CFA_TYPE_UNSUP_REGISTER_OFFSET  8   // not used in the unwinder yet.
 #define CFA_TYPE_OFFSET_DID_NOT_FIT     9
+// The CFA offset is the id of the expression in `cfa_expressions`.
+#define CFA_TYPE_EXPRESSION             10
+
+// Opcodes of the compact bytecode for the DWARF expressions that compute the CFA.
+// Each operation pops its operands from, and pushes its result to, a stack.
+#define CFA_EXPRESSION_OP_BREG_SP       1 // push(sp + operand)
+#define CFA_EXPRESSION_OP_BREG_FP       2 // push(fp + operand)
+#define CFA_EXPRESSION_OP_BREG_IP       3 // push(ip + operand)
+#define CFA_EXPRESSION_OP_DEREF         4 // push(*pop())
+#define CFA_EXPRESSION_OP_PLUS_UCONST   5 // push(pop() + operand)
+#define CFA_EXPRESSION_OP_AND           6 // push(pop() & pop())
+#define CFA_EXPRESSION_OP_LIT           7 // push(operand)
+#define CFA_EXPRESSION_OP_SHL           8 // b = pop(), a = pop(), push(a << b)
+
+// Maximum number of operations per expression.
+#define MAX_CFA_EXPRESSION_OPS 8
+// Maximum number of values an expression can push.
+#define MAX_CFA_EXPRESSION_STACK 3
+// Number of distinct expressions, shared across executables.
+#define MAX_CFA_EXPRESSIONS 1024
 
 // Values for the unwind table's frame pointer type.
 #define RBP_TYPE_UNCHANGED                0
@@ -78,6 +110,26 @@ struct lightswitch_config_t {
   bool verbose_logging;
   bool use_ring_buffers;
   bool use_task_pt_regs_helper;
+  // Only profile tasks within the cgroup stored in `cgroup_filter`.
+  bool filter_by_cgroup;
+  // Weight the samples of switched out tasks by the time they waited for a CPU
+  // once runnable, rather than by the time they were switched out.
+  bool runqueue_latency;
+  // Collect the kernel stacks of the idle task rather than discarding them.
+  bool keep_idle;
+  // Collect the kernel stacks of kernel threads rather than discarding them.
+  bool keep_kthreads;
+  // Follow the processes forked by the ones in `followed_processes`.
+  bool follow_children;
+  // The perf event fires from the timer interrupt, which must not be
+  // mistaken for the context that was interrupted.
+  bool timer_perf_event;
+  // Number of user frames after which unwinding stops, up to `MAX_STACK_DEPTH`.
+  // Kernel stacks are truncated to the same depth.
+  u32 max_stack_depth;
+  // Size of the values of `off_cpu_samples`, which only fit a `sample_t` with
+  // `max_stack_depth` user and kernel frames.
+  u32 deferred_sample_size;
 };
 
 struct unwinder_stats_t {
@@ -103,12 +155,22 @@ struct unwinder_stats_t {
   u64 bp_non_zero_for_bottom_frame;
   u64 vdso_encountered;
   u64 jit_encountered;
+  u64 success_frame_pointer;
+  u64 error_frame_pointer;
 };
 
 const volatile struct lightswitch_config_t lightswitch_config = {
     .verbose_logging = false,
     .use_ring_buffers = false,
     .use_task_pt_regs_helper = false,
+    .filter_by_cgroup = false,
+    .runqueue_latency = false,
+    .keep_idle = false,
+    .keep_kthreads = false,
+    .follow_children = false,
+    .timer_perf_event = false,
+    .max_stack_depth = DEFAULT_STACK_DEPTH,
+    .deferred_sample_size = sizeof(sample_t),
 };
 
 #define LOG(fmt, ...)                                                          \
@@ -149,6 +211,16 @@ typedef struct __attribute__((packed)) {
 _Static_assert(sizeof(stack_unwind_row_t) == 8,
                "unwind row has the expected size");
 
+typedef struct __attribute__((packed)) {
+  u8 opcode;
+  s32 operand;
+} cfa_expression_op_t;
+
+typedef struct __attribute__((packed)) {
+  u8 len;
+  cfa_expression_op_t ops[MAX_CFA_EXPRESSION_OPS];
+} cfa_expression_t;
+
 
 
 // The addresses of a native stack trace.
@@ -161,10 +233,116 @@ typedef struct {
   u64 addresses[MAX_STACK_DEPTH * 2];
 } native_stack_t;
 
+enum sample_kind {
+  // The task was running when the stack was collected.
+  SAMPLE_KIND_ON_CPU = 0,
+  // The task was switched out when the stack was collected.
+  SAMPLE_KIND_OFF_CPU = 1,
+  // The task returned from an allocator call.
+  SAMPLE_KIND_ALLOCATION = 2,
+  // The task freed some memory. These samples carry no stack.
+  SAMPLE_KIND_FREE = 3,
+  // The task waited on a futex.
+  SAMPLE_KIND_CONTENTION = 4,
+  // The task was runnable, waiting for a CPU.
+  SAMPLE_KIND_RUNQUEUE = 5,
+};
+
+// Context the stack was collected in.
+enum sample_context {
+  SAMPLE_CONTEXT_TASK = 0,
+  SAMPLE_CONTEXT_SOFTIRQ = 1,
+  SAMPLE_CONTEXT_HARDIRQ = 2,
+  SAMPLE_CONTEXT_NMI = 3,
+};
+
+// Why the native unwinder stopped before reaching the bottom of the stack. The
+// frames unwound until then are sent anyway.
+enum unwind_error {
+  UNWIND_ERROR_NONE = 0,
+  UNWIND_ERROR_MAPPING_NOT_FOUND = 1,
+  UNWIND_ERROR_MAPPING_DOES_NOT_CONTAIN_PC = 2,
+  UNWIND_ERROR_BINARY_SEARCH = 3,
+  UNWIND_ERROR_CFA_OFFSET_DID_NOT_FIT = 4,
+  UNWIND_ERROR_RBP_OFFSET_DID_NOT_FIT = 5,
+  UNWIND_ERROR_UNSUPPORTED_FRAME_POINTER_ACTION = 6,
+  UNWIND_ERROR_UNSUPPORTED_EXPRESSION = 7,
+  UNWIND_ERROR_UNSUPPORTED_CFA_REGISTER = 8,
+  UNWIND_ERROR_PREVIOUS_RSP_ZERO = 9,
+  UNWIND_ERROR_PREVIOUS_RBP_READ = 10,
+  UNWIND_ERROR_PREVIOUS_RIP_ZERO = 11,
+  UNWIND_ERROR_FRAME_POINTER = 12,
+  UNWIND_ERROR_TRUNCATED = 13,
+  UNWIND_ERROR_SHOULD_NEVER_HAPPEN = 14,
+  UNWIND_ERROR_PREVIOUS_RSP_READ = 15,
+};
+
+// `preempt_count` layout, from `include/linux/preempt.h`.
+#define SOFTIRQ_OFFSET          0x00000100
+#define HARDIRQ_OFFSET          0x00010000
+#define HARDIRQ_MASK            0x000f0000
+#define NMI_MASK                0x00f00000
+
+// `futex(2)` operations that wait, from `include/uapi/linux/futex.h`.
+#define FUTEX_WAIT              0
+#define FUTEX_LOCK_PI           6
+#define FUTEX_WAIT_BITSET       9
+#define FUTEX_WAIT_REQUEUE_PI   11
+#define FUTEX_LOCK_PI2          13
+#define FUTEX_CMD_MASK          0x7f
+
+// How the task of a contention sample waited. Runtimes tend to use different
+// operations for their locks and for their idle waits, such as glibc, whose
+// mutexes wait with FUTEX_WAIT and condition variables with FUTEX_WAIT_BITSET.
+enum futex_wait {
+  FUTEX_WAIT_NONE = 0,
+  FUTEX_WAIT_WAIT = 1,
+  FUTEX_WAIT_WAIT_BITSET = 2,
+  FUTEX_WAIT_LOCK_PI = 3,
+  FUTEX_WAIT_WAIT_REQUEUE_PI = 4,
+  FUTEX_WAIT_LOCK_PI2 = 5,
+  // `futex_waitv(2)`, which waits on several futexes at once.
+  FUTEX_WAIT_WAITV = 6,
+};
+
+// Entry of the array passed to `futex_waitv(2)`, from `include/uapi/linux/futex.h`.
+struct futex_waitv_entry {
+  u64 val;
+  u64 uaddr;
+  u32 flags;
+  u32 __reserved;
+};
+
 typedef struct {
   int pid;
   int tid;
   u64 collected_at;
+  // On-CPU samples store the period of the perf event, which is in nanoseconds
+  // for clock events. Off-CPU samples store the nanoseconds the task spent
+  // switched out. Allocation samples store the requested bytes. Contention
+  // samples store the nanoseconds the task waited on the futex. Run-queue
+  // samples store the nanoseconds the task waited for a CPU.
+  u64 weight;
+  // cgroup v2 id of the task.
+  u64 cgroup_id;
+  // Memory returned by the allocator or passed to `free`, for heap samples, or
+  // the futex waited on, for contention samples.
+  u64 address;
+  // One of `enum sample_kind`.
+  u32 kind;
+  // CPU the stack was collected on.
+  u32 cpu;
+  // One of `enum sample_context`.
+  u32 context;
+  // One of `enum unwind_error`.
+  u32 unwind_error;
+  // One of `enum futex_wait`, for contention samples.
+  u32 futex_wait;
+  // Keeps the stack 8-byte aligned.
+  u32 padding;
+  // Name of the task, only set for kernel threads and the idle task, as there's
+  // no process to read it from once the sample is processed.
+  char comm[COMM_LEN];
   native_stack_t stack;
 } sample_t;
 
@@ -174,9 +352,31 @@ typedef struct {
   unsigned long long bp;
   unsigned long long lr;
   u64 tail_calls;
+  // Whether the sample should be held until the task is switched back in, or
+  // until it's done waiting on a futex, rather than being sent straight away.
+  bool defer_sample;
+  // Whether any frame was unwound by following the frame pointers, as done in
+  // JIT code and code without unwind information.
+  bool used_frame_pointers;
   sample_t sample;
 } unwind_state_t;
 
+// Arguments of an allocator call, stored until it returns.
+typedef struct {
+  u64 size;
+  // Memory released by `realloc`, if any.
+  u64 freed_address;
+} allocation_t;
+
+// Allocator calls of a thread that haven't returned yet. Allocators might call
+// each other, such as a `realloc` implemented on top of `malloc`, but only the
+// outermost call is reported as the inner ones are implementation details.
+typedef struct {
+  allocation_t outermost;
+  // Number of nested calls in progress.
+  u32 depth;
+} inflight_allocations_t;
+
 enum event_type {
   EVENT_NEW_PROCESS = 1,
   EVENT_NEED_UNWIND_INFO = 2,
@@ -5,7 +5,7 @@
 use plain::Plain;
 use std::ops::Add;
 
-use crate::unwind_info::types::CompactUnwindRow;
+use crate::unwind_info::types::{CfaExpression, CompactUnwindRow};
 
 include!(concat!(env!("OUT_DIR"), "/profiler_bindings.rs"));
 
@@ -15,6 +15,7 @@ unsafe impl Plain for exec_mappings_key {}
 unsafe impl Plain for mapping_t {}
 unsafe impl Plain for page_key_t {}
 unsafe impl Plain for page_value_t {}
+unsafe impl Plain for cfa_expression_t {}
 
 impl exec_mappings_key {
     pub fn new(pid: u32, address: u64, prefix_len: u32) -> Self {
@@ -70,6 +71,8 @@ impl Add for unwinder_stats_t {
                 + other.bp_non_zero_for_bottom_frame,
             vdso_encountered: self.vdso_encountered + other.vdso_encountered,
             jit_encountered: self.jit_encountered + other.jit_encountered,
+            success_frame_pointer: self.success_frame_pointer + other.success_frame_pointer,
+            error_frame_pointer: self.error_frame_pointer + other.error_frame_pointer,
         }
     }
 }
@@ -87,3 +90,16 @@ impl From<&CompactUnwindRow> for stack_unwind_row_t {
         }
     }
 }
+
+impl From<&CfaExpression> for cfa_expression_t {
+    fn from(expression: &CfaExpression) -> Self {
+        let ops = expression.ops;
+        cfa_expression_t {
+            len: expression.len,
+            ops: ops.map(|op| cfa_expression_op_t {
+                opcode: op.opcode as u8,
+                operand: op.operand,
+            }),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+#include "vmlinux.h"
+#include "profiler.h"
+
+#include <bpf/bpf_core_read.h>
+#include <bpf/bpf_helpers.h>
+#include <bpf/bpf_tracing.h>
+
+#include "runqueue.h"
+
+#define TASK_RUNNING 0
+
+// `task_struct::state` was renamed to `__state` in Linux 5.14.
+struct task_struct___o {
+  volatile long int state;
+} __attribute__((preserve_access_index));
+
+static __always_inline long get_task_state(struct task_struct *task) {
+  if (bpf_core_field_exists(task->__state)) {
+    return BPF_CORE_READ(task, __state);
+  }
+  return BPF_CORE_READ((struct task_struct___o *)task, state);
+}
+
+static __always_inline int record_enqueue(u32 tid) {
+  // The idle task is never in a run queue.
+  if (tid == 0) {
+    return 0;
+  }
+
+  u64 now = bpf_ktime_get_boot_ns();
+  bpf_map_update_elem(&runqueue_enqueued_at, &tid, &now, BPF_ANY);
+  return 0;
+}
+
+SEC("tracepoint/sched/sched_wakeup")
+int runqueue_wakeup(struct trace_event_raw_sched_wakeup_template *ctx) {
+  return record_enqueue(ctx->pid);
+}
+
+SEC("tracepoint/sched/sched_wakeup_new")
+int runqueue_wakeup_new(struct trace_event_raw_sched_wakeup_template *ctx) {
+  return record_enqueue(ctx->pid);
+}
+
+SEC("tracepoint/sched/sched_switch")
+int runqueue_switch(struct trace_event_raw_sched_switch *ctx) {
+  // Tasks that are preempted are still runnable, so they go straight back to
+  // the run queue. The current task is the one being switched out.
+  struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+  if (get_task_state(task) == TASK_RUNNING) {
+    record_enqueue(ctx->prev_pid);
+  }
+  return 0;
+}
+
+char LICENSE[] SEC("license") = "Dual MIT/GPL";
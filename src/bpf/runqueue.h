@@ -0,0 +1,17 @@
+#ifndef __LIGHTSWITCH_RUNQUEUE__
+#define __LIGHTSWITCH_RUNQUEUE__
+
+// Maximum number of runnable threads waiting for a CPU that can be tracked at once.
+#define MAX_RUNQUEUE_TASKS 10240
+
+// When threads became runnable, keyed by thread id. Written by the run-queue
+// tracers and read by the profiler once the thread is switched in. Uses an LRU
+// as threads that exit while runnable won't ever be switched in.
+struct {
+  __uint(type, BPF_MAP_TYPE_LRU_HASH);
+  __uint(max_entries, MAX_RUNQUEUE_TASKS);
+  __type(key, u32);
+  __type(value, u64);
+} runqueue_enqueued_at SEC(".maps");
+
+#endif
@@ -15,4 +15,8 @@ static __always_inline bool process_is_known(int per_process_id) {
   key.data = 0;
 
   return bpf_map_lookup_elem(&exec_mappings, &key) != NULL;
+}
+
+static __always_inline bool process_is_denied(int per_process_id) {
+  return bpf_map_lookup_elem(&denied_processes, &per_process_id) != NULL;
 }
\ No newline at end of file
@@ -11,6 +11,15 @@ struct {
   __uint(max_entries, MAX_MAPPINGS);
 } exec_mappings SEC(".maps");
 
+// Processes that aren't profiled, so that no new process events are sent for
+// them. Entries are removed when they exec or exit.
+struct {
+  __uint(type, BPF_MAP_TYPE_LRU_HASH);
+  __uint(max_entries, 16384);
+  __type(key, int);
+  __type(value, bool);
+} denied_processes SEC(".maps");
+
 struct {
   __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
   __uint(max_entries, 1);
@@ -50,5 +59,7 @@ DEFINE_COUNTER(error_failure_sending_stack);
 DEFINE_COUNTER(bp_non_zero_for_bottom_frame);
 DEFINE_COUNTER(vdso_encountered);
 DEFINE_COUNTER(jit_encountered);
+DEFINE_COUNTER(success_frame_pointer);
+DEFINE_COUNTER(error_frame_pointer);
 
 #endif
@@ -12,6 +12,13 @@ typedef struct {
     u64 pid_tgid;
 } mmap_data_key_t;
 
+typedef struct {
+    u64 start_address;
+    u64 length;
+    u64 offset;
+    u64 flags;
+} mmap_data_t;
+
 struct {
   __uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
   __uint(key_size, sizeof(u32));
@@ -31,6 +38,28 @@ struct {
   __type(value, u64);
 } tracked_munmap SEC(".maps");
 
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(max_entries, 500);
+  __type(key, mmap_data_key_t);
+  __type(value, mmap_data_t);
+} tracked_mmap SEC(".maps");
+
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(max_entries, 500);
+  __type(key, mmap_data_key_t);
+  __type(value, mmap_data_t);
+} tracked_mprotect SEC(".maps");
+
+// Processes whose children are profiled too, along with their descendants.
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(max_entries, 16384);
+  __type(key, int);
+  __type(value, bool);
+} followed_processes SEC(".maps");
+
 // Arguments from
 // /sys/kernel/debug/tracing/events/syscalls/sys_enter_munmap/format
 struct munmap_entry_args {
@@ -43,6 +72,44 @@ struct munmap_entry_args {
     size_t len;
 };
 
+// Arguments from
+// /sys/kernel/debug/tracing/events/syscalls/sys_enter_mmap/format
+struct mmap_entry_args {
+    unsigned short common_type;
+    unsigned char common_flags;
+    unsigned char common_preempt_count;
+    int common_pid;
+    int __syscall_nr;
+    unsigned long addr;
+    unsigned long len;
+    unsigned long prot;
+    unsigned long flags;
+    unsigned long fd;
+    unsigned long off;
+};
+
+// Arguments from
+// /sys/kernel/debug/tracing/events/syscalls/sys_enter_mprotect/format
+struct mprotect_entry_args {
+    unsigned short common_type;
+    unsigned char common_flags;
+    unsigned char common_preempt_count;
+    int common_pid;
+    int __syscall_nr;
+    unsigned long start;
+    size_t len;
+    unsigned long prot;
+};
+
+#define PROT_EXEC 0x4
+#define MAX_ERRNO 4095
+
+static __always_inline int send_tracer_event(void *ctx, tracer_event_t *event) {
+    if (lightswitch_config.use_ring_buffers) {
+        return bpf_ringbuf_output(&tracer_events_rb, event, sizeof(tracer_event_t), 0);
+    }
+    return bpf_perf_event_output(ctx, &tracer_events, BPF_F_CURRENT_CPU, event, sizeof(tracer_event_t));
+}
 
 SEC("tracepoint/sched/sched_process_exit")
 int tracer_process_exit(void *ctx) {
@@ -52,12 +119,15 @@ int tracer_process_exit(void *ctx) {
     int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
     int per_thread_id = BPF_CORE_READ(task, thread_pid, numbers[level].nr);
 
-    if (!process_is_known(per_process_id)) {
+    // Only report main thread terminating.
+    if (per_process_id != per_thread_id) {
         return 0;
     }
 
-    // Only report main thread terminating.
-    if (per_process_id != per_thread_id) {
+    // The pid might be reused by a process that should be profiled.
+    bpf_map_delete_elem(&denied_processes, &per_process_id);
+    bool followed = bpf_map_delete_elem(&followed_processes, &per_process_id) == 0;
+    if (!process_is_known(per_process_id) && !followed) {
         return 0;
     }
 
@@ -67,12 +137,7 @@ int tracer_process_exit(void *ctx) {
         .start_address = 0,
     };
 
-    int ret = 0;
-    if (lightswitch_config.use_ring_buffers) {
-        ret = bpf_ringbuf_output(&tracer_events_rb, &event, sizeof(tracer_event_t), 0);
-    } else {
-        ret = bpf_perf_event_output(ctx, &tracer_events, BPF_F_CURRENT_CPU, &event, sizeof(tracer_event_t));
-    }
+    int ret = send_tracer_event(ctx, &event);
     if (ret < 0) {
         LOG("[error] failed to send process exit tracer event");
         return 0;
@@ -82,6 +147,78 @@ int tracer_process_exit(void *ctx) {
     return 0;
 }
 
+SEC("tracepoint/sched/sched_process_exec")
+int tracer_process_exec(void *ctx) {
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+    unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+    // The new executable might be profiled, so it has to be checked again.
+    bpf_map_delete_elem(&denied_processes, &per_process_id);
+
+    // The address space has been replaced, so the known mappings are stale.
+    if (!process_is_known(per_process_id)) {
+        return 0;
+    }
+
+    tracer_event_t event = {
+        .type = TRACER_EVENT_TYPE_PROCESS_EXEC,
+        .pid = per_process_id,
+        .start_address = 0,
+        .timestamp = bpf_ktime_get_boot_ns(),
+    };
+
+    int ret = send_tracer_event(ctx, &event);
+    if (ret < 0) {
+        LOG("[error] failed to send process exec tracer event");
+        return 0;
+    }
+
+    LOG("[debug] sent process exec tracer event");
+    return 0;
+}
+
+SEC("tp_btf/sched_process_fork")
+int BPF_PROG(tracer_process_fork, struct task_struct *parent, struct task_struct *child) {
+    if (!lightswitch_config.follow_children) {
+        return 0;
+    }
+
+    // New threads are reported too.
+    if (BPF_CORE_READ(child, pid) != BPF_CORE_READ(child, tgid)) {
+        return 0;
+    }
+
+    unsigned int level = BPF_CORE_READ(parent, nsproxy, pid_ns_for_children, level);
+    int parent_process_id = BPF_CORE_READ(parent, group_leader, thread_pid, numbers[level].nr);
+    int child_process_id = BPF_CORE_READ(child, thread_pid, numbers[level].nr);
+
+    if (bpf_map_lookup_elem(&followed_processes, &parent_process_id) == NULL) {
+        return 0;
+    }
+
+    // Follow the child right away so its own children are followed even if
+    // userspace hasn't processed this event yet.
+    bool followed = true;
+    bpf_map_update_elem(&followed_processes, &child_process_id, &followed, BPF_ANY);
+    bpf_map_delete_elem(&denied_processes, &child_process_id);
+
+    tracer_event_t event = {
+        .type = TRACER_EVENT_TYPE_PROCESS_FORK,
+        .pid = child_process_id,
+        .start_address = 0,
+    };
+
+    int ret = send_tracer_event(ctx, &event);
+    if (ret < 0) {
+        LOG("[error] failed to send process fork tracer event");
+        return 0;
+    }
+
+    LOG("[debug] sent process fork tracer event for %d", child_process_id);
+    return 0;
+}
+
 SEC("tracepoint/syscalls/sys_enter_munmap")
 int tracer_enter_munmap(struct munmap_entry_args *args) {
     u64 start_address = args->addr;
@@ -133,11 +270,7 @@ int tracer_exit_munmap(struct trace_event_raw_sys_exit *ctx) {
         .start_address = *start_address,
     };
 
-    if (lightswitch_config.use_ring_buffers) {
-        ret = bpf_ringbuf_output(&tracer_events_rb, &event, sizeof(tracer_event_t), 0);
-    } else {
-        ret = bpf_perf_event_output(ctx, &tracer_events, BPF_F_CURRENT_CPU, &event, sizeof(tracer_event_t));
-    }
+    ret = send_tracer_event(ctx, &event);
     if (ret < 0) {
         LOG("[error] failed to send munmap tracer event");
     }
@@ -145,4 +278,152 @@ int tracer_exit_munmap(struct trace_event_raw_sys_exit *ctx) {
     return 0;
 }
 
+SEC("tracepoint/syscalls/sys_enter_mmap")
+int tracer_enter_mmap(struct mmap_entry_args *args) {
+    if (!(args->prot & PROT_EXEC)) {
+        return 0;
+    }
+
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+    unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+    // New processes are read in full once they are seen.
+    if (!process_is_known(per_process_id)) {
+        return 0;
+    }
+
+    mmap_data_key_t key = {
+        .pid_tgid = bpf_get_current_pid_tgid(),
+    };
+    // The start address is only known once the memory is mapped.
+    mmap_data_t data = {
+        .start_address = 0,
+        .length = args->len,
+        .offset = args->off,
+        .flags = args->flags,
+    };
+    bpf_map_update_elem(&tracked_mmap, &key, &data, BPF_ANY);
+
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_exit_mmap")
+int tracer_exit_mmap(struct trace_event_raw_sys_exit *ctx) {
+    mmap_data_key_t key = {
+        .pid_tgid = bpf_get_current_pid_tgid(),
+    };
+
+    mmap_data_t *data = bpf_map_lookup_elem(&tracked_mmap, &key);
+    if (data == NULL) {
+        return 0;
+    }
+    u64 length = data->length;
+    u64 offset = data->offset;
+    u64 flags = data->flags;
+    bpf_map_delete_elem(&tracked_mmap, &key);
+
+    // Errors are returned as negative errno values.
+    u64 start_address = ctx->ret;
+    if (start_address >= (u64)-MAX_ERRNO) {
+        return 0;
+    }
+
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+    unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+    LOG("[debug] sending mmap event");
+
+    tracer_event_t event = {
+        .type = TRACER_EVENT_TYPE_MMAP,
+        .pid = per_process_id,
+        .start_address = start_address,
+        .length = length,
+        .offset = offset,
+        .flags = flags,
+    };
+
+    int ret = send_tracer_event(ctx, &event);
+    if (ret < 0) {
+        LOG("[error] failed to send mmap tracer event");
+    }
+
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_enter_mprotect")
+int tracer_enter_mprotect(struct mprotect_entry_args *args) {
+    if (!(args->prot & PROT_EXEC)) {
+        return 0;
+    }
+
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+    unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+    if (!process_is_known(per_process_id)) {
+        return 0;
+    }
+
+    // Memory that's already known to be executable, such as JIT code that's made
+    // writable and executable again, doesn't change the mappings.
+    mapping_t *mapping = find_mapping(per_process_id, args->start);
+    if (mapping != NULL && args->start >= mapping->begin && args->start + args->len <= mapping->end) {
+        return 0;
+    }
+
+    mmap_data_key_t key = {
+        .pid_tgid = bpf_get_current_pid_tgid(),
+    };
+    mmap_data_t data = {
+        .start_address = args->start,
+        .length = args->len,
+        .offset = 0,
+    };
+    bpf_map_update_elem(&tracked_mprotect, &key, &data, BPF_ANY);
+
+    return 0;
+}
+
+SEC("tracepoint/syscalls/sys_exit_mprotect")
+int tracer_exit_mprotect(struct trace_event_raw_sys_exit *ctx) {
+    mmap_data_key_t key = {
+        .pid_tgid = bpf_get_current_pid_tgid(),
+    };
+
+    mmap_data_t *data = bpf_map_lookup_elem(&tracked_mprotect, &key);
+    if (data == NULL) {
+        return 0;
+    }
+    u64 start_address = data->start_address;
+    u64 length = data->length;
+    bpf_map_delete_elem(&tracked_mprotect, &key);
+
+    if (ctx->ret != 0) {
+        return 0;
+    }
+
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+    unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+    LOG("[debug] sending mprotect event");
+
+    tracer_event_t event = {
+        .type = TRACER_EVENT_TYPE_MPROTECT,
+        .pid = per_process_id,
+        .start_address = start_address,
+        .length = length,
+        .offset = 0,
+    };
+
+    int ret = send_tracer_event(ctx, &event);
+    if (ret < 0) {
+        LOG("[error] failed to send mprotect tracer event");
+    }
+
+    return 0;
+}
+
 char LICENSE[] SEC("license") = "Dual MIT/GPL";
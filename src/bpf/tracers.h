@@ -3,10 +3,22 @@
 enum tracer_event_type {
     TRACER_EVENT_TYPE_PROCESS_EXIT = 1,
     TRACER_EVENT_TYPE_MUNMAP = 2,
+    TRACER_EVENT_TYPE_PROCESS_FORK = 3,
+    TRACER_EVENT_TYPE_PROCESS_EXEC = 4,
+    TRACER_EVENT_TYPE_MMAP = 5,
+    TRACER_EVENT_TYPE_MPROTECT = 6,
 };
 
 typedef struct {
     u32 type;
     int pid;
     u64 start_address;
+    // Length of the memory made executable by mmap or mprotect.
+    u64 length;
+    // File offset of the memory mapped by mmap.
+    u64 offset;
+    // Flags of the mmap call.
+    u64 flags;
+    // Boot time in nanoseconds when the process called exec, for exec events.
+    u64 timestamp;
 } tracer_event_t;
\ No newline at end of file
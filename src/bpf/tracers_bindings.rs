@@ -14,6 +14,20 @@ impl From<tracer_event_t> for TracerEvent {
             tracer_event_type_TRACER_EVENT_TYPE_MUNMAP => {
                 TracerEvent::Munmap(event.pid, event.start_address)
             }
+            tracer_event_type_TRACER_EVENT_TYPE_PROCESS_FORK => TracerEvent::ProcessFork(event.pid),
+            tracer_event_type_TRACER_EVENT_TYPE_PROCESS_EXEC => {
+                TracerEvent::ProcessExec(event.pid, event.timestamp)
+            }
+            tracer_event_type_TRACER_EVENT_TYPE_MMAP => TracerEvent::Mmap(
+                event.pid,
+                event.start_address,
+                event.length,
+                event.offset,
+                event.flags,
+            ),
+            tracer_event_type_TRACER_EVENT_TYPE_MPROTECT => {
+                TracerEvent::Mprotect(event.pid, event.start_address, event.length)
+            }
             _ => {
                 panic!("invalid event type {}, should never happen", event.type_);
             }
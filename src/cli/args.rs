@@ -1,12 +1,19 @@
 use clap::Parser;
 use clap::Subcommand;
+use regex::Regex;
 use std::path::PathBuf;
 use std::time::Duration;
 
+use lightswitch::bpf::profiler_bindings::MAX_STACK_DEPTH;
+use lightswitch::perf_events::PerfEventType;
+use lightswitch::probes::UserProbe;
 use lightswitch::profiler::ProfilerConfig;
 
+use crate::trigger::Trigger;
+use crate::validators::cpu_budget_in_range;
 use crate::validators::parse_duration;
 use crate::validators::sample_freq_in_range;
+use crate::validators::sample_period_is_positive;
 use crate::validators::value_is_power_of_two;
 
 #[derive(clap::ValueEnum, Debug, Clone, Default)]
@@ -19,6 +26,18 @@ pub(crate) enum LoggingLevel {
     Error,
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Default, PartialEq)]
+pub(crate) enum ProfilingMode {
+    #[default]
+    OnCpu,
+    OffCpu,
+    Wallclock,
+    Heap,
+    Probes,
+    Contention,
+    RunQueue,
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Default)]
 pub(crate) enum ProfileFormat {
     None,
@@ -60,9 +79,18 @@ pub(crate) enum DebugInfoBackend {
 
 #[derive(Subcommand, Debug)]
 pub(crate) enum Commands {
-    ObjectInfo { path: String },
-    ShowUnwind { path: String },
+    ObjectInfo {
+        path: String,
+    },
+    ShowUnwind {
+        path: String,
+    },
     SystemInfo,
+    /// Run a command and profile it, along with the processes it forks, until it exits
+    Run {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -70,10 +98,32 @@ pub(crate) struct CliArgs {
     /// Specific PIDs to profile
     #[arg(long)]
     pub(crate) pids: Vec<i32>,
+    /// Profile the processes whose name, as in /proc/<pid>/comm, matches this regex. Can be repeated
+    #[arg(long)]
+    pub(crate) comm: Vec<Regex>,
+    /// Profile the processes whose executable path matches this regex. Can be repeated
+    #[arg(long)]
+    pub(crate) exe: Vec<Regex>,
+    /// Profile the processes whose command line, with the arguments joined by spaces, matches this
+    /// regex. Can be repeated
+    #[arg(long)]
+    pub(crate) cmdline: Vec<Regex>,
+    /// Only profile the processes within this cgroup v2 subtree, such as /sys/fs/cgroup/system.slice
+    #[arg(long)]
+    pub(crate) cgroup: Option<PathBuf>,
     /// How long this agent will run in seconds
     #[arg(short='D', long, default_value = ProfilerConfig::default().duration.as_secs().to_string(),
         value_parser = parse_duration)]
     pub(crate) duration: Duration,
+    /// Only start recording once this condition is met. One of cpu:<pid>:<percent>:<seconds>
+    /// (the process used more than this percentage of a CPU for this many seconds),
+    /// file:<path> (the file exists) or signal:<SIGUSR1|SIGUSR2>. Can be repeated, the first
+    /// trigger that fires starts the recording
+    #[arg(long)]
+    pub(crate) trigger: Vec<Trigger>,
+    /// When a trigger fires, also keep the samples taken during this many seconds before it
+    #[arg(long, default_value = "0", value_parser = parse_duration)]
+    pub(crate) pre_roll: Duration,
     /// Enable libbpf logs. This includes the BPF verifier output
     #[arg(long)]
     pub(crate) libbpf_debug: bool,
@@ -90,6 +140,35 @@ pub(crate) struct CliArgs {
       value_parser = sample_freq_in_range,
     )]
     pub(crate) sample_freq: u64,
+    /// Take a sample every this many events instead of using the sampling frequency
+    #[arg(long, value_parser = sample_period_is_positive)]
+    pub(crate) sample_period: Option<u64>,
+    /// Adjust the sampling frequency at runtime to keep the CPU usage of the profiler under this
+    /// percentage of a CPU, between --min-sample-freq and --max-sample-freq
+    #[arg(long, value_parser = cpu_budget_in_range)]
+    pub(crate) cpu_budget: Option<f64>,
+    /// Lowest sampling frequency in Hz when --cpu-budget is given
+    #[arg(long, default_value_t = 2, value_parser = sample_freq_in_range)]
+    pub(crate) min_sample_freq: u64,
+    /// Highest sampling frequency in Hz when --cpu-budget is given
+    #[arg(long, default_value_t = 1009, value_parser = sample_freq_in_range)]
+    pub(crate) max_sample_freq: u64,
+    /// Event that triggers on-CPU samples. One of cpu-clock, task-clock, page-faults, minor-faults,
+    /// major-faults, context-switches, cpu-migrations, tracepoint:<id>, tracepoint:<category>:<name>
+    /// or kprobe:<function>. Every tracepoint and kprobe hit is sampled
+    #[arg(long, default_value_t = ProfilerConfig::default().perf_event)]
+    pub(crate) perf_event: PerfEventType,
+    /// What to profile. Off-CPU profiles are weighted by the time tasks spend switched out.
+    /// Heap profiles show the bytes allocated by, and still in use by, the processes given with --pids.
+    /// Probes profiles count the hits of the functions and USDT probes given with --probe.
+    /// Contention profiles are weighted by the time tasks spend waiting on futexes, labelled by lock.
+    /// Run-queue profiles are weighted by the time runnable tasks wait for a CPU.
+    #[arg(long, default_value_t, value_enum)]
+    pub(crate) mode: ProfilingMode,
+    /// Function or USDT probe that triggers the collection of a stack in probes mode. Either
+    /// uprobe:<binary>:<function> or usdt:<binary>:<provider>:<name>. Can be repeated
+    #[arg(long)]
+    pub(crate) probe: Vec<UserProbe>,
     /// Output file for Flame Graph in SVG format
     #[arg(long, default_value_t, value_enum)]
     pub(crate) profile_format: ProfileFormat,
@@ -126,6 +205,33 @@ pub(crate) struct CliArgs {
     // Exclude myself from profiling
     #[arg(long, help = "Do not profile the profiler (myself)")]
     pub(crate) exclude_self: bool,
+    #[arg(
+        long,
+        help = "Only open perf events for the threads of the processes given with --pids, and of the children they are followed to, rather than on every CPU"
+    )]
+    pub(crate) per_process_perf_events: bool,
+    #[arg(
+        long,
+        help = "When --pids, --comm, --exe or --cmdline are given, also profile the processes they fork and their descendants"
+    )]
+    pub(crate) follow_children: bool,
+    #[arg(
+        long,
+        help = "Keep the on-CPU samples of the idle task (pid 0), which only have a kernel stack, to account for idle CPU time"
+    )]
+    pub(crate) keep_idle: bool,
+    #[arg(
+        long,
+        help = "Keep the on-CPU samples of kernel threads, such as kworkers, which only have a kernel stack"
+    )]
+    pub(crate) keep_kthreads: bool,
+    #[arg(
+        long,
+        default_value_t = ProfilerConfig::default().max_stack_depth,
+        value_parser = clap::value_parser!(u32).range(1..=MAX_STACK_DEPTH as i64),
+        help = "Maximum number of user frames to unwind, and of kernel frames to collect. Deeper stacks are truncated"
+    )]
+    pub(crate) max_stack_depth: u32,
     #[arg(long, default_value_t, value_enum)]
     pub(crate) symbolizer: Symbolizer,
     #[arg(long, default_value_t, value_enum)]
@@ -13,15 +13,18 @@ use clap::Parser;
 use crossbeam_channel::bounded;
 use crossbeam_channel::tick;
 use inferno::flamegraph;
+use lightswitch::adaptive_frequency::AdaptiveFrequencyConfig;
 use lightswitch::collector::{AggregatorCollector, Collector, NullCollector, StreamingCollector};
 use lightswitch::debug_info::DebugInfoManager;
 use nix::unistd::Uid;
 use prost::Message;
-use tracing::{debug, error, info, Level};
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::FmtSubscriber;
 
-use lightswitch_capabilities::system_info::SystemInfo;
+use lightswitch_capabilities::system_info::{
+    get_trace_event_id, kprobe_function_exists, SystemInfo,
+};
 use lightswitch_metadata::metadata_provider::{
     GlobalMetadataProvider, ThreadSafeGlobalMetadataProvider,
 };
@@ -30,9 +33,11 @@ use lightswitch::debug_info::{
     DebugInfoBackendFilesystem, DebugInfoBackendNull, DebugInfoBackendRemote,
 };
 use lightswitch::kernel::kernel_build_id;
+use lightswitch::perf_events::{PerfEventConfig, PerfEventType};
+use lightswitch::process::ProcessMatcher;
 use lightswitch::profile::symbolize_profile;
 use lightswitch::profile::{fold_profile, to_pprof};
-use lightswitch::profiler::{Profiler, ProfilerConfig};
+use lightswitch::profiler::{Profiler, ProfilerConfig, ProfilerMode};
 use lightswitch::unwind_info::compact_unwind_info;
 use lightswitch::unwind_info::CompactUnwindInfoBuilder;
 use lightswitch_object::kernel::kaslr_offset;
@@ -40,6 +45,8 @@ use lightswitch_object::ObjectFile;
 
 mod args;
 mod killswitch;
+mod run;
+mod trigger;
 mod validators;
 
 use crate::args::CliArgs;
@@ -49,11 +56,14 @@ use crate::args::FlamegraphAggregation;
 use crate::args::LoggingLevel;
 use crate::args::ProfileFormat;
 use crate::args::ProfileSender;
+use crate::args::ProfilingMode;
 use crate::args::Symbolizer;
 use crate::killswitch::KillSwitch;
+use crate::trigger::TriggerWatcher;
 
 const DEFAULT_SERVER_URL: &str = "http://localhost:4567";
 static KILLSWITCH_POLL_INTERVAL: Duration = Duration::from_secs(5);
+static TRIGGER_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Exit the main thread if any thread panics. We prefer this behaviour because pretty much every
 /// thread is load bearing for the correct functioning.
@@ -102,8 +112,9 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    match args.command {
-        None => {} // record profiles by default
+    let command = match args.command {
+        None => None, // record profiles by default
+        Some(Commands::Run { command }) => Some(command),
         Some(Commands::ObjectInfo { path }) => {
             show_object_file_info(&path);
             return Ok(());
@@ -121,7 +132,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
             return Ok(());
         }
-    }
+    };
 
     if !Uid::current().is_root() {
         error!("root permissions are required to run lightswitch");
@@ -155,6 +166,94 @@ fn main() -> Result<(), Box<dyn Error>> {
     let metadata_provider: ThreadSafeGlobalMetadataProvider =
         Arc::new(Mutex::new(GlobalMetadataProvider::default()));
 
+    let mode = match args.mode {
+        ProfilingMode::OnCpu => ProfilerMode::OnCpu,
+        ProfilingMode::OffCpu => ProfilerMode::OffCpu,
+        ProfilingMode::Wallclock => ProfilerMode::Wallclock,
+        ProfilingMode::Heap => ProfilerMode::Heap,
+        ProfilingMode::Probes => ProfilerMode::Probes,
+        ProfilingMode::Contention => ProfilerMode::Contention,
+        ProfilingMode::RunQueue => ProfilerMode::RunQueue,
+    };
+
+    if args.min_sample_freq > args.max_sample_freq {
+        error!("--min-sample-freq can't be higher than --max-sample-freq");
+        std::process::exit(1);
+    }
+
+    if args.per_process_perf_events && args.pids.is_empty() {
+        error!(
+            "--per-process-perf-events requires the processes to profile to be passed with --pids"
+        );
+        std::process::exit(1);
+    }
+
+    if args.per_process_perf_events
+        && (!args.comm.is_empty() || !args.exe.is_empty() || !args.cmdline.is_empty())
+    {
+        error!("--per-process-perf-events can't be used with --comm, --exe or --cmdline");
+        std::process::exit(1);
+    }
+
+    if mode == ProfilerMode::Heap && args.pids.is_empty() {
+        error!("heap profiling requires the processes to profile to be passed with --pids");
+        std::process::exit(1);
+    }
+
+    if mode == ProfilerMode::Heap && command.is_some() {
+        error!("heap profiling can't be used with the run command");
+        std::process::exit(1);
+    }
+
+    if mode == ProfilerMode::Heap && !args.trigger.is_empty() {
+        error!("triggers can't be used for heap profiling");
+        std::process::exit(1);
+    }
+
+    if mode == ProfilerMode::Probes {
+        if args.probe.is_empty() {
+            error!("probes profiling requires at least one --probe");
+            std::process::exit(1);
+        }
+        for probe in &args.probe {
+            if let Err(e) = probe.resolve() {
+                error!("could not find probe {}: {}", probe, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if mode == ProfilerMode::Wallclock && !args.perf_event.is_clock() {
+        error!("wall-clock profiling requires a clock perf event (cpu-clock or task-clock)");
+        std::process::exit(1);
+    }
+
+    match &args.perf_event {
+        PerfEventType::NamedTracepoint { category, name } => {
+            if let Err(e) = get_trace_event_id(category, name) {
+                error!("tracepoint {}:{} not found: {}", category, name, e);
+                std::process::exit(1);
+            }
+        }
+        PerfEventType::Kprobe(function) => {
+            if !kprobe_function_exists(function).unwrap_or(false) {
+                error!("kernel function {} can't be probed", function);
+                std::process::exit(1);
+            }
+        }
+        _ => {}
+    }
+
+    if args.perf_event.is_trace_event() && args.sample_period.is_some() {
+        warn!("--sample-period is ignored for tracepoints and kprobes, every hit is sampled");
+    }
+
+    let perf_event_config = PerfEventConfig {
+        event: args.perf_event.clone(),
+        sample_freq: args.sample_freq,
+        sample_period: args.sample_period,
+    };
+
     let collector: Arc<Mutex<Box<dyn Collector + Send>>> =
         Arc::new(Mutex::new(match args.sender {
             ProfileSender::None => Box::new(NullCollector::new()),
@@ -164,7 +263,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                 args.symbolizer == Symbolizer::Local,
                 &server_url,
                 ProfilerConfig::default().session_duration,
-                args.sample_freq,
+                perf_event_config.clone(),
+                mode,
                 metadata_provider.clone(),
             )),
         }));
@@ -191,10 +291,32 @@ fn main() -> Result<(), Box<dyn Error>> {
         bpf_logging: args.bpf_logging,
         duration: args.duration,
         sample_freq: args.sample_freq,
+        sample_period: args.sample_period,
+        perf_event: args.perf_event,
+        mode,
+        probes: args.probe,
         perf_buffer_bytes: args.perf_buffer_bytes,
         mapsize_info: args.mapsize_info,
         mapsize_rate_limits: args.mapsize_rate_limits,
         exclude_self: args.exclude_self,
+        per_process_perf_events: args.per_process_perf_events,
+        follow_children: args.follow_children || command.is_some(),
+        process_matcher: ProcessMatcher {
+            comm: args.comm,
+            exe: args.exe,
+            cmdline: args.cmdline,
+        },
+        cgroup: args.cgroup,
+        adaptive_sample_freq: args
+            .cpu_budget
+            .map(|cpu_budget_pct| AdaptiveFrequencyConfig {
+                min_sample_freq: args.min_sample_freq,
+                max_sample_freq: args.max_sample_freq,
+                cpu_budget_pct,
+            }),
+        keep_idle: args.keep_idle,
+        keep_kthreads: args.keep_kthreads,
+        max_stack_depth: args.max_stack_depth,
         debug_info_manager,
         max_native_unwind_info_size_mb: args.max_native_unwind_info_size_mb,
         use_ring_buffers,
@@ -204,6 +326,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let (stop_signal_sender, stop_signal_receive) = bounded(1);
     let profiler_stop_signal_sender = stop_signal_sender.clone();
+    let command_stop_signal_sender = stop_signal_sender.clone();
     ctrlc::set_handler(move || {
         info!("received Ctrl+C, stopping...");
         let _ = profiler_stop_signal_sender.send(());
@@ -227,10 +350,79 @@ fn main() -> Result<(), Box<dyn Error>> {
         metadata_provider.clone(),
     );
     p.profile_pids(args.pids);
-    let profile_duration = p.run(collector.clone());
+
+    // Start a thread to run the command, which has to wait for the profiler to be ready
+    // to start running, and stop the profiler once the command exits
+    if let Some(command) = command {
+        let (pid_sender, pid_receive) = bounded(1);
+        let (ready_signal_sender, ready_signal_receive) = bounded(1);
+        let command_thread = thread::Builder::new().name("command-thread".to_string());
+        let _ = command_thread.spawn(move || {
+            let mut child = match run::spawn_stopped(&command) {
+                Ok(child) => child,
+                Err(e) => {
+                    error!("could not run {:?}: {}", command, e);
+                    std::process::exit(1);
+                }
+            };
+            let _ = pid_sender.send(child.id() as i32);
+
+            if ready_signal_receive.recv().is_err() {
+                let _ = child.kill();
+            } else if let Err(e) = run::resume(&child) {
+                error!("could not resume {:?}: {}", command, e);
+                let _ = child.kill();
+            }
+            match child.wait() {
+                Ok(status) => info!(
+                    "command exited with {}. Sending stop signal to profiler.",
+                    status
+                ),
+                Err(e) => error!("failed to wait for the command: {}", e),
+            }
+            let _ = command_stop_signal_sender.send(());
+        });
+        p.profile_pids(vec![pid_receive.recv().expect("receive command pid")]);
+        p.notify_when_ready(ready_signal_sender);
+    }
+
+    // Start a thread to start recording once any of the triggers fires
+    if !args.trigger.is_empty() {
+        let mut trigger_watcher = match TriggerWatcher::new(args.trigger) {
+            Ok(trigger_watcher) => trigger_watcher,
+            Err(e) => {
+                error!("could not set up the triggers: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let (start_signal_sender, start_signal_receive) = bounded(1);
+        let trigger_ticker = tick(TRIGGER_POLL_INTERVAL);
+        let trigger_poll_thread = thread::Builder::new().name("trigger-poll-thread".to_string());
+        let _ = trigger_poll_thread.spawn(move || loop {
+            if let Ok(now) = trigger_ticker.recv() {
+                if let Some(trigger) = trigger_watcher.poll(now) {
+                    info!(
+                        "trigger {} fired. Sending start signal to profiler.",
+                        trigger
+                    );
+                    let _ = start_signal_sender.send(());
+                    break;
+                }
+            }
+        });
+        p.start_on_trigger(start_signal_receive, args.pre_roll);
+    }
+    let profile_duration = match p.run(collector.clone()) {
+        Ok(profile_duration) => profile_duration,
+        Err(e) => {
+            error!("could not start profiling: {:?}", e);
+            std::process::exit(1);
+        }
+    };
 
     let collector = collector.lock().unwrap();
     let (mut profile, procs, objs) = collector.finish();
+    let session_sample_freqs = collector.session_sample_freqs();
 
     // If we need to send the profile to the backend there's nothing else to do.
     match args.sender {
@@ -253,6 +445,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             let folded = fold_profile(
                 profile,
                 args.flamegraph_aggregation == FlamegraphAggregation::Function,
+                mode,
             );
             let mut options: flamegraph::Options<'_> = flamegraph::Options::default();
             let data = folded.as_bytes();
@@ -279,7 +472,9 @@ fn main() -> Result<(), Box<dyn Error>> {
                 objs,
                 &metadata_provider,
                 profile_duration,
-                args.sample_freq,
+                &perf_event_config,
+                mode,
+                session_sample_freqs,
             );
             pprof_profile.encode(&mut buffer).unwrap();
             let profile_name = args.profile_name.unwrap_or_else(|| "profile.pb".into());
@@ -355,7 +550,7 @@ mod tests {
         cmd.arg("--help");
         cmd.assert().success();
         let actual = String::from_utf8(cmd.unwrap().stdout).unwrap();
-        insta::assert_yaml_snapshot!(actual, @r#""Usage: lightswitch [OPTIONS] [COMMAND]\n\nCommands:\n  object-info  \n  show-unwind  \n  system-info  \n  help         Print this message or the help of the given subcommand(s)\n\nOptions:\n      --pids <PIDS>\n          Specific PIDs to profile\n\n  -D, --duration <DURATION>\n          How long this agent will run in seconds\n          \n          [default: 18446744073709551615]\n\n      --libbpf-debug\n          Enable libbpf logs. This includes the BPF verifier output\n\n      --bpf-logging\n          Enable BPF programs logging\n\n      --logging <LOGGING>\n          Set lightswitch's logging level\n          \n          [default: info]\n          [possible values: trace, debug, info, warn, error]\n\n      --sample-freq <SAMPLE_FREQ_IN_HZ>\n          Per-CPU Sampling Frequency in Hz\n          \n          [default: 19]\n\n      --profile-format <PROFILE_FORMAT>\n          Output file for Flame Graph in SVG format\n          \n          [default: flame-graph]\n          [possible values: none, flame-graph, pprof]\n\n      --flamegraph-aggregation <FLAMEGRAPH_AGGREGATION>\n          What information to show in the flamegraph. Won't do anything for other profile formats\n          \n          [default: function]\n          [possible values: function, all]\n\n      --profile-path <PROFILE_PATH>\n          Path for the generated profile\n\n      --profile-name <PROFILE_NAME>\n          Name for the generated profile\n\n      --sender <SENDER>\n          Where to write the profile\n          \n          [default: local-disk]\n\n          Possible values:\n          - none:       Discard the profile. Used for kernel tests\n          - local-disk\n          - remote\n\n      --server-url <SERVER_URL>\n          \n\n      --token <TOKEN>\n          \n\n      --perf-buffer-bytes <PERF_BUFFER_BYTES>\n          Size of each profiler perf buffer, in bytes (must be a power of 2)\n          \n          [default: 524288]\n\n      --mapsize-info\n          Print eBPF map sizes after creation\n\n      --mapsize-rate-limits <MAPSIZE_RATE_LIMITS>\n          max number of rate limit entries\n          \n          [default: 5000]\n\n      --exclude-self\n          Do not profile the profiler (myself)\n\n      --symbolizer <SYMBOLIZER>\n          [default: local]\n          [possible values: local, none]\n\n      --debug-info-backend <DEBUG_INFO_BACKEND>\n          [default: none]\n          [possible values: none, copy, remote]\n\n      --max-native-unwind-info-size-mb <MAX_NATIVE_UNWIND_INFO_SIZE_MB>\n          approximate max size in megabytes used for the BPF maps that hold unwind information\n          \n          [default: 2147483647]\n\n      --enable-deadlock-detector\n          enable parking_lot's deadlock detector\n\n      --cache-dir-base <CACHE_DIR_BASE>\n          [default: /tmp]\n\n      --killswitch-path-override <KILLSWITCH_PATH_OVERRIDE>\n          Override the default path to the killswitch file (/tmp/lighswitch/killswitch) which prevents the profiler from starting\n\n      --unsafe-start\n          Force the profiler to start even if the system killswitch is enabled\n\n      --force-perf-buffer\n          force perf buffers even if ring buffers can be used\n\n  -h, --help\n          Print help (see a summary with '-h')\n""#);
+        insta::assert_yaml_snapshot!(actual, @r#""Usage: lightswitch [OPTIONS] [COMMAND]\n\nCommands:\n  object-info  \n  show-unwind  \n  system-info  \n  run          Run a command and profile it, along with the processes it forks, until it exits\n  help         Print this message or the help of the given subcommand(s)\n\nOptions:\n      --pids <PIDS>\n          Specific PIDs to profile\n\n      --comm <COMM>\n          Profile the processes whose name, as in /proc/<pid>/comm, matches this regex. Can be repeated\n\n      --exe <EXE>\n          Profile the processes whose executable path matches this regex. Can be repeated\n\n      --cmdline <CMDLINE>\n          Profile the processes whose command line, with the arguments joined by spaces, matches this regex. Can be repeated\n\n      --cgroup <CGROUP>\n          Only profile the processes within this cgroup v2 subtree, such as /sys/fs/cgroup/system.slice\n\n  -D, --duration <DURATION>\n          How long this agent will run in seconds\n          \n          [default: 18446744073709551615]\n\n      --trigger <TRIGGER>\n          Only start recording once this condition is met. One of cpu:<pid>:<percent>:<seconds> (the process used more than this percentage of a CPU for this many seconds), file:<path> (the file exists) or signal:<SIGUSR1|SIGUSR2>. Can be repeated, the first trigger that fires starts the recording\n\n      --pre-roll <PRE_ROLL>\n          When a trigger fires, also keep the samples taken during this many seconds before it\n          \n          [default: 0]\n\n      --libbpf-debug\n          Enable libbpf logs. This includes the BPF verifier output\n\n      --bpf-logging\n          Enable BPF programs logging\n\n      --logging <LOGGING>\n          Set lightswitch's logging level\n          \n          [default: info]\n          [possible values: trace, debug, info, warn, error]\n\n      --sample-freq <SAMPLE_FREQ_IN_HZ>\n          Per-CPU Sampling Frequency in Hz\n          \n          [default: 19]\n\n      --sample-period <SAMPLE_PERIOD>\n          Take a sample every this many events instead of using the sampling frequency\n\n      --cpu-budget <CPU_BUDGET>\n          Adjust the sampling frequency at runtime to keep the CPU usage of the profiler under this percentage of a CPU, between --min-sample-freq and --max-sample-freq\n\n      --min-sample-freq <MIN_SAMPLE_FREQ>\n          Lowest sampling frequency in Hz when --cpu-budget is given\n          \n          [default: 2]\n\n      --max-sample-freq <MAX_SAMPLE_FREQ>\n          Highest sampling frequency in Hz when --cpu-budget is given\n          \n          [default: 1009]\n\n      --perf-event <PERF_EVENT>\n          Event that triggers on-CPU samples. One of cpu-clock, task-clock, page-faults, minor-faults, major-faults, context-switches, cpu-migrations, tracepoint:<id>, tracepoint:<category>:<name> or kprobe:<function>. Every tracepoint and kprobe hit is sampled\n          \n          [default: cpu-clock]\n\n      --mode <MODE>\n          What to profile. Off-CPU profiles are weighted by the time tasks spend switched out. Heap profiles show the bytes allocated by, and still in use by, the processes given with --pids. Probes profiles count the hits of the functions and USDT probes given with --probe. Contention profiles are weighted by the time tasks spend waiting on futexes, labelled by lock. Run-queue profiles are weighted by the time runnable tasks wait for a CPU\n          \n          [default: on-cpu]\n          [possible values: on-cpu, off-cpu, wallclock, heap, probes, contention, run-queue]\n\n      --probe <PROBE>\n          Function or USDT probe that triggers the collection of a stack in probes mode. Either uprobe:<binary>:<function> or usdt:<binary>:<provider>:<name>. Can be repeated\n\n      --profile-format <PROFILE_FORMAT>\n          Output file for Flame Graph in SVG format\n          \n          [default: flame-graph]\n          [possible values: none, flame-graph, pprof]\n\n      --flamegraph-aggregation <FLAMEGRAPH_AGGREGATION>\n          What information to show in the flamegraph. Won't do anything for other profile formats\n          \n          [default: function]\n          [possible values: function, all]\n\n      --profile-path <PROFILE_PATH>\n          Path for the generated profile\n\n      --profile-name <PROFILE_NAME>\n          Name for the generated profile\n\n      --sender <SENDER>\n          Where to write the profile\n          \n          [default: local-disk]\n\n          Possible values:\n          - none:       Discard the profile. Used for kernel tests\n          - local-disk\n          - remote\n\n      --server-url <SERVER_URL>\n          \n\n      --token <TOKEN>\n          \n\n      --perf-buffer-bytes <PERF_BUFFER_BYTES>\n          Size of each profiler perf buffer, in bytes (must be a power of 2)\n          \n          [default: 524288]\n\n      --mapsize-info\n          Print eBPF map sizes after creation\n\n      --mapsize-rate-limits <MAPSIZE_RATE_LIMITS>\n          max number of rate limit entries\n          \n          [default: 5000]\n\n      --exclude-self\n          Do not profile the profiler (myself)\n\n      --per-process-perf-events\n          Only open perf events for the threads of the processes given with --pids, and of the children they are followed to, rather than on every CPU\n\n      --follow-children\n          When --pids, --comm, --exe or --cmdline are given, also profile the processes they fork and their descendants\n\n      --keep-idle\n          Keep the on-CPU samples of the idle task (pid 0), which only have a kernel stack, to account for idle CPU time\n\n      --keep-kthreads\n          Keep the on-CPU samples of kernel threads, such as kworkers, which only have a kernel stack\n\n      --max-stack-depth <MAX_STACK_DEPTH>\n          Maximum number of user frames to unwind, and of kernel frames to collect. Deeper stacks are truncated\n          \n          [default: 127]\n\n      --symbolizer <SYMBOLIZER>\n          [default: local]\n          [possible values: local, none]\n\n      --debug-info-backend <DEBUG_INFO_BACKEND>\n          [default: none]\n          [possible values: none, copy, remote]\n\n      --max-native-unwind-info-size-mb <MAX_NATIVE_UNWIND_INFO_SIZE_MB>\n          approximate max size in megabytes used for the BPF maps that hold unwind information\n          \n          [default: 2147483647]\n\n      --enable-deadlock-detector\n          enable parking_lot's deadlock detector\n\n      --cache-dir-base <CACHE_DIR_BASE>\n          [default: /tmp]\n\n      --killswitch-path-override <KILLSWITCH_PATH_OVERRIDE>\n          Override the default path to the killswitch file (/tmp/lighswitch/killswitch) which prevents the profiler from starting\n\n      --unsafe-start\n          Force the profiler to start even if the system killswitch is enabled\n\n      --force-perf-buffer\n          force perf buffers even if ring buffers can be used\n\n  -h, --help\n          Print help (see a summary with '-h')\n""#);
     }
 
     #[rstest]
@@ -0,0 +1,83 @@
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+
+/// Spawns `command` stopped at the first instruction of the new program, so it doesn't
+/// run until [`resume`] is called.
+///
+/// The command is traced until then, which means that [`resume`] has to be called from
+/// the same thread.
+pub(crate) fn spawn_stopped(command: &[String]) -> io::Result<Child> {
+    let Some((program, args)) = command.split_first() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+    };
+
+    let mut command = Command::new(program);
+    command.args(args);
+    // SAFETY: `ptrace(PTRACE_TRACEME)` is async-signal-safe. The child gets a SIGTRAP
+    // once `execve` succeeds.
+    unsafe {
+        command.pre_exec(|| ptrace::traceme().map_err(io::Error::from));
+    }
+    let mut child = command.spawn()?;
+
+    match waitpid(pid(&child), None) {
+        Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => Ok(child),
+        Ok(status) => {
+            let _ = child.kill();
+            Err(io::Error::other(format!(
+                "command didn't stop before running, status: {status:?}"
+            )))
+        }
+        Err(e) => {
+            let _ = child.kill();
+            Err(e.into())
+        }
+    }
+}
+
+/// Lets a command spawned with [`spawn_stopped`] run.
+pub(crate) fn resume(child: &Child) -> nix::Result<()> {
+    ptrace::detach(pid(child), None)
+}
+
+fn pid(child: &Child) -> Pid {
+    Pid::from_raw(child.id() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_spawn_stopped() {
+        let mut child = spawn_stopped(&[
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "exit 3".to_string(),
+        ])
+        .unwrap();
+
+        // The command has been executed, but it hasn't run yet.
+        let process = procfs::process::Process::new(child.id() as i32).unwrap();
+        assert_eq!(process.stat().unwrap().state, 't');
+        assert_eq!(
+            process.exe().unwrap().canonicalize().unwrap(),
+            Path::new("/bin/sh").canonicalize().unwrap()
+        );
+
+        resume(&child).unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn test_spawn_empty_command() {
+        assert!(spawn_stopped(&[]).is_err());
+    }
+}
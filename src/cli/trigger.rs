@@ -0,0 +1,250 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use tracing::debug;
+
+static TRIGGER_SIGNAL_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_trigger_signal(_signal: c_int) {
+    TRIGGER_SIGNAL_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Condition that starts the recording of profiles.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Trigger {
+    /// The process used more than `percent` of a CPU for at least `duration`.
+    Cpu {
+        pid: i32,
+        percent: f64,
+        duration: Duration,
+    },
+    /// The file at this path exists.
+    File(PathBuf),
+    /// The profiler received this signal, either SIGUSR1 or SIGUSR2.
+    Signal(Signal),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Cpu {
+                pid,
+                percent,
+                duration,
+            } => write!(f, "cpu:{pid}:{percent}:{}", duration.as_secs()),
+            Trigger::File(path) => write!(f, "file:{}", path.display()),
+            Trigger::Signal(signal) => write!(f, "signal:{signal}"),
+        }
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(trigger) = s.strip_prefix("cpu:") {
+            let expected = || format!("expected cpu:<pid>:<percent>:<seconds>, got `{s}'");
+            let mut parts = trigger.split(':');
+            let (Some(pid), Some(percent), Some(seconds), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(expected());
+            };
+            return Ok(Trigger::Cpu {
+                pid: pid.parse().map_err(|_| expected())?,
+                percent: percent.parse().map_err(|_| expected())?,
+                duration: Duration::from_secs(seconds.parse().map_err(|_| expected())?),
+            });
+        }
+
+        if let Some(path) = s.strip_prefix("file:") {
+            if path.is_empty() {
+                return Err(format!("expected file:<path>, got `{s}'"));
+            }
+            return Ok(Trigger::File(path.into()));
+        }
+
+        if let Some(signal) = s.strip_prefix("signal:") {
+            return match Signal::from_str(signal) {
+                Ok(signal @ (Signal::SIGUSR1 | Signal::SIGUSR2)) => Ok(Trigger::Signal(signal)),
+                _ => Err(format!(
+                    "expected signal:SIGUSR1 or signal:SIGUSR2, got `{s}'"
+                )),
+            };
+        }
+
+        Err(format!("unknown trigger `{s}'"))
+    }
+}
+
+/// CPU time of a process the last time it was polled, and since when it's been
+/// over the threshold.
+struct CpuUsage {
+    polled_at: Instant,
+    cpu_time: Duration,
+    over_threshold_since: Option<Instant>,
+}
+
+fn process_cpu_time(pid: i32) -> Option<Duration> {
+    let stat = procfs::process::Process::new(pid).ok()?.stat().ok()?;
+    Some(Duration::from_secs_f64(
+        (stat.utime + stat.stime) as f64 / procfs::ticks_per_second() as f64,
+    ))
+}
+
+/// Polls the triggers until one of them fires.
+pub(crate) struct TriggerWatcher {
+    triggers: Vec<Trigger>,
+    cpu_usage: Vec<Option<CpuUsage>>,
+}
+
+impl TriggerWatcher {
+    /// Installs the handlers of the signals used as triggers.
+    pub(crate) fn new(triggers: Vec<Trigger>) -> Result<Self, nix::Error> {
+        for trigger in &triggers {
+            if let Trigger::Signal(signal) = trigger {
+                let action = SigAction::new(
+                    SigHandler::Handler(handle_trigger_signal),
+                    SaFlags::SA_RESTART,
+                    SigSet::empty(),
+                );
+                unsafe { sigaction(*signal, &action) }?;
+            }
+        }
+
+        Ok(TriggerWatcher {
+            cpu_usage: triggers.iter().map(|_| None).collect(),
+            triggers,
+        })
+    }
+
+    /// Returns the first trigger that fired, if any.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<&Trigger> {
+        let signal_received = TRIGGER_SIGNAL_RECEIVED.load(Ordering::SeqCst);
+
+        for (trigger, cpu_usage) in self.triggers.iter().zip(self.cpu_usage.iter_mut()) {
+            let fired = match trigger {
+                Trigger::Cpu {
+                    pid,
+                    percent,
+                    duration,
+                } => {
+                    let cpu_time = process_cpu_time(*pid);
+                    let previous = cpu_usage.take();
+                    match (previous, cpu_time) {
+                        (Some(previous), Some(cpu_time)) => {
+                            let elapsed = now.duration_since(previous.polled_at).as_secs_f64();
+                            let usage = if elapsed == 0.0 {
+                                0.0
+                            } else {
+                                100.0 * cpu_time.saturating_sub(previous.cpu_time).as_secs_f64()
+                                    / elapsed
+                            };
+                            debug!("process {} CPU usage: {:.2}%", pid, usage);
+                            let over_threshold_since = if usage > *percent {
+                                previous.over_threshold_since.or(Some(previous.polled_at))
+                            } else {
+                                None
+                            };
+                            *cpu_usage = Some(CpuUsage {
+                                polled_at: now,
+                                cpu_time,
+                                over_threshold_since,
+                            });
+                            over_threshold_since
+                                .is_some_and(|since| now.duration_since(since) >= *duration)
+                        }
+                        (None, Some(cpu_time)) => {
+                            *cpu_usage = Some(CpuUsage {
+                                polled_at: now,
+                                cpu_time,
+                                over_threshold_since: None,
+                            });
+                            false
+                        }
+                        // The process doesn't exist (yet).
+                        (_, None) => false,
+                    }
+                }
+                Trigger::File(path) => Path::new(path).exists(),
+                Trigger::Signal(_) => signal_received,
+            };
+
+            if fired {
+                return Some(trigger);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd;
+
+    #[test]
+    fn test_trigger_roundtrip() {
+        for trigger in [
+            Trigger::Cpu {
+                pid: 1234,
+                percent: 80.5,
+                duration: Duration::from_secs(10),
+            },
+            Trigger::File("/tmp/lightswitch/start".into()),
+            Trigger::Signal(Signal::SIGUSR1),
+        ] {
+            assert_eq!(trigger.to_string().parse::<Trigger>(), Ok(trigger));
+        }
+
+        assert!("cpu:1234:80".parse::<Trigger>().is_err());
+        assert!("cpu:1234:80:10:1".parse::<Trigger>().is_err());
+        assert!("file:".parse::<Trigger>().is_err());
+        assert!("signal:SIGKILL".parse::<Trigger>().is_err());
+        assert!("timer:10".parse::<Trigger>().is_err());
+    }
+
+    #[test]
+    fn test_file_trigger() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut watcher = TriggerWatcher::new(vec![
+            Trigger::File("/this/file/does/not/exist".into()),
+            Trigger::File(file.path().into()),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            watcher.poll(Instant::now()),
+            Some(&Trigger::File(file.path().into()))
+        );
+    }
+
+    #[test]
+    fn test_cpu_trigger() {
+        let pid = unistd::getpid().as_raw();
+        let mut watcher = TriggerWatcher::new(vec![Trigger::Cpu {
+            pid,
+            percent: 0.0,
+            duration: Duration::from_secs(1),
+        }])
+        .unwrap();
+
+        let start = Instant::now();
+        assert_eq!(watcher.poll(start), None);
+
+        // Burn some CPU so the usage is over the threshold.
+        let busy_until = Instant::now() + Duration::from_millis(100);
+        while Instant::now() < busy_until {}
+        assert_eq!(watcher.poll(start + Duration::from_millis(500)), None);
+
+        let busy_until = Instant::now() + Duration::from_millis(100);
+        while Instant::now() < busy_until {}
+        assert!(watcher.poll(start + Duration::from_secs(1)).is_some());
+    }
+}
@@ -4,6 +4,7 @@ use std::ops::RangeInclusive;
 use std::time::Duration;
 
 const SAMPLE_FREQ_RANGE: RangeInclusive<u64> = 1..=1009;
+const CPU_BUDGET_RANGE: RangeInclusive<f64> = 0.1..=100.0;
 
 pub(crate) fn parse_duration(arg: &str) -> Result<Duration, std::num::ParseIntError> {
     let seconds = arg.parse()?;
@@ -35,6 +36,31 @@ pub(crate) fn sample_freq_in_range(s: &str) -> Result<u64, String> {
     Ok(sample_freq)
 }
 
+pub(crate) fn sample_period_is_positive(s: &str) -> Result<u64, String> {
+    let sample_period: u64 = s
+        .parse()
+        .map_err(|_| format!("`{s}' isn't a valid period"))?;
+    // A period of zero counts the events without ever sampling them.
+    if sample_period == 0 {
+        return Err("sample period must be greater than 0".to_string());
+    }
+    Ok(sample_period)
+}
+
+pub(crate) fn cpu_budget_in_range(s: &str) -> Result<f64, String> {
+    let cpu_budget: f64 = s
+        .parse()
+        .map_err(|_| format!("`{s}' isn't a valid percentage"))?;
+    if !CPU_BUDGET_RANGE.contains(&cpu_budget) {
+        return Err(format!(
+            "CPU budget not in allowed range {}-{}%",
+            CPU_BUDGET_RANGE.start(),
+            CPU_BUDGET_RANGE.end()
+        ));
+    }
+    Ok(cpu_budget)
+}
+
 // Convert a &str into a usize, if possible, and return the result if it's a
 // power of 2
 pub(crate) fn value_is_power_of_two(s: &str) -> Result<usize, String> {
@@ -71,6 +97,24 @@ mod tests {
 
     use rstest::rstest;
 
+    #[rstest]
+    #[case("3", Ok(3.0))]
+    #[case("0.5", Ok(0.5))]
+    #[case("0", Err("CPU budget not in allowed range 0.1-100%".to_string()))]
+    #[case("abc", Err("`abc' isn't a valid percentage".to_string()))]
+    fn test_cpu_budget_in_range(#[case] budget: &str, #[case] expected: Result<f64, String>) {
+        assert_eq!(cpu_budget_in_range(budget), expected);
+    }
+
+    #[rstest]
+    #[case("1", Ok(1))]
+    #[case("100000", Ok(100000))]
+    #[case("0", Err("sample period must be greater than 0".to_string()))]
+    #[case("-1", Err("`-1' isn't a valid period".to_string()))]
+    fn test_sample_period_is_positive(#[case] period: &str, #[case] expected: Result<u64, String>) {
+        assert_eq!(sample_period_is_positive(period), expected);
+    }
+
     #[rstest]
     #[case(49, (47,53), "")]
     #[case(97, (0, 0), "97 is prime")]
@@ -4,21 +4,27 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tracing::{debug, span, Level};
 
+use crate::perf_events::PerfEventConfig;
 use crate::process::ObjectFileInfo;
 use crate::process::ProcessInfo;
 use crate::profile::raw_to_processed;
 use crate::profile::AggregatedProfile;
 use crate::profile::AggregatedSample;
 use crate::profile::RawAggregatedProfile;
+use crate::profile::SampleKind;
 use crate::profile::{symbolize_profile, to_pprof};
+use crate::profiler::ProfilerMode;
 use lightswitch_object::ExecutableId;
 
 use lightswitch_metadata::metadata_provider::ThreadSafeGlobalMetadataProvider;
 
 pub trait Collector {
+    /// Receives the profile of a session along with the sampling frequency that
+    /// was in effect during it, which might change between sessions.
     fn collect(
         &mut self,
         profile: RawAggregatedProfile,
+        sample_freq: u64,
         procs: &HashMap<i32, ProcessInfo>,
         objs: &HashMap<ExecutableId, ObjectFileInfo>,
     );
@@ -29,6 +35,8 @@ pub trait Collector {
         &HashMap<i32, ProcessInfo>,
         &HashMap<ExecutableId, ObjectFileInfo>,
     );
+    /// Sampling frequency of each of the sessions in the profile returned by `finish`.
+    fn session_sample_freqs(&self) -> &[u64];
 }
 
 pub type ThreadSafeCollector = Arc<Mutex<Box<dyn Collector + Send>>>;
@@ -50,6 +58,7 @@ impl Collector for NullCollector {
     fn collect(
         &mut self,
         _profile: RawAggregatedProfile,
+        _sample_freq: u64,
         _procs: &HashMap<i32, ProcessInfo>,
         _objs: &HashMap<ExecutableId, ObjectFileInfo>,
     ) {
@@ -64,6 +73,10 @@ impl Collector for NullCollector {
     ) {
         (AggregatedProfile::new(), &self.procs, &self.objs)
     }
+
+    fn session_sample_freqs(&self) -> &[u64] {
+        &[]
+    }
 }
 
 #[derive(Default)]
@@ -73,7 +86,8 @@ pub struct StreamingCollector {
     pprof_ingest_url: String,
     http_client_timeout: Duration,
     profile_duration: Duration,
-    profile_frequency_hz: u64,
+    perf_event_config: PerfEventConfig,
+    mode: ProfilerMode,
     procs: HashMap<i32, ProcessInfo>,
     objs: HashMap<ExecutableId, ObjectFileInfo>,
     metadata_provider: ThreadSafeGlobalMetadataProvider,
@@ -85,7 +99,8 @@ impl StreamingCollector {
         local_symbolizer: bool,
         pprof_ingest_url: &str,
         profile_duration: Duration,
-        profile_frequency_hz: u64,
+        perf_event_config: PerfEventConfig,
+        mode: ProfilerMode,
         metadata_provider: ThreadSafeGlobalMetadataProvider,
     ) -> Self {
         Self {
@@ -94,7 +109,8 @@ impl StreamingCollector {
             pprof_ingest_url: format!("{pprof_ingest_url}/pprof/new"),
             http_client_timeout: Duration::from_secs(30),
             profile_duration,
-            profile_frequency_hz,
+            perf_event_config,
+            mode,
             metadata_provider,
             ..Default::default()
         }
@@ -106,6 +122,7 @@ impl Collector for StreamingCollector {
     fn collect(
         &mut self,
         profile: RawAggregatedProfile,
+        sample_freq: u64,
         procs: &HashMap<i32, ProcessInfo>,
         objs: &HashMap<ExecutableId, ObjectFileInfo>,
     ) {
@@ -122,7 +139,12 @@ impl Collector for StreamingCollector {
             objs,
             &self.metadata_provider,
             self.profile_duration,
-            self.profile_frequency_hz,
+            &PerfEventConfig {
+                sample_freq,
+                ..self.perf_event_config.clone()
+            },
+            self.mode,
+            &[sample_freq],
         );
 
         let client_builder = reqwest::blocking::Client::builder().timeout(self.http_client_timeout);
@@ -146,11 +168,16 @@ impl Collector for StreamingCollector {
     ) {
         (AggregatedProfile::new(), &self.procs, &self.objs)
     }
+
+    fn session_sample_freqs(&self) -> &[u64] {
+        &[]
+    }
 }
 
 #[derive(Default)]
 pub struct AggregatorCollector {
     profiles: Vec<AggregatedProfile>,
+    sample_freqs: Vec<u64>,
     procs: HashMap<i32, ProcessInfo>,
     objs: HashMap<ExecutableId, ObjectFileInfo>,
 }
@@ -166,11 +193,13 @@ impl Collector for AggregatorCollector {
     fn collect(
         &mut self,
         raw_profile: RawAggregatedProfile,
+        sample_freq: u64,
         procs: &HashMap<i32, ProcessInfo>,
         objs: &HashMap<ExecutableId, ObjectFileInfo>,
     ) {
         self.profiles
             .push(raw_to_processed(&raw_profile, procs, objs));
+        self.sample_freqs.push(sample_freq);
 
         for (k, v) in procs {
             self.procs.insert(*k, v.clone());
@@ -191,29 +220,45 @@ impl Collector for AggregatorCollector {
         let _span = span!(Level::DEBUG, "AggregatorCollector.finish").entered();
 
         let mut samples_count = HashMap::new();
-        for profile in &self.profiles {
+        let last_profile = self.profiles.len().saturating_sub(1);
+        for (i, profile) in self.profiles.iter().enumerate() {
             for sample in profile {
+                // The memory in use is a snapshot taken at the end of every session, only
+                // the latest one is relevant.
+                if sample.kind == SampleKind::InUse && i != last_profile {
+                    continue;
+                }
                 let sample_without_count = AggregatedSample {
                     count: 0,
+                    weight: 0,
                     ustack: sample.ustack.clone(),
                     kstack: sample.kstack.clone(),
+                    comm: sample.comm.clone(),
                     ..*sample
                 };
-                *samples_count.entry(sample_without_count).or_insert(0) += sample.count
+                let (count, weight) = samples_count.entry(sample_without_count).or_insert((0, 0));
+                *count += sample.count;
+                *weight += sample.weight;
             }
         }
 
         debug!("found {} unique samples", samples_count.len());
         let profile = samples_count
             .iter()
-            .map(|(sample, count)| AggregatedSample {
+            .map(|(sample, (count, weight))| AggregatedSample {
                 count: *count,
+                weight: *weight,
                 ustack: sample.ustack.clone(),
                 kstack: sample.kstack.clone(),
+                comm: sample.comm.clone(),
                 ..*sample
             })
             .collect();
 
         (profile, &self.procs, &self.objs)
     }
+
+    fn session_sample_freqs(&self) -> &[u64] {
+        &self.sample_freqs
+    }
 }
@@ -1,3 +1,4 @@
+pub mod adaptive_frequency;
 pub mod aggregator;
 pub mod bpf;
 pub mod collector;
@@ -5,6 +6,7 @@ pub mod debug_info;
 pub mod kernel;
 pub mod ksym;
 pub mod perf_events;
+pub mod probes;
 pub mod process;
 pub mod profile;
 pub mod profiler;
@@ -1,20 +1,265 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs::read_to_string;
 use std::io;
 use std::os::raw::c_int;
+use std::str::FromStr;
 
+use lightswitch_capabilities::system_info::get_trace_event_id;
 use perf_event_open_sys as sys;
 use perf_event_open_sys::bindings::perf_event_attr;
 
-/// # Safety
-pub unsafe fn setup_perf_event(cpu: i32, sample_freq: u64) -> Result<c_int, io::Error> {
+const KPROBE_PMU_TYPE_PATH: &str = "/sys/bus/event_source/devices/kprobe/type";
+
+/// Event that triggers on-CPU samples.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PerfEventType {
+    #[default]
+    CpuClock,
+    TaskClock,
+    PageFaults,
+    MinorFaults,
+    MajorFaults,
+    ContextSwitches,
+    CpuMigrations,
+    /// Tracepoint id, as found in `/sys/kernel/tracing/events/<category>/<name>/id`.
+    Tracepoint(u64),
+    /// Tracepoint given by its name, such as `block:block_rq_issue`.
+    NamedTracepoint {
+        category: String,
+        name: String,
+    },
+    /// Entry of a kernel function.
+    Kprobe(String),
+}
+
+impl PerfEventType {
+    /// The `perf_event_attr` type and config for this event.
+    fn type_and_config(&self) -> Result<(u32, u64), io::Error> {
+        let software = |config: u32| (sys::bindings::PERF_TYPE_SOFTWARE, config as u64);
+        Ok(match self {
+            PerfEventType::CpuClock => software(sys::bindings::PERF_COUNT_SW_CPU_CLOCK),
+            PerfEventType::TaskClock => software(sys::bindings::PERF_COUNT_SW_TASK_CLOCK),
+            PerfEventType::PageFaults => software(sys::bindings::PERF_COUNT_SW_PAGE_FAULTS),
+            PerfEventType::MinorFaults => software(sys::bindings::PERF_COUNT_SW_PAGE_FAULTS_MIN),
+            PerfEventType::MajorFaults => software(sys::bindings::PERF_COUNT_SW_PAGE_FAULTS_MAJ),
+            PerfEventType::ContextSwitches => {
+                software(sys::bindings::PERF_COUNT_SW_CONTEXT_SWITCHES)
+            }
+            PerfEventType::CpuMigrations => software(sys::bindings::PERF_COUNT_SW_CPU_MIGRATIONS),
+            PerfEventType::Tracepoint(id) => (sys::bindings::PERF_TYPE_TRACEPOINT, *id),
+            PerfEventType::NamedTracepoint { category, name } => {
+                let id = get_trace_event_id(category, name).map_err(io::Error::other)?;
+                (sys::bindings::PERF_TYPE_TRACEPOINT, id as u64)
+            }
+            PerfEventType::Kprobe(_) => {
+                let kprobe_type = read_to_string(KPROBE_PMU_TYPE_PATH)?
+                    .trim()
+                    .parse()
+                    .map_err(io::Error::other)?;
+                // The function is passed in `config1`. A zero config means it's not a kretprobe.
+                (kprobe_type, 0)
+            }
+        })
+    }
+
+    /// Whether the event counts nanoseconds rather than occurrences.
+    pub fn is_clock(&self) -> bool {
+        matches!(self, PerfEventType::CpuClock | PerfEventType::TaskClock)
+    }
+
+    /// Whether the event is a tracepoint or kprobe. BPF programs attached to them run
+    /// on every hit, so every hit is sampled.
+    pub fn is_trace_event(&self) -> bool {
+        matches!(
+            self,
+            PerfEventType::Tracepoint(_)
+                | PerfEventType::NamedTracepoint { .. }
+                | PerfEventType::Kprobe(_)
+        )
+    }
+
+    /// Whether the event is delivered from the timer interrupt rather than
+    /// from the context that was interrupted.
+    pub fn is_timer_event(&self) -> bool {
+        matches!(self, PerfEventType::CpuClock | PerfEventType::TaskClock)
+    }
+
+    /// Type and unit used for the pprof `sample_type` and `period_type`.
+    pub fn sample_type(&self) -> (String, &'static str) {
+        let r#type = match self {
+            // Kept as `cpu` as that's what profiles have always reported.
+            PerfEventType::CpuClock => "cpu".to_string(),
+            PerfEventType::TaskClock => "task_clock".to_string(),
+            PerfEventType::PageFaults => "page_faults".to_string(),
+            PerfEventType::MinorFaults => "minor_faults".to_string(),
+            PerfEventType::MajorFaults => "major_faults".to_string(),
+            PerfEventType::ContextSwitches => "context_switches".to_string(),
+            PerfEventType::CpuMigrations => "cpu_migrations".to_string(),
+            PerfEventType::Tracepoint(id) => format!("tracepoint_{id}"),
+            PerfEventType::NamedTracepoint { category, name } => format!("{category}:{name}"),
+            PerfEventType::Kprobe(function) => function.clone(),
+        };
+        let unit = if self.is_clock() {
+            "nanoseconds"
+        } else {
+            "count"
+        };
+
+        (r#type, unit)
+    }
+}
+
+impl fmt::Display for PerfEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PerfEventType::CpuClock => write!(f, "cpu-clock"),
+            PerfEventType::TaskClock => write!(f, "task-clock"),
+            PerfEventType::PageFaults => write!(f, "page-faults"),
+            PerfEventType::MinorFaults => write!(f, "minor-faults"),
+            PerfEventType::MajorFaults => write!(f, "major-faults"),
+            PerfEventType::ContextSwitches => write!(f, "context-switches"),
+            PerfEventType::CpuMigrations => write!(f, "cpu-migrations"),
+            PerfEventType::Tracepoint(id) => write!(f, "tracepoint:{id}"),
+            PerfEventType::NamedTracepoint { category, name } => {
+                write!(f, "tracepoint:{category}:{name}")
+            }
+            PerfEventType::Kprobe(function) => write!(f, "kprobe:{function}"),
+        }
+    }
+}
+
+impl FromStr for PerfEventType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu-clock" => Ok(PerfEventType::CpuClock),
+            "task-clock" => Ok(PerfEventType::TaskClock),
+            "page-faults" => Ok(PerfEventType::PageFaults),
+            "minor-faults" => Ok(PerfEventType::MinorFaults),
+            "major-faults" => Ok(PerfEventType::MajorFaults),
+            "context-switches" => Ok(PerfEventType::ContextSwitches),
+            "cpu-migrations" => Ok(PerfEventType::CpuMigrations),
+            _ => {
+                if let Some(tracepoint) = s.strip_prefix("tracepoint:") {
+                    if let Some((category, name)) = tracepoint.split_once(':') {
+                        return Ok(PerfEventType::NamedTracepoint {
+                            category: category.into(),
+                            name: name.into(),
+                        });
+                    }
+                    return tracepoint
+                        .parse()
+                        .map(PerfEventType::Tracepoint)
+                        .map_err(|_| format!("`{tracepoint}' isn't a valid tracepoint id"));
+                }
+                if let Some(function) = s.strip_prefix("kprobe:") {
+                    if function.is_empty() {
+                        return Err("expected kprobe:<function>".to_string());
+                    }
+                    return Ok(PerfEventType::Kprobe(function.into()));
+                }
+                Err(format!("unknown perf event `{s}'"))
+            }
+        }
+    }
+}
+
+/// Which event triggers on-CPU samples and how often.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PerfEventConfig {
+    pub event: PerfEventType,
+    /// Samples per second, used unless `sample_period` is set.
+    pub sample_freq: u64,
+    /// Take a sample every this many events.
+    pub sample_period: Option<u64>,
+}
+
+impl PerfEventConfig {
+    /// Nominal number of events, or nanoseconds for clock events, between two samples.
+    ///
+    /// In frequency mode the kernel keeps adjusting the actual period, which is what
+    /// every sample is weighted by. There's no good estimate for non-clock events.
+    pub fn nominal_period(&self) -> u64 {
+        match self.sample_period {
+            _ if self.event.is_trace_event() => 1,
+            Some(period) => period,
+            None if self.event.is_clock() => 1_000_000_000 / self.sample_freq,
+            None => 1,
+        }
+    }
+
+    /// Mean nominal period of the profiling sessions that sampled at these
+    /// frequencies, as they might have been adjusted at runtime.
+    pub fn mean_nominal_period(&self, session_sample_freqs: &[u64]) -> u64 {
+        if session_sample_freqs.is_empty() {
+            return self.nominal_period();
+        }
+        let total_period: u64 = session_sample_freqs
+            .iter()
+            .map(|sample_freq| {
+                PerfEventConfig {
+                    sample_freq: *sample_freq,
+                    ..self.clone()
+                }
+                .nominal_period()
+            })
+            .sum();
+        total_period / session_sample_freqs.len() as u64
+    }
+
+    /// Whether the events are opened in frequency mode, where the kernel adjusts
+    /// the period to meet the sampling frequency.
+    pub fn uses_sample_freq(&self) -> bool {
+        self.sample_period.is_none() && !self.event.is_trace_event()
+    }
+}
+
+/// Returns the attributes to open the perf event with. The name of the function
+/// for kprobes is returned too, as `attrs` points to it and it must outlive the
+/// `perf_event_open` call.
+fn perf_event_attrs(
+    config: &PerfEventConfig,
+) -> Result<(perf_event_attr, Option<CString>), io::Error> {
+    let (type_, event_config) = config.event.type_and_config()?;
     let mut attrs: perf_event_attr = perf_event_open_sys::bindings::perf_event_attr {
         size: std::mem::size_of::<sys::bindings::perf_event_attr>() as u32,
-        type_: sys::bindings::PERF_TYPE_SOFTWARE,
-        config: sys::bindings::PERF_COUNT_SW_CPU_CLOCK as u64,
+        type_,
+        config: event_config,
         ..Default::default()
     };
-    attrs.__bindgen_anon_1.sample_freq = sample_freq;
+    match config.sample_period {
+        _ if config.event.is_trace_event() => {
+            attrs.__bindgen_anon_1.sample_period = 1;
+        }
+        Some(sample_period) => {
+            attrs.__bindgen_anon_1.sample_period = sample_period;
+        }
+        None => {
+            attrs.__bindgen_anon_1.sample_freq = config.sample_freq;
+            attrs.set_freq(1);
+        }
+    }
     attrs.set_disabled(1);
-    attrs.set_freq(1);
+
+    let kprobe_function = match &config.event {
+        PerfEventType::Kprobe(function) => {
+            let function = CString::new(function.as_str()).map_err(io::Error::other)?;
+            attrs.__bindgen_anon_3.config1 = function.as_ptr() as u64;
+            Some(function)
+        }
+        _ => None,
+    };
+
+    Ok((attrs, kprobe_function))
+}
+
+/// Opens a perf event that samples every task running on the given CPU.
+///
+/// # Safety
+pub unsafe fn setup_perf_event(cpu: i32, config: &PerfEventConfig) -> Result<c_int, io::Error> {
+    let (mut attrs, _kprobe_function) = perf_event_attrs(config)?;
 
     let ret = sys::perf_event_open(
         &mut attrs, -1, /* pid */
@@ -28,3 +273,158 @@ pub unsafe fn setup_perf_event(cpu: i32, sample_freq: u64) -> Result<c_int, io::
 
     Ok(ret)
 }
+
+/// Opens a perf event that samples the given thread on any CPU, as well as the
+/// threads and processes it creates from now on.
+///
+/// # Safety
+pub unsafe fn setup_thread_perf_event(
+    tid: i32,
+    config: &PerfEventConfig,
+) -> Result<c_int, io::Error> {
+    let (mut attrs, _kprobe_function) = perf_event_attrs(config)?;
+    attrs.set_inherit(1);
+
+    let ret = sys::perf_event_open(
+        &mut attrs, tid, -1, /* cpu */
+        -1, /* group_fd */
+        0,  /* flags */
+    ) as c_int;
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret)
+}
+
+/// Changes the frequency of a perf event opened in frequency mode.
+///
+/// # Safety
+pub unsafe fn set_sample_freq(perf_fd: c_int, sample_freq: u64) -> Result<(), io::Error> {
+    // The kernel takes the new period as the frequency for events in frequency mode.
+    let mut sample_freq = sample_freq;
+    if sys::ioctls::PERIOD(perf_fd, &mut sample_freq) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perf_event_type_roundtrip() {
+        for event in [
+            PerfEventType::CpuClock,
+            PerfEventType::TaskClock,
+            PerfEventType::PageFaults,
+            PerfEventType::MinorFaults,
+            PerfEventType::MajorFaults,
+            PerfEventType::ContextSwitches,
+            PerfEventType::CpuMigrations,
+            PerfEventType::Tracepoint(1234),
+            PerfEventType::NamedTracepoint {
+                category: "block".into(),
+                name: "block_rq_issue".into(),
+            },
+            PerfEventType::Kprobe("tcp_sendmsg".into()),
+        ] {
+            assert_eq!(event.to_string().parse::<PerfEventType>(), Ok(event));
+        }
+
+        assert!("tracepoint:abc".parse::<PerfEventType>().is_err());
+        assert!("kprobe:".parse::<PerfEventType>().is_err());
+        assert!("cycles".parse::<PerfEventType>().is_err());
+    }
+
+    #[test]
+    fn test_trace_event_sample_type() {
+        let event = PerfEventType::NamedTracepoint {
+            category: "syscalls".into(),
+            name: "sys_enter_write".into(),
+        };
+        assert_eq!(
+            event.sample_type(),
+            ("syscalls:sys_enter_write".to_string(), "count")
+        );
+        assert_eq!(
+            PerfEventType::Kprobe("tcp_sendmsg".into()).sample_type(),
+            ("tcp_sendmsg".to_string(), "count")
+        );
+    }
+
+    #[test]
+    fn test_nominal_period() {
+        let config = PerfEventConfig {
+            event: PerfEventType::CpuClock,
+            sample_freq: 100,
+            sample_period: None,
+        };
+        assert_eq!(config.nominal_period(), 10_000_000);
+
+        let config = PerfEventConfig {
+            event: PerfEventType::PageFaults,
+            sample_freq: 100,
+            sample_period: Some(50),
+        };
+        assert_eq!(config.nominal_period(), 50);
+
+        let config = PerfEventConfig {
+            event: PerfEventType::PageFaults,
+            sample_freq: 100,
+            sample_period: None,
+        };
+        assert_eq!(config.nominal_period(), 1);
+
+        // Every hit of a trace event is sampled.
+        let config = PerfEventConfig {
+            event: PerfEventType::Tracepoint(1234),
+            sample_freq: 100,
+            sample_period: Some(50),
+        };
+        assert_eq!(config.nominal_period(), 1);
+    }
+
+    #[test]
+    fn test_mean_nominal_period() {
+        let config = PerfEventConfig {
+            event: PerfEventType::CpuClock,
+            sample_freq: 100,
+            sample_period: None,
+        };
+        assert_eq!(config.mean_nominal_period(&[]), 10_000_000);
+        assert_eq!(config.mean_nominal_period(&[100, 50]), 15_000_000);
+
+        let config = PerfEventConfig {
+            sample_period: Some(50),
+            ..config
+        };
+        assert_eq!(config.mean_nominal_period(&[100, 50]), 50);
+    }
+
+    #[test]
+    fn test_uses_sample_freq() {
+        let config = PerfEventConfig {
+            event: PerfEventType::CpuClock,
+            sample_freq: 100,
+            sample_period: None,
+        };
+        assert!(config.uses_sample_freq());
+
+        let config = PerfEventConfig {
+            sample_period: Some(50),
+            ..config
+        };
+        assert!(!config.uses_sample_freq());
+
+        let config = PerfEventConfig {
+            event: PerfEventType::Kprobe("tcp_sendmsg".into()),
+            sample_freq: 100,
+            sample_period: None,
+        };
+        assert!(!config.uses_sample_freq());
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use lightswitch_object::ObjectFile;
+
+/// Userspace location that triggers the collection of a stack every time it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserProbe {
+    /// Entry of a function. C++ and Rust functions must be given by their mangled name.
+    Function { binary: PathBuf, symbol: String },
+    /// Statically defined tracepoint, found in the `.note.stapsdt` ELF notes.
+    Usdt {
+        binary: PathBuf,
+        provider: String,
+        name: String,
+    },
+}
+
+/// Where a uprobe has to be attached for a [`UserProbe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedUserProbe {
+    pub binary: PathBuf,
+    pub file_offset: u64,
+    /// File offset of the USDT semaphore, if the probe has one.
+    pub semaphore_file_offset: Option<u64>,
+}
+
+impl UserProbe {
+    pub fn binary(&self) -> &PathBuf {
+        match self {
+            UserProbe::Function { binary, .. } | UserProbe::Usdt { binary, .. } => binary,
+        }
+    }
+
+    /// Finds the file offsets of the probe in its binary.
+    pub fn resolve(&self) -> Result<ResolvedUserProbe> {
+        let object_file = ObjectFile::from_path(self.binary())?;
+
+        let (file_offset, semaphore_file_offset) = match self {
+            UserProbe::Function { symbol, .. } => {
+                let file_offset = object_file
+                    .symbol_file_offset(symbol)
+                    .ok_or(anyhow!("function `{symbol}' not found"))?;
+                (file_offset, None)
+            }
+            UserProbe::Usdt { provider, name, .. } => {
+                let probe = object_file
+                    .usdt_probes()?
+                    .into_iter()
+                    .find(|probe| &probe.provider == provider && &probe.name == name)
+                    .ok_or(anyhow!("USDT probe `{provider}:{name}' not found"))?;
+                (probe.file_offset, probe.semaphore_file_offset)
+            }
+        };
+
+        Ok(ResolvedUserProbe {
+            binary: self.binary().clone(),
+            file_offset,
+            semaphore_file_offset,
+        })
+    }
+}
+
+impl fmt::Display for UserProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserProbe::Function { binary, symbol } => {
+                write!(f, "uprobe:{}:{symbol}", binary.display())
+            }
+            UserProbe::Usdt {
+                binary,
+                provider,
+                name,
+            } => write!(f, "usdt:{}:{provider}:{name}", binary.display()),
+        }
+    }
+}
+
+impl FromStr for UserProbe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(probe) = s.strip_prefix("uprobe:") {
+            let Some((binary, symbol)) = probe.rsplit_once(':') else {
+                return Err(format!("expected uprobe:<binary>:<function>, got `{s}'"));
+            };
+            return Ok(UserProbe::Function {
+                binary: binary.into(),
+                symbol: symbol.into(),
+            });
+        }
+
+        if let Some(probe) = s.strip_prefix("usdt:") {
+            let mut parts = probe.rsplitn(3, ':');
+            let (Some(name), Some(provider), Some(binary)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(format!(
+                    "expected usdt:<binary>:<provider>:<name>, got `{s}'"
+                ));
+            };
+            return Ok(UserProbe::Usdt {
+                binary: binary.into(),
+                provider: provider.into(),
+                name: name.into(),
+            });
+        }
+
+        Err(format!("unknown probe `{s}'"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_probe_roundtrip() {
+        for probe in [
+            UserProbe::Function {
+                binary: "/usr/lib/libstdc++.so.6".into(),
+                symbol: "__cxa_throw".into(),
+            },
+            UserProbe::Usdt {
+                binary: "/usr/lib/libstdc++.so.6".into(),
+                provider: "libstdcxx".into(),
+                name: "throw".into(),
+            },
+        ] {
+            assert_eq!(probe.to_string().parse::<UserProbe>(), Ok(probe));
+        }
+
+        assert!("uprobe:__cxa_throw".parse::<UserProbe>().is_err());
+        assert!("usdt:/bin/app:throw".parse::<UserProbe>().is_err());
+        assert!("kprobe:tcp_sendmsg".parse::<UserProbe>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_missing_function() {
+        let probe = UserProbe::Function {
+            binary: "/proc/self/exe".into(),
+            symbol: "this_function_does_not_exist".into(),
+        };
+
+        assert!(probe.resolve().is_err());
+    }
+}
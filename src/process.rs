@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::Instant;
 
+use regex::Regex;
 use tracing::debug;
 
 use lightswitch_object::BuildId;
@@ -11,6 +12,23 @@ use lightswitch_object::Runtime;
 
 pub type Pid = i32;
 
+/// How many of the executables a process ran before calling exec are kept, so that
+/// processes that exec in a loop, such as shells, don't grow without bound.
+const MAX_PREVIOUS_MAPPINGS: usize = 32;
+
+/// Mappings of an executable a process ran before calling exec.
+#[derive(Debug, Clone)]
+pub struct PreviousMappings {
+    pub mappings: ExecutableMappings,
+    /// Generation of the process while it ran the executable.
+    pub generation: u32,
+    /// Boot time in nanoseconds of the exec that loaded the executable, or zero if
+    /// it was running when the process was first seen.
+    pub started_at: u64,
+    /// Boot time in nanoseconds of the exec that replaced the executable.
+    pub replaced_at: u64,
+}
+
 /// What type of mapping we are dealing with.
 #[derive(Debug, Clone, PartialEq)]
 pub enum ExecutableMappingType {
@@ -33,9 +51,66 @@ pub enum ProcessStatus {
 pub struct ProcessInfo {
     pub status: ProcessStatus,
     pub mappings: ExecutableMappings,
+    /// Number of times the process called exec since it was first seen.
+    pub generation: u32,
+    /// Mappings of the last executables the process ran before calling exec, oldest
+    /// first, as the samples taken back then still refer to them.
+    pub previous_mappings: Vec<PreviousMappings>,
     pub last_used: Instant,
 }
 
+impl ProcessInfo {
+    /// Keeps the mappings of `previous`, the state of this process before it called
+    /// exec at `exec_at`, boot time in nanoseconds, as an older generation.
+    pub fn push_previous_generation(&mut self, previous: ProcessInfo, exec_at: u64) {
+        let started_at = previous
+            .previous_mappings
+            .last()
+            .map_or(0, |previous| previous.replaced_at);
+        self.generation = previous.generation + 1;
+        self.previous_mappings = previous.previous_mappings;
+        self.previous_mappings.push(PreviousMappings {
+            mappings: previous.mappings,
+            generation: previous.generation,
+            started_at,
+            replaced_at: exec_at,
+        });
+        let excess = self
+            .previous_mappings
+            .len()
+            .saturating_sub(MAX_PREVIOUS_MAPPINGS);
+        self.previous_mappings.drain(..excess);
+    }
+
+    /// Generation of the process when a sample was collected, `collected_at` being
+    /// its boot time in nanoseconds.
+    pub fn generation_at(&self, collected_at: u64) -> u32 {
+        match self
+            .previous_mappings
+            .iter()
+            .find(|previous| collected_at < previous.replaced_at)
+        {
+            None => self.generation,
+            Some(previous) if previous.started_at <= collected_at => previous.generation,
+            // The process ran an executable whose mappings were already dropped.
+            Some(previous) => previous.generation - 1,
+        }
+    }
+
+    /// Mappings of the executable the process ran in the given generation, which are
+    /// empty if they were already dropped.
+    pub fn mappings_for(&self, generation: u32) -> &ExecutableMappings {
+        static NO_MAPPINGS: ExecutableMappings = ExecutableMappings(Vec::new());
+        if generation >= self.generation {
+            return &self.mappings;
+        }
+        self.previous_mappings
+            .iter()
+            .find(|previous| previous.generation == generation)
+            .map_or(&NO_MAPPINGS, |previous| &previous.mappings)
+    }
+}
+
 /// Stores information for a executable mapping with all
 /// the information we need to do everything symbolization
 /// related.
@@ -106,6 +181,60 @@ impl ExecutableMapping {
     }
 }
 
+/// Selects processes by their name, executable path or command line. A process
+/// is selected if any of the regexes matches.
+#[derive(Debug, Default, Clone)]
+pub struct ProcessMatcher {
+    /// Matched against the process name, as in `/proc/<pid>/comm`.
+    pub comm: Vec<Regex>,
+    /// Matched against the path of the executable.
+    pub exe: Vec<Regex>,
+    /// Matched against the arguments, joined by spaces.
+    pub cmdline: Vec<Regex>,
+}
+
+impl ProcessMatcher {
+    pub fn is_empty(&self) -> bool {
+        self.comm.is_empty() && self.exe.is_empty() && self.cmdline.is_empty()
+    }
+
+    /// Whether the process matches, reading its details from procfs. Processes that
+    /// can't be read, such as the ones that already exited, don't match.
+    pub fn matches(&self, pid: Pid) -> bool {
+        let Ok(process) = procfs::process::Process::new(pid) else {
+            return false;
+        };
+
+        if !self.comm.is_empty() {
+            if let Ok(stat) = process.stat() {
+                if self.comm.iter().any(|regex| regex.is_match(&stat.comm)) {
+                    return true;
+                }
+            }
+        }
+
+        if !self.exe.is_empty() {
+            if let Ok(exe) = process.exe() {
+                let exe = exe.to_string_lossy();
+                if self.exe.iter().any(|regex| regex.is_match(&exe)) {
+                    return true;
+                }
+            }
+        }
+
+        if !self.cmdline.is_empty() {
+            if let Ok(cmdline) = process.cmdline() {
+                let cmdline = cmdline.join(" ");
+                if self.cmdline.iter().any(|regex| regex.is_match(&cmdline)) {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
 pub struct ObjectFileInfo {
     pub path: PathBuf,
     pub elf_load_segments: Vec<ElfLoad>,
@@ -208,4 +337,91 @@ mod tests {
             .normalized_address(0x110, &mapping)
             .is_none());
     }
+
+    #[test]
+    fn test_process_matcher() {
+        let pid = std::process::id() as Pid;
+        let exe = std::env::current_exe().unwrap();
+        let exe_name = exe.file_name().unwrap().to_str().unwrap();
+        let comm = &exe_name[..exe_name.len().min(15)];
+
+        assert!(ProcessMatcher::default().is_empty());
+        assert!(!ProcessMatcher::default().matches(pid));
+
+        let matcher = ProcessMatcher {
+            comm: vec![Regex::new(&format!("^{}$", regex::escape(comm))).unwrap()],
+            ..Default::default()
+        };
+        assert!(!matcher.is_empty());
+        assert!(matcher.matches(pid));
+
+        let matcher = ProcessMatcher {
+            exe: vec![Regex::new(&format!("/{}$", regex::escape(exe_name))).unwrap()],
+            ..Default::default()
+        };
+        assert!(matcher.matches(pid));
+
+        let matcher = ProcessMatcher {
+            cmdline: vec![Regex::new(&regex::escape(exe_name)).unwrap()],
+            ..Default::default()
+        };
+        assert!(matcher.matches(pid));
+
+        let matcher = ProcessMatcher {
+            comm: vec![Regex::new("^this-is-not-the-test-runner$").unwrap()],
+            exe: vec![Regex::new("^/this/is/not/the/test/runner$").unwrap()],
+            cmdline: vec![Regex::new("^this is not the test runner$").unwrap()],
+        };
+        assert!(!matcher.matches(pid));
+    }
+
+    #[test]
+    fn test_mappings_for_generation() {
+        let mapping = |executable_id| ExecutableMapping {
+            executable_id: ExecutableId(executable_id),
+            build_id: None,
+            kind: ExecutableMappingType::FileBacked,
+            start_addr: 0x100,
+            end_addr: 0x200,
+            offset: 0x0,
+            load_address: 0x0,
+            soft_delete: false,
+        };
+
+        let proc_info = |executable_id| ProcessInfo {
+            status: ProcessStatus::Running,
+            mappings: ExecutableMappings(vec![mapping(executable_id)]),
+            generation: 0,
+            previous_mappings: Vec::new(),
+            last_used: Instant::now(),
+        };
+        let executable_id = |proc_info: &ProcessInfo, collected_at| {
+            proc_info
+                .mappings_for(proc_info.generation_at(collected_at))
+                .for_address(&0x150)
+                .map(|mapping| mapping.executable_id)
+        };
+
+        // exec replaces the image mapped at the same address
+        let mut current = proc_info(0x1);
+        for (executable_id, exec_at) in (0x2..).zip([1000, 2000]) {
+            let mut next = proc_info(executable_id);
+            next.push_previous_generation(current, exec_at);
+            current = next;
+        }
+        assert_eq!(current.generation, 2);
+        assert_eq!(executable_id(&current, 999), Some(ExecutableId(0x1)));
+        assert_eq!(executable_id(&current, 1000), Some(ExecutableId(0x2)));
+        assert_eq!(executable_id(&current, 2500), Some(ExecutableId(0x3)));
+
+        // Only the last executables are kept
+        for exec_at in 3..=MAX_PREVIOUS_MAPPINGS as u64 + 1 {
+            let mut next = proc_info(0x4);
+            next.push_previous_generation(current, exec_at * 1000);
+            current = next;
+        }
+        assert_eq!(current.previous_mappings.len(), MAX_PREVIOUS_MAPPINGS);
+        assert_eq!(executable_id(&current, 999), None);
+        assert_eq!(executable_id(&current, 1500), Some(ExecutableId(0x2)));
+    }
 }
@@ -14,14 +14,20 @@ use tracing::{debug, error, span, Level};
 use crate::kernel::KERNEL_PID;
 use crate::ksym::Ksym;
 use crate::ksym::KsymIter;
+use crate::perf_events::PerfEventConfig;
 use crate::process::ObjectFileInfo;
 use crate::process::ProcessInfo;
 use crate::profile::{
-    AggregatedProfile, AggregatedSample, Frame, FrameAddress, RawAggregatedProfile, SymbolizedFrame,
+    AggregatedProfile, AggregatedSample, Frame, FrameAddress, RawAggregatedProfile, SampleKind,
+    SymbolizedFrame,
 };
+use crate::profiler::ProfilerMode;
 use crate::usym::symbolize_native_stack_blaze;
 use lightswitch_object::ExecutableId;
 
+/// Id of the synthetic mapping holding the unwind error frames in pprof profiles.
+const UNWIND_ERROR_MAPPING_ID: u64 = u64::MAX;
+
 struct ProfileLabel {
     value: MetadataLabelValue,
 }
@@ -36,19 +42,57 @@ impl From<ProfileLabel> for LabelStringOrNumber {
 }
 
 /// Converts a given symbolized profile to Google's pprof.
+///
+/// The sampling frequency might have been adjusted at runtime, so the period of
+/// on-CPU profiles is the mean of the sessions' and the frequency of every session
+/// is recorded in the comments.
 pub fn to_pprof(
     profile: AggregatedProfile,
     procs: &HashMap<i32, ProcessInfo>,
     objs: &HashMap<ExecutableId, ObjectFileInfo>,
     metadata_provider: &ThreadSafeGlobalMetadataProvider,
     profile_duration: Duration,
-    profile_frequency_hz: u64,
+    perf_event_config: &PerfEventConfig,
+    mode: ProfilerMode,
+    session_sample_freqs: &[u64],
 ) -> pprof::Profile {
     // Not exactly when the profile session really started but works for now.
     let profile_start = SystemTime::now();
 
-    let mut pprof = PprofBuilder::new(profile_start, profile_duration, profile_frequency_hz);
-    let mut task_to_labels: HashMap<i32, Vec<Label>> = HashMap::new();
+    let mut pprof = PprofBuilder::new(
+        profile_start,
+        profile_duration,
+        perf_event_config.sample_freq,
+    );
+    match mode {
+        ProfilerMode::OnCpu => {
+            let (sample_type, unit) = perf_event_config.event.sample_type();
+            pprof.set_period_type(
+                &sample_type,
+                unit,
+                perf_event_config.mean_nominal_period(session_sample_freqs) as i64,
+            );
+        }
+        ProfilerMode::OffCpu => pprof.set_period_type("off_cpu", "nanoseconds", 1),
+        ProfilerMode::Wallclock => pprof.set_period_type("wallclock", "nanoseconds", 1),
+        ProfilerMode::Heap => {
+            // Same layout as Go's heap profiles, so pprof offers to switch between views.
+            pprof.set_period_type("space", "bytes", 1);
+            pprof.set_sample_types(&[
+                ("alloc_objects", "count"),
+                ("alloc_space", "bytes"),
+                ("inuse_objects", "count"),
+                ("inuse_space", "bytes"),
+            ]);
+        }
+        ProfilerMode::Probes => pprof.set_period_type("probe_hits", "count", 1),
+        ProfilerMode::Contention => pprof.set_period_type("contention", "nanoseconds", 1),
+        ProfilerMode::RunQueue => pprof.set_period_type("runqueue_latency", "nanoseconds", 1),
+    }
+    for (session, sample_freq) in session_sample_freqs.iter().enumerate() {
+        pprof.add_comment(&format!("session={session} sample_freq_hz={sample_freq}"));
+    }
+    let mut task_to_labels: HashMap<(i32, u64), Vec<Label>> = HashMap::new();
 
     for sample in profile {
         let ustack = sample.ustack;
@@ -125,7 +169,10 @@ pub fn to_pprof(
                 continue;
             };
 
-            let Some(mapping) = info.mappings.for_address(&virtual_address) else {
+            let Some(mapping) = info
+                .mappings_for(sample.generation)
+                .for_address(&virtual_address)
+            else {
                 // todo: maybe append an error frame for debugging?
                 continue;
             };
@@ -190,19 +237,107 @@ pub fn to_pprof(
             }
         }
 
-        let labels = task_to_labels.entry(sample.tid).or_insert_with(|| {
-            let metadata = metadata_provider.lock().unwrap().get_metadata(TaskKey {
-                tid: sample.tid,
-                pid: sample.pid,
+        // Root the truncated stack in a frame naming why unwinding stopped, as the
+        // folded profiles do. Each error gets its own address in a synthetic mapping
+        // so that their locations aren't merged.
+        if let Some(unwind_error) = sample.unwind_error {
+            let mapping_id = pprof.add_mapping(
+                UNWIND_ERROR_MAPPING_ID,
+                0x0,
+                0x0,
+                0x0,
+                "[unwind error]",
+                "no-build-id",
+            );
+            let (line, _) = pprof.add_line(
+                &format!("[unwind error: {}]", unwind_error.as_str()),
+                None,
+                None,
+            );
+            let location = pprof.add_location(unwind_error as u64 + 1, mapping_id, vec![line]);
+            location_ids.push(location);
+        }
+
+        let labels = task_to_labels
+            .entry((sample.tid, sample.cgroup_id))
+            .or_insert_with(|| {
+                let mut metadata_provider = metadata_provider.lock().unwrap();
+                let mut metadata = metadata_provider.get_metadata(TaskKey {
+                    tid: sample.tid,
+                    pid: sample.pid,
+                });
+                if sample.cgroup_id != 0 {
+                    metadata.extend(metadata_provider.get_cgroup_metadata(sample.cgroup_id));
+                }
+                metadata
+                    .into_iter()
+                    .map(|label| {
+                        pprof.new_label(&label.key, ProfileLabel { value: label.value }.into())
+                    })
+                    .collect()
             });
-            metadata
-                .into_iter()
-                .map(|label| {
-                    pprof.new_label(&label.key, ProfileLabel { value: label.value }.into())
-                })
-                .collect()
-        });
-        pprof.add_sample(location_ids, sample.count as i64, labels);
+        let mut labels = labels.clone();
+        labels.push(pprof.new_label(
+            "cpu",
+            LabelStringOrNumber::Number(sample.cpu.into(), "cpu".into()),
+        ));
+        labels.push(pprof.new_label(
+            "execution.context",
+            LabelStringOrNumber::String(sample.context.as_str().to_string()),
+        ));
+        if let Some(unwind_error) = sample.unwind_error {
+            labels.push(pprof.new_label(
+                "unwind.error",
+                LabelStringOrNumber::String(unwind_error.as_str().to_string()),
+            ));
+        }
+        match mode {
+            ProfilerMode::OnCpu
+            | ProfilerMode::OffCpu
+            | ProfilerMode::Probes
+            | ProfilerMode::RunQueue
+            | ProfilerMode::Heap => {}
+            ProfilerMode::Wallclock => {
+                labels.push(pprof.new_label(
+                    "thread.state",
+                    LabelStringOrNumber::String(sample.kind.thread_state().to_string()),
+                ));
+            }
+            ProfilerMode::Contention => {
+                if let Some(lock_address) = sample.lock_address {
+                    labels.push(pprof.new_label(
+                        "lock.address",
+                        LabelStringOrNumber::String(format!("0x{lock_address:x}")),
+                    ));
+                }
+                if let Some(futex_wait) = sample.futex_wait {
+                    labels.push(pprof.new_label(
+                        "futex.wait",
+                        LabelStringOrNumber::String(futex_wait.as_str().to_string()),
+                    ));
+                }
+            }
+        }
+        if mode == ProfilerMode::Heap {
+            let (count, bytes) = (sample.count as i64, sample.weight as i64);
+            let values = match sample.kind {
+                SampleKind::Allocation => vec![count, bytes, 0, 0],
+                SampleKind::InUse => vec![0, 0, count, bytes],
+                SampleKind::OnCpu
+                | SampleKind::OffCpu
+                | SampleKind::Free
+                | SampleKind::Contention
+                | SampleKind::RunQueue => continue,
+            };
+            pprof.add_sample_values(location_ids, values, &labels);
+            continue;
+        }
+        pprof.add_weighted_sample(
+            location_ids,
+            sample.count as i64,
+            sample.weight as i64,
+            &labels,
+        );
     }
 
     pprof.build()
@@ -216,7 +351,20 @@ pub fn to_pprof(
 ///
 /// The frame names are separated by semicolons and the count is at the end separated with a space. We insert some synthetic
 /// frames to quickly identify the thread and process names and other pieces of metadata.
-pub fn fold_profile(profile: AggregatedProfile, only_show_function_names: bool) -> String {
+///
+/// Off-CPU, wall-clock and run-queue profiles use the time spent, in nanoseconds, rather than the number of samples.
+/// Wall-clock profiles also get a synthetic frame telling whether the thread was running or waiting.
+/// Heap profiles use the bytes allocated, with a synthetic frame telling apart the memory allocated
+/// during the session from the memory still in use at its end. Contention profiles use the time
+/// spent waiting, with a synthetic frame for the address of the lock.
+///
+/// Stacks that couldn't be fully unwound get a synthetic root frame with the reason, right before
+/// the user frames, so the samples that would otherwise be missing are visible.
+pub fn fold_profile(
+    profile: AggregatedProfile,
+    only_show_function_names: bool,
+    mode: ProfilerMode,
+) -> String {
     let mut folded = String::new();
 
     for sample in profile {
@@ -227,7 +375,15 @@ pub fn fold_profile(profile: AggregatedProfile, only_show_function_names: bool)
             .rev()
             .map(|e| e.format_all_info(only_show_function_names))
             .collect::<Vec<String>>();
-        let ustack = ustack.join(";");
+        let mut ustack = ustack.join(";");
+        if let Some(unwind_error) = sample.unwind_error {
+            let unwind_error_frame = format!("[unwind error: {}]", unwind_error.as_str());
+            ustack = if ustack.is_empty() {
+                unwind_error_frame
+            } else {
+                format!("{unwind_error_frame};{ustack}")
+            };
+        }
         let kstack = sample
             .kstack
             .clone()
@@ -236,15 +392,53 @@ pub fn fold_profile(profile: AggregatedProfile, only_show_function_names: bool)
             .map(|e| format!("kernel: {e}"))
             .collect::<Vec<String>>();
         let kstack = kstack.join(";");
-        let count: String = sample.count.to_string();
+        let count: String = match mode {
+            ProfilerMode::OnCpu | ProfilerMode::Probes => sample.count.to_string(),
+            ProfilerMode::OffCpu
+            | ProfilerMode::Wallclock
+            | ProfilerMode::Heap
+            | ProfilerMode::Contention
+            | ProfilerMode::RunQueue => sample.weight.to_string(),
+        };
+        let sample_kind_frame = match mode {
+            ProfilerMode::Wallclock => format!(";[{}]", sample.kind.thread_state()),
+            ProfilerMode::Heap => match sample.kind.heap_view() {
+                Some(heap_view) => format!(";[{heap_view}]"),
+                None => "".to_string(),
+            },
+            ProfilerMode::Contention => match (sample.lock_address, sample.futex_wait) {
+                (Some(lock_address), Some(futex_wait)) => {
+                    format!(";[lock 0x{lock_address:x} {}]", futex_wait.as_str())
+                }
+                (Some(lock_address), None) => format!(";[lock 0x{lock_address:x}]"),
+                (None, _) => "".to_string(),
+            },
+            ProfilerMode::OnCpu
+            | ProfilerMode::OffCpu
+            | ProfilerMode::Probes
+            | ProfilerMode::RunQueue => "".to_string(),
+        };
 
-        let task_and_process_names = TaskName::for_task(sample.tid).unwrap_or(TaskName::errored());
+        // Kernel threads and the idle task only have a kernel stack, their `comm` is the root frame.
+        let task_frames = if sample.pid == KERNEL_PID {
+            sample
+                .comm
+                .clone()
+                .unwrap_or_else(|| TaskName::errored().current_thread)
+        } else {
+            let task_and_process_names =
+                TaskName::for_task(sample.tid).unwrap_or(TaskName::errored());
+            format!(
+                "{};{}",
+                task_and_process_names.main_thread, task_and_process_names.current_thread
+            )
+        };
 
         writeln!(
             folded,
-            "{};{}{}{} {}",
-            task_and_process_names.main_thread,
-            task_and_process_names.current_thread,
+            "{}{}{}{} {}",
+            task_frames,
+            sample_kind_frame,
             if ustack.trim().is_empty() {
                 "".to_string()
             } else {
@@ -298,11 +492,22 @@ pub fn symbolize_profile(
             pid: sample.pid,
             tid: sample.tid,
             count: sample.count,
+            weight: sample.weight,
+            cgroup_id: sample.cgroup_id,
+            kind: sample.kind,
+            cpu: sample.cpu,
+            context: sample.context,
+            lock_address: sample.lock_address,
+            unwind_error: sample.unwind_error,
+            comm: sample.comm.clone(),
+            futex_wait: sample.futex_wait,
+            generation: sample.generation,
             ustack: symbolize_user_stack(
                 &addresses_per_sample,
                 procs,
                 objs,
                 sample.pid,
+                sample.generation,
                 &sample.ustack,
             ),
             kstack: symbolize_kernel_stack(&sample.kstack, &ksyms),
@@ -328,9 +533,10 @@ pub fn fetch_symbols_for_profile(
         let Some(info) = procs.get(&sample.pid) else {
             continue;
         };
+        let mappings = info.mappings_for(sample.generation);
 
         for frame in &sample.ustack {
-            let Some(mapping) = info.mappings.for_address(&frame.virtual_address) else {
+            let Some(mapping) = mappings.for_address(&frame.virtual_address) else {
                 continue;
             };
 
@@ -414,6 +620,7 @@ fn symbolize_user_stack(
     procs: &HashMap<i32, ProcessInfo>,
     objs: &HashMap<ExecutableId, ObjectFileInfo>,
     pid: i32,
+    generation: u32,
     native_stack: &[Frame],
 ) -> Vec<Frame> {
     let mut result = Vec::new();
@@ -427,7 +634,10 @@ fn symbolize_user_stack(
             continue;
         };
 
-        let Some(mapping) = info.mappings.for_address(&frame.virtual_address) else {
+        let Some(mapping) = info
+            .mappings_for(generation)
+            .for_address(&frame.virtual_address)
+        else {
             result.push(Frame::with_error(
                 frame.virtual_address,
                 "<could not find mapping>".to_string(),
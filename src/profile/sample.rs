@@ -1,23 +1,252 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::hash::Hash;
+use std::mem::offset_of;
 
 use anyhow::anyhow;
 use lightswitch_object::ExecutableId;
 use tracing::error;
 
+use crate::bpf::profiler_bindings::{
+    futex_wait_FUTEX_WAIT_LOCK_PI, futex_wait_FUTEX_WAIT_LOCK_PI2, futex_wait_FUTEX_WAIT_NONE,
+    futex_wait_FUTEX_WAIT_WAIT, futex_wait_FUTEX_WAIT_WAITV, futex_wait_FUTEX_WAIT_WAIT_BITSET,
+    futex_wait_FUTEX_WAIT_WAIT_REQUEUE_PI, sample_context_SAMPLE_CONTEXT_HARDIRQ,
+    sample_context_SAMPLE_CONTEXT_NMI, sample_context_SAMPLE_CONTEXT_SOFTIRQ,
+    sample_context_SAMPLE_CONTEXT_TASK, sample_kind_SAMPLE_KIND_ALLOCATION,
+    sample_kind_SAMPLE_KIND_CONTENTION, sample_kind_SAMPLE_KIND_FREE,
+    sample_kind_SAMPLE_KIND_OFF_CPU, sample_kind_SAMPLE_KIND_ON_CPU,
+    sample_kind_SAMPLE_KIND_RUNQUEUE, unwind_error_UNWIND_ERROR_BINARY_SEARCH,
+    unwind_error_UNWIND_ERROR_CFA_OFFSET_DID_NOT_FIT, unwind_error_UNWIND_ERROR_FRAME_POINTER,
+    unwind_error_UNWIND_ERROR_MAPPING_DOES_NOT_CONTAIN_PC,
+    unwind_error_UNWIND_ERROR_MAPPING_NOT_FOUND, unwind_error_UNWIND_ERROR_NONE,
+    unwind_error_UNWIND_ERROR_PREVIOUS_RBP_READ, unwind_error_UNWIND_ERROR_PREVIOUS_RIP_ZERO,
+    unwind_error_UNWIND_ERROR_PREVIOUS_RSP_READ, unwind_error_UNWIND_ERROR_PREVIOUS_RSP_ZERO,
+    unwind_error_UNWIND_ERROR_RBP_OFFSET_DID_NOT_FIT,
+    unwind_error_UNWIND_ERROR_SHOULD_NEVER_HAPPEN, unwind_error_UNWIND_ERROR_TRUNCATED,
+    unwind_error_UNWIND_ERROR_UNSUPPORTED_CFA_REGISTER,
+    unwind_error_UNWIND_ERROR_UNSUPPORTED_EXPRESSION,
+    unwind_error_UNWIND_ERROR_UNSUPPORTED_FRAME_POINTER_ACTION,
+};
+use crate::bpf::profiler_bindings::{native_stack_t, sample_t, COMM_LEN};
 use crate::kernel::KERNEL_PID;
 use crate::process::ObjectFileInfo;
 use crate::process::Pid;
 use crate::process::ProcessInfo;
 use crate::profile::Frame;
 
+/// What the task was doing when its stack was collected.
+#[derive(Debug, Default, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum SampleKind {
+    #[default]
+    OnCpu,
+    OffCpu,
+    /// The task allocated memory.
+    Allocation,
+    /// Memory allocated by the task that hadn't been freed by the end of the session.
+    /// These are never sent by the BPF programs but derived from allocations.
+    InUse,
+    /// The task freed the memory at [`RawSample::address`]. There's no stack.
+    Free,
+    /// The task waited on the futex at [`RawSample::address`].
+    Contention,
+    /// The task was runnable, waiting for a CPU.
+    RunQueue,
+}
+
+impl SampleKind {
+    /// Human readable state of the thread, used to tag stacks in wall-clock profiles.
+    pub fn thread_state(&self) -> &'static str {
+        match self {
+            SampleKind::OffCpu | SampleKind::Contention | SampleKind::RunQueue => "waiting",
+            SampleKind::OnCpu | SampleKind::Allocation | SampleKind::InUse | SampleKind::Free => {
+                "running"
+            }
+        }
+    }
+
+    /// Which of the heap profile views the sample belongs to, if any.
+    pub fn heap_view(&self) -> Option<&'static str> {
+        match self {
+            SampleKind::Allocation => Some("alloc_space"),
+            SampleKind::InUse => Some("inuse_space"),
+            SampleKind::OnCpu
+            | SampleKind::OffCpu
+            | SampleKind::Free
+            | SampleKind::Contention
+            | SampleKind::RunQueue => None,
+        }
+    }
+}
+
+/// Context the stack was collected in.
+#[derive(Debug, Default, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum ExecutionContext {
+    /// Process context, including the idle task.
+    #[default]
+    Task,
+    SoftIrq,
+    HardIrq,
+    Nmi,
+}
+
+impl ExecutionContext {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecutionContext::Task => "task",
+            ExecutionContext::SoftIrq => "softirq",
+            ExecutionContext::HardIrq => "hardirq",
+            ExecutionContext::Nmi => "nmi",
+        }
+    }
+}
+
+/// How the task of a contention sample waited on the futex, which tells lock waits
+/// apart from idle ones for runtimes that use different operations for them. glibc's
+/// mutexes wait with `FUTEX_WAIT`, while its condition variables, semaphores and
+/// barriers wait with `FUTEX_WAIT_BITSET`.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum FutexWait {
+    Wait,
+    WaitBitset,
+    LockPi,
+    WaitRequeuePi,
+    LockPi2,
+    /// `futex_waitv(2)`, which waits on several futexes at once.
+    WaitV,
+}
+
+impl FutexWait {
+    /// Parses the `enum futex_wait` value sent by the BPF programs, which is `None`
+    /// for the samples that aren't contention samples.
+    fn from_raw(raw: u32) -> Result<Option<Self>, RawSampleParsingError> {
+        Ok(Some(match raw {
+            futex_wait_FUTEX_WAIT_NONE => return Ok(None),
+            futex_wait_FUTEX_WAIT_WAIT => FutexWait::Wait,
+            futex_wait_FUTEX_WAIT_WAIT_BITSET => FutexWait::WaitBitset,
+            futex_wait_FUTEX_WAIT_LOCK_PI => FutexWait::LockPi,
+            futex_wait_FUTEX_WAIT_WAIT_REQUEUE_PI => FutexWait::WaitRequeuePi,
+            futex_wait_FUTEX_WAIT_LOCK_PI2 => FutexWait::LockPi2,
+            futex_wait_FUTEX_WAIT_WAITV => FutexWait::WaitV,
+            other => return Err(RawSampleParsingError::UnknownFutexWait(other)),
+        }))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FutexWait::Wait => "FUTEX_WAIT",
+            FutexWait::WaitBitset => "FUTEX_WAIT_BITSET",
+            FutexWait::LockPi => "FUTEX_LOCK_PI",
+            FutexWait::WaitRequeuePi => "FUTEX_WAIT_REQUEUE_PI",
+            FutexWait::LockPi2 => "FUTEX_LOCK_PI2",
+            FutexWait::WaitV => "futex_waitv",
+        }
+    }
+}
+
+/// Why the native unwinder stopped before reaching the bottom of the stack. The
+/// user stack of these samples only has the frames unwound until then.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum UnwindError {
+    MappingNotFound,
+    MappingDoesNotContainPc,
+    BinarySearch,
+    CfaOffsetDidNotFit,
+    RbpOffsetDidNotFit,
+    UnsupportedFramePointerAction,
+    UnsupportedExpression,
+    UnsupportedCfaRegister,
+    PreviousRspZero,
+    PreviousRspRead,
+    PreviousRbpRead,
+    PreviousRipZero,
+    FramePointer,
+    /// The stack is deeper than what the unwinder can walk.
+    Truncated,
+    ShouldNeverHappen,
+}
+
+impl UnwindError {
+    /// Parses the `enum unwind_error` value sent by the unwinder, which is
+    /// `None` for the stacks that were fully unwound.
+    fn from_raw(raw: u32) -> Result<Option<Self>, RawSampleParsingError> {
+        Ok(Some(match raw {
+            unwind_error_UNWIND_ERROR_NONE => return Ok(None),
+            unwind_error_UNWIND_ERROR_MAPPING_NOT_FOUND => UnwindError::MappingNotFound,
+            unwind_error_UNWIND_ERROR_MAPPING_DOES_NOT_CONTAIN_PC => {
+                UnwindError::MappingDoesNotContainPc
+            }
+            unwind_error_UNWIND_ERROR_BINARY_SEARCH => UnwindError::BinarySearch,
+            unwind_error_UNWIND_ERROR_CFA_OFFSET_DID_NOT_FIT => UnwindError::CfaOffsetDidNotFit,
+            unwind_error_UNWIND_ERROR_RBP_OFFSET_DID_NOT_FIT => UnwindError::RbpOffsetDidNotFit,
+            unwind_error_UNWIND_ERROR_UNSUPPORTED_FRAME_POINTER_ACTION => {
+                UnwindError::UnsupportedFramePointerAction
+            }
+            unwind_error_UNWIND_ERROR_UNSUPPORTED_EXPRESSION => UnwindError::UnsupportedExpression,
+            unwind_error_UNWIND_ERROR_UNSUPPORTED_CFA_REGISTER => {
+                UnwindError::UnsupportedCfaRegister
+            }
+            unwind_error_UNWIND_ERROR_PREVIOUS_RSP_ZERO => UnwindError::PreviousRspZero,
+            unwind_error_UNWIND_ERROR_PREVIOUS_RSP_READ => UnwindError::PreviousRspRead,
+            unwind_error_UNWIND_ERROR_PREVIOUS_RBP_READ => UnwindError::PreviousRbpRead,
+            unwind_error_UNWIND_ERROR_PREVIOUS_RIP_ZERO => UnwindError::PreviousRipZero,
+            unwind_error_UNWIND_ERROR_FRAME_POINTER => UnwindError::FramePointer,
+            unwind_error_UNWIND_ERROR_TRUNCATED => UnwindError::Truncated,
+            unwind_error_UNWIND_ERROR_SHOULD_NEVER_HAPPEN => UnwindError::ShouldNeverHappen,
+            other => return Err(RawSampleParsingError::UnknownUnwindError(other)),
+        }))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnwindError::MappingNotFound => "mapping not found",
+            UnwindError::MappingDoesNotContainPc => "mapping does not contain pc",
+            UnwindError::BinarySearch => "unwind row not found",
+            UnwindError::CfaOffsetDidNotFit => "cfa offset did not fit",
+            UnwindError::RbpOffsetDidNotFit => "rbp offset did not fit",
+            UnwindError::UnsupportedFramePointerAction => "unsupported frame pointer action",
+            UnwindError::UnsupportedExpression => "unsupported expression",
+            UnwindError::UnsupportedCfaRegister => "unsupported cfa register",
+            UnwindError::PreviousRspZero => "previous rsp is zero",
+            UnwindError::PreviousRspRead => "previous rsp read failed",
+            UnwindError::PreviousRbpRead => "previous rbp read failed",
+            UnwindError::PreviousRipZero => "previous rip is zero",
+            UnwindError::FramePointer => "invalid frame pointer",
+            UnwindError::Truncated => "truncated",
+            UnwindError::ShouldNeverHappen => "should never happen",
+        }
+    }
+}
+
 /// This *must* be in sync with the C struct `sample_t`.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct RawSample {
     pub pid: Pid,
     pub tid: Pid,
     pub collected_at: u64,
+    /// On-CPU samples are weighted by the perf event period that triggered them,
+    /// off-CPU samples by the nanoseconds spent switched out, allocations by
+    /// the bytes requested, and contention and run-queue samples by the nanoseconds
+    /// waited.
+    pub weight: u64,
+    /// cgroup v2 id of the task.
+    pub cgroup_id: u64,
+    /// Memory allocated or freed, for heap samples, or the futex waited on, for
+    /// contention samples.
+    pub address: u64,
+    pub kind: SampleKind,
+    /// CPU the stack was collected on.
+    pub cpu: u32,
+    pub context: ExecutionContext,
+    /// Set when the user stack couldn't be fully unwound.
+    pub unwind_error: Option<UnwindError>,
+    /// Name of the task, only collected for kernel threads and the idle task.
+    pub comm: Option<String>,
+    /// How the task waited on the futex, for contention samples.
+    pub futex_wait: Option<FutexWait>,
+    /// Generation of the process when the sample was collected, which selects the
+    /// mappings of the executable it ran before any later exec. Set once the profile
+    /// is collected, as exec events can be handled after the samples are received.
+    pub generation: u32,
     pub ustack: Vec<u64>,
     pub kstack: Vec<u64>,
 }
@@ -30,6 +259,14 @@ pub enum RawSampleParsingError {
     StackTooSmall,
     #[error("expected fewer bytes for the sample")]
     SampleTooLarge,
+    #[error("unknown sample kind {0}")]
+    UnknownKind(u32),
+    #[error("unknown sample context {0}")]
+    UnknownContext(u32),
+    #[error("unknown unwind error {0}")]
+    UnknownUnwindError(u32),
+    #[error("unknown futex wait {0}")]
+    UnknownFutexWait(u32),
 }
 
 /// The unwound stack trace, [`native_stack_t`], is stored in the last field of [`sample_t`] and only the
@@ -37,30 +274,78 @@ pub enum RawSampleParsingError {
 /// This is similar to C99's "Flexible Array Fields" and it is the reason why we need to manually parse it
 /// as `plain` doesn't correctly know how to deal with this.
 impl RawSample {
+    /// Offset of the stack addresses within [`sample_t`].
+    const STACK_OFFSET: usize = offset_of!(sample_t, stack) + offset_of!(native_stack_t, addresses);
+
+    /// Size of a [`sample_t`] with up to `max_stack_depth` user and kernel frames.
+    pub fn max_size(max_stack_depth: u32) -> usize {
+        Self::STACK_OFFSET + 2 * max_stack_depth as usize * std::mem::size_of::<u64>()
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        u64::from_ne_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
     pub fn from_bytes(data: &[u8]) -> Result<Self, RawSampleParsingError> {
         let sample_len = data.len();
-        if sample_len < 24 {
+        if sample_len < Self::STACK_OFFSET {
             return Err(RawSampleParsingError::BeforeStackTooSmall);
         }
-        if sample_len > 24 + 127 * 2 * 8 {
+        if sample_len > std::mem::size_of::<sample_t>() {
             return Err(RawSampleParsingError::SampleTooLarge);
         }
 
-        let pid = i32::from_ne_bytes(data[0..4].try_into().unwrap());
-        let tid = i32::from_ne_bytes(data[4..8].try_into().unwrap());
-        let collected_at = u64::from_ne_bytes(data[8..16].try_into().unwrap());
-        let ulen = u32::from_ne_bytes(data[16..20].try_into().unwrap()) as usize;
-        let klen = u32::from_ne_bytes(data[20..24].try_into().unwrap()) as usize;
-
-        if sample_len < 24 + (ulen + klen) * 8 {
+        let pid = Self::read_u32(data, offset_of!(sample_t, pid)) as i32;
+        let tid = Self::read_u32(data, offset_of!(sample_t, tid)) as i32;
+        let collected_at = Self::read_u64(data, offset_of!(sample_t, collected_at));
+        let weight = Self::read_u64(data, offset_of!(sample_t, weight));
+        let cgroup_id = Self::read_u64(data, offset_of!(sample_t, cgroup_id));
+        let address = Self::read_u64(data, offset_of!(sample_t, address));
+        let kind = match Self::read_u32(data, offset_of!(sample_t, kind)) {
+            sample_kind_SAMPLE_KIND_ON_CPU => SampleKind::OnCpu,
+            sample_kind_SAMPLE_KIND_OFF_CPU => SampleKind::OffCpu,
+            sample_kind_SAMPLE_KIND_ALLOCATION => SampleKind::Allocation,
+            sample_kind_SAMPLE_KIND_FREE => SampleKind::Free,
+            sample_kind_SAMPLE_KIND_CONTENTION => SampleKind::Contention,
+            sample_kind_SAMPLE_KIND_RUNQUEUE => SampleKind::RunQueue,
+            other => return Err(RawSampleParsingError::UnknownKind(other)),
+        };
+        let cpu = Self::read_u32(data, offset_of!(sample_t, cpu));
+        let context = match Self::read_u32(data, offset_of!(sample_t, context)) {
+            sample_context_SAMPLE_CONTEXT_TASK => ExecutionContext::Task,
+            sample_context_SAMPLE_CONTEXT_SOFTIRQ => ExecutionContext::SoftIrq,
+            sample_context_SAMPLE_CONTEXT_HARDIRQ => ExecutionContext::HardIrq,
+            sample_context_SAMPLE_CONTEXT_NMI => ExecutionContext::Nmi,
+            other => return Err(RawSampleParsingError::UnknownContext(other)),
+        };
+        let unwind_error =
+            UnwindError::from_raw(Self::read_u32(data, offset_of!(sample_t, unwind_error)))?;
+        let futex_wait =
+            FutexWait::from_raw(Self::read_u32(data, offset_of!(sample_t, futex_wait)))?;
+        let comm_offset = offset_of!(sample_t, comm);
+        let comm = data[comm_offset..comm_offset + COMM_LEN as usize]
+            .split(|byte| *byte == 0)
+            .next()
+            .filter(|comm| !comm.is_empty())
+            .map(|comm| String::from_utf8_lossy(comm).into_owned());
+        let stack_offset = offset_of!(sample_t, stack);
+        let ulen = Self::read_u32(data, stack_offset + offset_of!(native_stack_t, ulen)) as usize;
+        let klen = Self::read_u32(data, stack_offset + offset_of!(native_stack_t, klen)) as usize;
+
+        if sample_len < Self::STACK_OFFSET + (ulen + klen) * 8 {
             return Err(RawSampleParsingError::StackTooSmall);
         }
 
-        let ustack = data[24..(24 + ulen * 8)]
+        let stack = &data[Self::STACK_OFFSET..];
+        let ustack = stack[..(ulen * 8)]
             .chunks_exact(8)
             .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()))
             .collect::<Vec<_>>();
-        let kstack = data[(24 + ulen * 8)..(24 + ulen * 8 + klen * 8)]
+        let kstack = stack[(ulen * 8)..(ulen * 8 + klen * 8)]
             .chunks_exact(8)
             .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()))
             .collect::<Vec<_>>();
@@ -69,8 +354,18 @@ impl RawSample {
             pid,
             tid,
             collected_at,
+            weight,
+            cgroup_id,
+            address,
+            kind,
+            cpu,
+            context,
+            unwind_error,
+            comm,
+            futex_wait,
             ustack,
             kstack,
+            ..Default::default()
         })
     }
 }
@@ -79,10 +374,22 @@ impl std::hash::Hash for RawSample {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.pid.hash(state);
         self.kstack.hash(state);
-        // The collected_at field is excluded when hashing
-        // the samples for aggregation.
+        // The collected_at, weight and address fields are excluded
+        // when hashing the samples for aggregation.
         self.tid.hash(state);
+        self.cgroup_id.hash(state);
+        self.kind.hash(state);
+        self.cpu.hash(state);
+        self.context.hash(state);
+        self.unwind_error.hash(state);
+        self.comm.hash(state);
+        self.futex_wait.hash(state);
+        self.generation.hash(state);
         self.ustack.hash(state);
+        // Except for contention samples, which are aggregated per lock.
+        if self.kind == SampleKind::Contention {
+            self.address.hash(state);
+        }
     }
 }
 
@@ -110,6 +417,8 @@ impl fmt::Display for RawSample {
 pub struct RawAggregatedSample {
     pub sample: RawSample,
     pub count: u64,
+    /// Sum of the weights of all the aggregated samples.
+    pub weight: u64,
 }
 
 impl RawAggregatedSample {
@@ -128,14 +437,26 @@ impl RawAggregatedSample {
             ustack: Vec::new(),
             kstack: Vec::new(),
             count: self.count,
+            weight: self.weight,
+            cgroup_id: self.sample.cgroup_id,
+            kind: self.sample.kind,
+            cpu: self.sample.cpu,
+            context: self.sample.context,
+            lock_address: (self.sample.kind == SampleKind::Contention)
+                .then_some(self.sample.address),
+            unwind_error: self.sample.unwind_error,
+            comm: self.sample.comm.clone(),
+            futex_wait: self.sample.futex_wait,
+            generation: self.sample.generation,
         };
 
         let Some(info) = procs.get(&self.sample.pid) else {
             return Err(anyhow!("process not found"));
         };
 
+        let mappings = info.mappings_for(self.sample.generation);
         for virtual_address in &self.sample.ustack {
-            let Some(mapping) = info.mappings.for_address(virtual_address) else {
+            let Some(mapping) = mappings.for_address(virtual_address) else {
                 continue;
             };
 
@@ -180,7 +501,11 @@ impl RawAggregatedSample {
             });
         }
 
-        if processed_sample.ustack.is_empty() && processed_sample.kstack.is_empty() {
+        // Samples whose unwinding failed right away are kept, so the loss is accounted for.
+        if processed_sample.ustack.is_empty()
+            && processed_sample.kstack.is_empty()
+            && processed_sample.unwind_error.is_none()
+        {
             return Err(anyhow!("no user or kernel stack present"));
         }
 
@@ -213,6 +538,21 @@ pub struct AggregatedSample {
     pub ustack: Vec<Frame>,
     pub kstack: Vec<Frame>,
     pub count: u64,
+    pub weight: u64,
+    pub cgroup_id: u64,
+    pub kind: SampleKind,
+    pub cpu: u32,
+    pub context: ExecutionContext,
+    /// Futex the task waited on, for contention samples.
+    pub lock_address: Option<u64>,
+    /// Set when the user stack couldn't be fully unwound.
+    pub unwind_error: Option<UnwindError>,
+    /// Name of the task, only collected for kernel threads and the idle task.
+    pub comm: Option<String>,
+    /// How the task waited on the futex, for contention samples.
+    pub futex_wait: Option<FutexWait>,
+    /// Generation of the process the user stack belongs to.
+    pub generation: u32,
 }
 
 impl fmt::Display for AggregatedSample {
@@ -247,8 +587,7 @@ pub type AggregatedProfile = Vec<AggregatedSample>;
 
 #[cfg(test)]
 mod tests {
-    use crate::bpf::profiler_bindings::native_stack_t;
-    use crate::bpf::profiler_bindings::sample_t;
+    use crate::bpf::profiler_bindings::MAX_STACK_DEPTH;
     use crate::profile::SymbolizedFrame;
 
     use super::*;
@@ -264,14 +603,24 @@ mod tests {
             pid: 234,
             tid: 987,
             collected_at: 0xDEADBEEF,
+            weight: 0xBEEF,
+            cgroup_id: 0xCAFE,
+            address: 0,
+            kind: sample_kind_SAMPLE_KIND_OFF_CPU,
+            cpu: 0,
+            context: sample_context_SAMPLE_CONTEXT_TASK,
+            unwind_error: unwind_error_UNWIND_ERROR_NONE,
+            futex_wait: 0,
+            padding: 0,
+            comm: [0; COMM_LEN as usize],
             stack: native_stack_t {
                 ulen: 2,
                 klen: 1,
-                addresses: [0; 254],
+                addresses: [0; MAX_STACK_DEPTH as usize * 2],
             },
         };
         assert_eq!(
-            RawSample::from_bytes(&unsafe { plain::as_bytes(&c_sample) }[..30]),
+            RawSample::from_bytes(&unsafe { plain::as_bytes(&c_sample) }[..70]),
             Err(RawSampleParsingError::StackTooSmall)
         );
     }
@@ -282,10 +631,20 @@ mod tests {
             pid: 234,
             tid: 987,
             collected_at: 0xDEADBEEF,
+            weight: 0xBEEF,
+            cgroup_id: 0xCAFE,
+            address: 0,
+            kind: sample_kind_SAMPLE_KIND_OFF_CPU,
+            cpu: 0,
+            context: sample_context_SAMPLE_CONTEXT_TASK,
+            unwind_error: unwind_error_UNWIND_ERROR_NONE,
+            futex_wait: 0,
+            padding: 0,
+            comm: [0; COMM_LEN as usize],
             stack: native_stack_t {
                 ulen: 2,
                 klen: 1,
-                addresses: [0; 254],
+                addresses: [0; MAX_STACK_DEPTH as usize * 2],
             },
         };
         let bytes = unsafe { plain::as_bytes(&c_sample) };
@@ -298,16 +657,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sample_max_size() {
+        assert_eq!(
+            RawSample::max_size(MAX_STACK_DEPTH),
+            std::mem::size_of::<sample_t>()
+        );
+        assert!(RawSample::max_size(1) < RawSample::max_size(MAX_STACK_DEPTH));
+    }
+
     #[test]
     fn test_sample_parsing() {
         let mut c_sample = sample_t {
             pid: 234,
             tid: 987,
             collected_at: 0xDEADBEEF,
+            weight: 0xBEEF,
+            cgroup_id: 0xCAFE,
+            address: 0,
+            kind: sample_kind_SAMPLE_KIND_OFF_CPU,
+            cpu: 3,
+            context: sample_context_SAMPLE_CONTEXT_HARDIRQ,
+            unwind_error: unwind_error_UNWIND_ERROR_NONE,
+            futex_wait: 0,
+            padding: 0,
+            comm: [0; COMM_LEN as usize],
             stack: native_stack_t {
                 ulen: 2,
                 klen: 1,
-                addresses: [0; 254],
+                addresses: [0; MAX_STACK_DEPTH as usize * 2],
             },
         };
 
@@ -323,8 +701,162 @@ mod tests {
                 pid: 234,
                 tid: 987,
                 collected_at: 0xDEADBEEF,
+                weight: 0xBEEF,
+                cgroup_id: 0xCAFE,
+                kind: SampleKind::OffCpu,
+                cpu: 3,
+                context: ExecutionContext::HardIrq,
                 ustack: vec![0xFFFBBBDDD, 0x113355770],
-                kstack: vec![0xBBBAAADDD]
+                kstack: vec![0xBBBAAADDD],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_partial_sample_parsing() {
+        let mut c_sample = sample_t {
+            pid: 234,
+            tid: 987,
+            collected_at: 0xDEADBEEF,
+            weight: 0xBEEF,
+            cgroup_id: 0xCAFE,
+            address: 0,
+            kind: sample_kind_SAMPLE_KIND_ON_CPU,
+            cpu: 0,
+            context: sample_context_SAMPLE_CONTEXT_TASK,
+            unwind_error: unwind_error_UNWIND_ERROR_MAPPING_NOT_FOUND,
+            futex_wait: 0,
+            padding: 0,
+            comm: [0; COMM_LEN as usize],
+            stack: native_stack_t {
+                ulen: 1,
+                klen: 0,
+                addresses: [0; MAX_STACK_DEPTH as usize * 2],
+            },
+        };
+        c_sample.stack.addresses[0] = 0xFFFBBBDDD;
+
+        assert_eq!(
+            RawSample::from_bytes(unsafe { plain::as_bytes(&c_sample) })
+                .unwrap()
+                .unwind_error,
+            Some(UnwindError::MappingNotFound)
+        );
+
+        c_sample.unwind_error = 0xBAD;
+        assert_eq!(
+            RawSample::from_bytes(unsafe { plain::as_bytes(&c_sample) }),
+            Err(RawSampleParsingError::UnknownUnwindError(0xBAD))
+        );
+    }
+
+    #[test]
+    fn test_kernel_sample_comm_parsing() {
+        let mut c_sample = sample_t {
+            pid: 0,
+            tid: 42,
+            collected_at: 0xDEADBEEF,
+            weight: 0xBEEF,
+            cgroup_id: 0xCAFE,
+            address: 0,
+            kind: sample_kind_SAMPLE_KIND_ON_CPU,
+            cpu: 0,
+            context: sample_context_SAMPLE_CONTEXT_TASK,
+            unwind_error: unwind_error_UNWIND_ERROR_NONE,
+            futex_wait: 0,
+            padding: 0,
+            comm: [0; COMM_LEN as usize],
+            stack: native_stack_t {
+                ulen: 0,
+                klen: 1,
+                addresses: [0; MAX_STACK_DEPTH as usize * 2],
+            },
+        };
+        c_sample.stack.addresses[0] = 0xBBBAAADDD;
+        for (c, byte) in c_sample.comm.iter_mut().zip(b"kworker/0:1") {
+            *c = *byte as _;
+        }
+
+        assert_eq!(
+            RawSample::from_bytes(unsafe { plain::as_bytes(&c_sample) })
+                .unwrap()
+                .comm,
+            Some("kworker/0:1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_contention_sample_parsing() {
+        let mut c_sample = sample_t {
+            pid: 234,
+            tid: 987,
+            collected_at: 0xDEADBEEF,
+            weight: 0,
+            cgroup_id: 0xCAFE,
+            address: 0x7F00BEEF,
+            kind: sample_kind_SAMPLE_KIND_CONTENTION,
+            cpu: 0,
+            context: sample_context_SAMPLE_CONTEXT_TASK,
+            unwind_error: unwind_error_UNWIND_ERROR_NONE,
+            futex_wait: futex_wait_FUTEX_WAIT_WAIT_BITSET,
+            padding: 0,
+            comm: [0; COMM_LEN as usize],
+            stack: native_stack_t {
+                ulen: 1,
+                klen: 0,
+                addresses: [0; MAX_STACK_DEPTH as usize * 2],
+            },
+        };
+        c_sample.stack.addresses[0] = 0xAAAAAAAAA;
+
+        let raw_sample = RawSample::from_bytes(unsafe { plain::as_bytes(&c_sample) }).unwrap();
+        assert_eq!(raw_sample.kind, SampleKind::Contention);
+        assert_eq!(raw_sample.address, 0x7F00BEEF);
+        assert_eq!(raw_sample.futex_wait, Some(FutexWait::WaitBitset));
+
+        c_sample.futex_wait = 0xFF;
+        assert_eq!(
+            RawSample::from_bytes(unsafe { plain::as_bytes(&c_sample) }),
+            Err(RawSampleParsingError::UnknownFutexWait(0xFF))
+        );
+    }
+
+    #[test]
+    fn test_free_sample_parsing() {
+        let c_sample = sample_t {
+            pid: 234,
+            tid: 987,
+            collected_at: 0xDEADBEEF,
+            weight: 0,
+            cgroup_id: 0xCAFE,
+            address: 0x7F00BEEF,
+            kind: sample_kind_SAMPLE_KIND_FREE,
+            cpu: 0,
+            context: sample_context_SAMPLE_CONTEXT_TASK,
+            unwind_error: unwind_error_UNWIND_ERROR_NONE,
+            futex_wait: 0,
+            padding: 0,
+            comm: [0; COMM_LEN as usize],
+            stack: native_stack_t {
+                ulen: 0,
+                klen: 0,
+                addresses: [0; MAX_STACK_DEPTH as usize * 2],
+            },
+        };
+
+        assert_eq!(
+            RawSample::from_bytes(&unsafe { plain::as_bytes(&c_sample) }[..64]),
+            Ok(RawSample {
+                pid: 234,
+                tid: 987,
+                collected_at: 0xDEADBEEF,
+                cgroup_id: 0xCAFE,
+                address: 0x7F00BEEF,
+                kind: SampleKind::Free,
+                ustack: vec![],
+                kstack: vec![],
+                ..Default::default()
             })
         );
     }
@@ -336,11 +868,12 @@ mod tests {
             sample: RawSample {
                 pid: 1234,
                 tid: 1235,
-                collected_at: 1748865070,
                 ustack: vec![0xffff, 0xdeadbeef],
                 kstack: vec![],
+                ..Default::default()
             },
             count: 1,
+            weight: 0,
         };
         insta::assert_yaml_snapshot!(format!("{}", raw_aggregated_sample), @r#""RawAggregatedSample { sample: \"RawSample { pid: 1234, tid: 1235, ustack: \\\"[  0: 0x000000000000ffff,  1: 0x00000000deadbeef]\\\", kstack: \\\"[]\\\" }\", count: 1 }""#);
 
@@ -352,8 +885,10 @@ mod tests {
                 collected_at: 1748865170,
                 ustack: vec![],
                 kstack: vec![],
+                ..Default::default()
             },
             count: 1,
+            weight: 0,
         };
         insta::assert_yaml_snapshot!(format!("{}", raw_aggregated_sample), @r#""RawAggregatedSample { sample: \"RawSample { pid: 1234, tid: 1235, ustack: \\\"[]\\\", kstack: \\\"[]\\\" }\", count: 1 }""#);
     }
@@ -393,6 +928,7 @@ mod tests {
             ustack: ustack_data,
             kstack: kstack_data.clone(),
             count: 128,
+            ..Default::default()
         };
         insta::assert_yaml_snapshot!(format!("{}", sample), @r#""SymbolizedAggregatedSample { pid: 1234567, tid: 1234568, ustack: \"[  0: ufunc3,  1: ufunc2,  2: ufunc1]\", kstack: \"[  0: kfunc2,  1: kfunc1]\", count: 128 }""#);
 
@@ -404,6 +940,7 @@ mod tests {
             ustack: ustack_data,
             kstack: kstack_data.clone(),
             count: 1001,
+            ..Default::default()
         };
         insta::assert_yaml_snapshot!(format!("{}", sample), @r#""SymbolizedAggregatedSample { pid: 98765, tid: 98766, ustack: \"[NONE]\", kstack: \"[  0: kfunc2,  1: kfunc1]\", count: 1001 }""#);
     }
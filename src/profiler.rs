@@ -6,6 +6,7 @@ use parking_lot::RwLock;
 use std::collections::hash_map::Entry;
 use std::collections::hash_map::OccupiedEntry;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env::temp_dir;
 use std::fs;
 use std::fs::File;
@@ -15,14 +16,16 @@ use std::mem::size_of;
 use std::mem::ManuallyDrop;
 use std::mem::MaybeUninit;
 use std::os::fd::{AsFd, AsRawFd};
-use std::os::unix::fs::FileExt;
+use std::os::raw::c_int;
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
-use crossbeam_channel::{bounded, select, tick, unbounded, Receiver, Sender};
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{bounded, never, select, tick, unbounded, Receiver, Sender};
 use itertools::Itertools;
 use libbpf_rs::num_possible_cpus;
 use libbpf_rs::skel::SkelBuilder;
@@ -30,14 +33,20 @@ use libbpf_rs::skel::{OpenSkel, Skel};
 use libbpf_rs::MapCore;
 use libbpf_rs::MapHandle;
 use libbpf_rs::MapType;
-use libbpf_rs::{Link, MapFlags, PerfBufferBuilder};
+use libbpf_rs::{Link, MapFlags, PerfBufferBuilder, UprobeOpts};
 use memmap2::MmapOptions;
+use nix::libc::MAP_ANONYMOUS;
 use procfs;
 use tracing::{debug, error, info, span, warn, Level};
 
-use crate::aggregator::Aggregator;
+use crate::adaptive_frequency::{
+    bpf_run_time, profiler_cpu_time, AdaptiveFrequencyConfig, BpfStats, FrequencyController,
+    OverheadMeter,
+};
+use crate::aggregator::{Aggregator, LiveAllocations};
 use crate::bpf::profiler_bindings::*;
 use crate::bpf::profiler_skel::{OpenProfilerSkel, ProfilerSkel, ProfilerSkelBuilder};
+use crate::bpf::runqueue_skel::{RunqueueSkel, RunqueueSkelBuilder};
 use crate::bpf::tracers_bindings::*;
 use crate::bpf::tracers_skel::OpenTracersSkel;
 use crate::bpf::tracers_skel::{TracersSkel, TracersSkelBuilder};
@@ -46,14 +55,17 @@ use crate::debug_info::DebugInfoBackendNull;
 use crate::debug_info::DebugInfoManager;
 use crate::kernel::get_all_kernel_modules;
 use crate::kernel::KERNEL_PID;
-use crate::perf_events::setup_perf_event;
+use crate::perf_events::{
+    set_sample_freq, setup_perf_event, setup_thread_perf_event, PerfEventConfig, PerfEventType,
+};
+use crate::probes::UserProbe;
 use crate::process::{
     ExecutableMapping, ExecutableMappingType, ExecutableMappings, ObjectFileInfo, Pid, ProcessInfo,
-    ProcessStatus,
+    ProcessMatcher, ProcessStatus,
 };
 use crate::profile::*;
 use crate::unwind_info::manager::UnwindInfoManager;
-use crate::unwind_info::types::CompactUnwindRow;
+use crate::unwind_info::types::{CfaType, CompactUnwindRow, CFA_EXPRESSIONS};
 use crate::util::executable_path;
 use crate::util::page_size;
 use crate::util::roundup_page;
@@ -66,10 +78,56 @@ use lightswitch_metadata::types::TaskKey;
 use lightswitch_object::{ExecutableId, ObjectFile, Runtime};
 
 const MAX_UNWIND_INFO_SIZE: usize = 7_000_000;
+/// Off-CPU samples aren't driven by a sampling frequency, so the stacks ring
+/// buffer is sized assuming this many samples might be in flight at once.
+const OFF_CPU_EXPECTED_SAMPLES: u32 = 1024;
+/// The pending samples of switched out threads are preallocated, so their number
+/// is limited to keep them within this many bytes.
+const OFF_CPU_SAMPLES_MAX_BYTES: u32 = 16 * 1024 * 1024;
+/// Highest sampling frequency while waiting for a trigger, as only the samples of
+/// the pre-roll window are kept.
+const PRE_ROLL_SAMPLE_FREQ: u64 = 7;
+/// Allocations and user-defined probes can happen at a very high rate. This many
+/// samples are expected to be in flight at once when using uprobes.
+const UPROBE_EXPECTED_SAMPLES: u32 = 4096;
+
+/// What triggers the collection of a stack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProfilerMode {
+    /// Periodically sample the stacks of tasks running on a CPU.
+    #[default]
+    OnCpu,
+    /// Collect the stacks of tasks when they are switched out, weighted by the time
+    /// they spent off-CPU.
+    OffCpu,
+    /// Combine on-CPU and off-CPU samples so that the time attributed to every thread
+    /// adds up to the elapsed time.
+    Wallclock,
+    /// Collect the stacks that allocate memory in the profiled processes, weighted by
+    /// the bytes requested, as well as the memory that hasn't been freed yet.
+    Heap,
+    /// Collect a stack every time one of the user-defined probes is hit.
+    Probes,
+    /// Collect the stacks of tasks that wait on a futex, weighted by the time they
+    /// waited, per lock.
+    Contention,
+    /// Collect the stacks of tasks when they are switched out, weighted by the time
+    /// they waited for a CPU once runnable again.
+    RunQueue,
+}
 
 pub enum TracerEvent {
     ProcessExit(Pid),
     Munmap(Pid, u64),
+    /// A followed process forked this child process.
+    ProcessFork(Pid),
+    /// A known process replaced its executable at this boot time, in nanoseconds.
+    ProcessExec(Pid, u64),
+    /// A known process mapped an executable region with this start address, length,
+    /// file offset and mmap flags.
+    Mmap(Pid, u64, u64, u64, u64),
+    /// A known process made the region with this start address and length executable.
+    Mprotect(Pid, u64, u64),
 }
 
 pub struct KnownExecutableInfo {
@@ -77,6 +135,8 @@ pub struct KnownExecutableInfo {
     unwind_info_start_address: u64,
     unwind_info_end_address: u64,
     last_used: Instant,
+    /// The CFA expressions the unwind information uses.
+    cfa_expression_ids: Vec<u16>,
 }
 
 pub struct NativeUnwindState {
@@ -118,6 +178,9 @@ pub struct Profiler {
     native_unwinder: ManuallyDrop<ProfilerSkel<'static>>,
     tracers_open_object: ManuallyDrop<Box<MaybeUninit<OpenObject>>>,
     tracers: ManuallyDrop<TracersSkel<'static>>,
+    runqueue_open_object: ManuallyDrop<Box<MaybeUninit<OpenObject>>>,
+    /// Only loaded when measuring the run-queue latency.
+    runqueue: ManuallyDrop<Option<RunqueueSkel<'static>>>,
     procs: Arc<RwLock<HashMap<Pid, ProcessInfo>>>,
     object_files: Arc<RwLock<HashMap<ExecutableId, ObjectFileInfo>>>,
     // Channel for new process events.
@@ -128,12 +191,27 @@ pub struct Profiler {
     tracers_chan_receive: Arc<Receiver<TracerEvent>>,
     /// Profiler stop channel. Used to receive signals from users to stop profiling.
     stop_chan_receive: Receiver<()>,
+    /// Profiler start channel. If set, profiles are only recorded once a trigger fires.
+    start_chan_receive: Option<Receiver<()>>,
+    /// How far back the samples collected before the trigger fired are kept.
+    pre_roll: Duration,
+    /// Profiler ready channel. If set, notified once profiling has started.
+    ready_chan_send: Option<Sender<()>>,
     pub(crate) native_unwind_state: NativeUnwindState,
     /// Pids excluded from profiling.
     filter_pids: HashMap<Pid, bool>,
-    // Profile channel
-    profile_send: Arc<Sender<RawAggregatedProfile>>,
-    profile_receive: Arc<Receiver<RawAggregatedProfile>>,
+    /// Whether to open perf events for the threads of the filtered processes rather
+    /// than on every CPU.
+    per_process_perf_events: bool,
+    /// Whether to also profile the descendants of the filtered processes.
+    follow_children: bool,
+    /// Descendants of the filtered processes that are being profiled.
+    followed_pids: HashSet<Pid>,
+    /// Processes to profile by name, executable or command line.
+    process_matcher: ProcessMatcher,
+    // Profile channel, along with the sampling frequency of the session
+    profile_send: Arc<Sender<(RawAggregatedProfile, u64)>>,
+    profile_receive: Arc<Receiver<(RawAggregatedProfile, u64)>>,
     // A vector of raw samples received from bpf in the current profiling session
     raw_samples: Vec<RawSample>,
     // Raw samples channel. Used for receiving raw samples from the ringbuf/perfbuf poll thread
@@ -141,8 +219,23 @@ pub struct Profiler {
     raw_sample_receive: Arc<Receiver<RawSample>>,
     /// For how long to profile.
     duration: Duration,
-    /// Per-CPU sampling frequency in Hz.
-    sample_freq: u64,
+    /// Event that triggers on-CPU samples and how often.
+    perf_event_config: PerfEventConfig,
+    /// Perf events that trigger on-CPU samples.
+    perf_fds: Vec<c_int>,
+    /// Links of the BPF program to `perf_fds`, which own and close them.
+    perf_event_links: Vec<Link>,
+    /// Adjusts the sampling frequency to keep the overhead within a budget, if enabled.
+    frequency_controller: Option<FrequencyController>,
+    overhead_meter: OverheadMeter,
+    /// Keeps the run time of the BPF programs accounted while the frequency is adjusted.
+    _bpf_stats: Option<BpfStats>,
+    /// Samples dropped because the perf buffers were full.
+    lost_samples: Arc<AtomicU64>,
+    /// What triggers the collection of a stack.
+    mode: ProfilerMode,
+    /// Functions and USDT probes that trigger the collection of a stack.
+    probes: Vec<UserProbe>,
     /// Size of the perf buffer.
     perf_buffer_bytes: usize,
     /// For how long to profile until the aggregated in-kernel profiles are read.
@@ -158,6 +251,8 @@ pub struct Profiler {
     unwind_info_manager: UnwindInfoManager,
     use_ring_buffers: bool,
     aggregator: Aggregator,
+    /// Allocations that haven't been freed yet, when profiling the heap.
+    live_allocations: LiveAllocations,
     metadata_provider: ThreadSafeGlobalMetadataProvider,
     // Baseline for calculating raw_sample collection wall clock time
     // as bpf currently only supports getting the offset since system boot.
@@ -170,11 +265,33 @@ pub struct ProfilerConfig {
     pub bpf_logging: bool,
     pub duration: Duration,
     pub sample_freq: u64,
+    /// Overrides `sample_freq` to take a sample every this many events.
+    pub sample_period: Option<u64>,
+    pub perf_event: PerfEventType,
+    pub mode: ProfilerMode,
+    /// Functions and USDT probes that trigger the collection of a stack in probes mode.
+    pub probes: Vec<UserProbe>,
     pub perf_buffer_bytes: usize,
     pub session_duration: Duration,
     pub mapsize_info: bool,
     pub mapsize_rate_limits: u32,
     pub exclude_self: bool,
+    /// When profiling specific pids, only open perf events for their threads.
+    pub per_process_perf_events: bool,
+    /// When profiling specific pids, also profile the processes they fork.
+    pub follow_children: bool,
+    /// Profile the processes that match, along with the specific pids if any.
+    pub process_matcher: ProcessMatcher,
+    /// Only profile the tasks within this cgroup v2 subtree.
+    pub cgroup: Option<PathBuf>,
+    /// Adjust the sampling frequency at runtime to keep the overhead within a budget.
+    pub adaptive_sample_freq: Option<AdaptiveFrequencyConfig>,
+    /// Collect the kernel stacks of the idle task, to account for idle time.
+    pub keep_idle: bool,
+    /// Collect the kernel stacks of kernel threads, such as kworkers.
+    pub keep_kthreads: bool,
+    /// Number of user frames after which unwinding stops, up to [`MAX_STACK_DEPTH`].
+    pub max_stack_depth: u32,
     pub debug_info_manager: Box<dyn DebugInfoManager>,
     pub max_native_unwind_info_size_mb: i32,
     pub use_ring_buffers: bool,
@@ -189,11 +306,23 @@ impl Default for ProfilerConfig {
             bpf_logging: false,
             duration: Duration::MAX,
             sample_freq: 19,
+            sample_period: None,
+            perf_event: PerfEventType::CpuClock,
+            mode: ProfilerMode::OnCpu,
+            probes: Vec::new(),
             perf_buffer_bytes: 512 * 1024,
             session_duration: Duration::from_secs(5),
             mapsize_info: false,
             mapsize_rate_limits: 5000,
             exclude_self: false,
+            per_process_perf_events: false,
+            follow_children: false,
+            process_matcher: ProcessMatcher::default(),
+            cgroup: None,
+            adaptive_sample_freq: None,
+            keep_idle: false,
+            keep_kthreads: false,
+            max_stack_depth: DEFAULT_STACK_DEPTH,
             debug_info_manager: Box::new(DebugInfoBackendNull {}),
             max_native_unwind_info_size_mb: i32::MAX,
             use_ring_buffers: true,
@@ -202,6 +331,16 @@ impl Default for ProfilerConfig {
     }
 }
 
+impl ProfilerConfig {
+    pub fn perf_event_config(&self) -> PerfEventConfig {
+        PerfEventConfig {
+            event: self.perf_event.clone(),
+            sample_freq: self.sample_freq,
+            sample_period: self.sample_period,
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error, PartialEq, Eq, Hash, Clone)]
 pub enum AddProcessError {
     #[error("could not evict process information")]
@@ -230,6 +369,9 @@ impl Drop for Profiler {
 
         unsafe { ManuallyDrop::drop(&mut self.tracers) };
         unsafe { ManuallyDrop::drop(&mut self.tracers_open_object) };
+
+        unsafe { ManuallyDrop::drop(&mut self.runqueue) };
+        unsafe { ManuallyDrop::drop(&mut self.runqueue_open_object) };
     }
 }
 
@@ -257,6 +399,35 @@ fn fetch_vdso_info(
     Ok((dumped_vdso, object))
 }
 
+/// Finds the allocator used by a process, which is the first executable mapping that
+/// defines `malloc`. This is typically the C library, unless the executable is
+/// statically linked or ships its own allocator.
+fn find_allocator(pid: Pid) -> Option<(PathBuf, ObjectFile)> {
+    let proc = procfs::process::Process::new(pid).ok()?;
+    let maps = proc.maps().ok()?;
+
+    for map in maps.iter() {
+        if !map.perms.contains(procfs::process::MMPermissions::EXECUTE) {
+            continue;
+        }
+        let procfs::process::MMapPath::Path(path) = &map.pathname else {
+            continue;
+        };
+        let Ok(exe_path) = executable_path(pid, path) else {
+            continue;
+        };
+        let Ok(object_file) = ObjectFile::from_path(&exe_path) else {
+            continue;
+        };
+
+        if object_file.symbol_file_offset("malloc").is_some() {
+            return Some((exe_path, object_file));
+        }
+    }
+
+    None
+}
+
 enum AddUnwindInformationResult {
     /// The unwind information information and its pages were correctly loaded in BPF maps.
     Success,
@@ -280,6 +451,8 @@ enum AddUnwindInformationError {
     BpfUnwindInfo(String),
     #[error("failed to write to BPF map that stores pages: {0}")]
     BpfPages(String),
+    #[error("failed to write to BPF map that stores CFA expressions: {0}")]
+    BpfCfaExpressions(String),
 }
 
 impl Profiler {
@@ -304,7 +477,7 @@ impl Profiler {
         inner_map_shape
     }
 
-    fn get_stacks_ringbuf_max_entries(sample_freq: u32) -> u32 {
+    fn get_stacks_ringbuf_max_entries(expected_samples: u32) -> u32 {
         // The assumption here is that although the ringbuf is shared
         // by all CPUs, it's not expected to get filled up since
         // 1. At any "single instance", we expect at most n samples to be written
@@ -315,7 +488,7 @@ impl Profiler {
         // reads the sample and dispatches it to another thread for processing.
 
         let num_cpus = get_online_cpus().expect("get online CPUs").len() as u32;
-        let num_expected_entries = std::cmp::max(num_cpus, sample_freq);
+        let num_expected_entries = std::cmp::max(num_cpus, expected_samples);
 
         let sample_size_bytes = std::mem::size_of::<sample_t>() as u32;
         let max_entries_bytes: u32 = sample_size_bytes * num_expected_entries;
@@ -343,11 +516,107 @@ impl Profiler {
             .lightswitch_config
             .use_ring_buffers
             .write(profiler_config.use_ring_buffers);
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .filter_by_cgroup
+            .write(profiler_config.cgroup.is_some());
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .runqueue_latency
+            .write(profiler_config.mode == ProfilerMode::RunQueue);
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .keep_idle
+            .write(profiler_config.keep_idle);
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .keep_kthreads
+            .write(profiler_config.keep_kthreads);
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .timer_perf_event
+            .write(profiler_config.perf_event.is_timer_event());
+        let max_stack_depth = profiler_config.max_stack_depth.min(MAX_STACK_DEPTH);
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .max_stack_depth
+            .write(max_stack_depth);
+        let deferred_sample_size = RawSample::max_size(max_stack_depth) as u32;
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .deferred_sample_size
+            .write(deferred_sample_size);
+
+        if matches!(
+            profiler_config.mode,
+            ProfilerMode::OnCpu | ProfilerMode::Heap | ProfilerMode::Probes
+        ) {
+            // Can't be zero.
+            open_skel
+                .maps
+                .off_cpu_samples
+                .set_max_entries(1)
+                .expect("set off_cpu_samples entries to one as it's unused");
+        } else {
+            // Samples are stored for every waiting thread, so they are only as
+            // large as the configured stack depth requires.
+            open_skel
+                .maps
+                .off_cpu_samples
+                .set_value_size(deferred_sample_size)
+                .expect("set off_cpu_samples value size");
+            open_skel
+                .maps
+                .off_cpu_samples
+                .set_max_entries(
+                    (OFF_CPU_SAMPLES_MAX_BYTES / deferred_sample_size).min(MAX_OFF_CPU_SAMPLES),
+                )
+                .expect("set off_cpu_samples entries");
+        }
+
+        if profiler_config.mode != ProfilerMode::RunQueue {
+            open_skel
+                .maps
+                .runqueue_enqueued_at
+                .set_max_entries(1)
+                .expect("set runqueue_enqueued_at entries to one as it's unused");
+        }
+
+        if profiler_config.mode != ProfilerMode::Heap {
+            open_skel
+                .maps
+                .inflight_allocations
+                .set_max_entries(1)
+                .expect("set inflight_allocations entries to one as it's unused");
+        }
 
         if profiler_config.use_ring_buffers {
             // Set sample collecting ringbuf size based sampling frequency
-            let profile_sample_max_entries =
-                Self::get_stacks_ringbuf_max_entries(profiler_config.sample_freq as u32);
+            let expected_samples = match profiler_config.mode {
+                ProfilerMode::OnCpu => profiler_config.sample_freq as u32,
+                ProfilerMode::OffCpu | ProfilerMode::Contention | ProfilerMode::RunQueue => {
+                    OFF_CPU_EXPECTED_SAMPLES
+                }
+                ProfilerMode::Wallclock => {
+                    profiler_config.sample_freq as u32 + OFF_CPU_EXPECTED_SAMPLES
+                }
+                ProfilerMode::Heap | ProfilerMode::Probes => UPROBE_EXPECTED_SAMPLES,
+            };
+            let profile_sample_max_entries = Self::get_stacks_ringbuf_max_entries(expected_samples);
             open_skel
                 .maps
                 .stacks_rb
@@ -405,6 +674,29 @@ impl Profiler {
         }
     }
 
+    /// Restricts profiling to the tasks within the given cgroup v2 subtree, including the
+    /// ones that join it later on.
+    fn set_cgroup_filter(bpf: &ProfilerSkel, cgroup: &Path) {
+        let cgroup_dir = File::open(cgroup)
+            .unwrap_or_else(|e| panic!("could not open cgroup {} with: {:?}", cgroup.display(), e));
+        let cgroup_id = cgroup_dir.metadata().expect("cgroup metadata").ino();
+        debug!(
+            "profiling cgroup {} with id {}",
+            cgroup.display(),
+            cgroup_id
+        );
+
+        let key: u32 = 0;
+        bpf.maps
+            .cgroup_filter
+            .update(
+                &key.to_le_bytes(),
+                &cgroup_dir.as_raw_fd().to_le_bytes(),
+                MapFlags::ANY,
+            )
+            .expect("update cgroup_filter");
+    }
+
     pub fn show_actual_profiler_map_sizes(bpf: &ProfilerSkel) {
         info!("BPF map sizes:");
         info!(
@@ -445,6 +737,7 @@ impl Profiler {
 
         let mut native_unwinder_open_object = ManuallyDrop::new(Box::new(MaybeUninit::uninit()));
         let mut tracers_open_object = ManuallyDrop::new(Box::new(MaybeUninit::uninit()));
+        let mut runqueue_open_object = ManuallyDrop::new(Box::new(MaybeUninit::uninit()));
 
         let mut skel_builder = ProfilerSkelBuilder::default();
         skel_builder.obj_builder.debug(profiler_config.libbpf_debug);
@@ -465,6 +758,10 @@ impl Profiler {
         };
 
         info!("native unwinder BPF program loaded");
+
+        if let Some(cgroup) = &profiler_config.cgroup {
+            Self::set_cgroup_filter(&native_unwinder, cgroup);
+        }
         let native_unwinder_maps = &native_unwinder.maps;
         let exec_mappings_fd = native_unwinder_maps.exec_mappings.as_fd();
 
@@ -486,6 +783,11 @@ impl Profiler {
             .exec_mappings
             .reuse_fd(exec_mappings_fd)
             .expect("reuse exec_mappings");
+        open_tracers
+            .maps
+            .denied_processes
+            .reuse_fd(native_unwinder_maps.denied_processes.as_fd())
+            .expect("reuse denied_processes");
         open_tracers
             .maps
             .rodata_data
@@ -498,6 +800,12 @@ impl Profiler {
             .lightswitch_config
             .use_ring_buffers
             .write(profiler_config.use_ring_buffers);
+        open_tracers
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .follow_children
+            .write(profiler_config.follow_children);
         Self::set_tracers_map_sizes(&mut open_tracers, &profiler_config);
 
         let tracers = ManuallyDrop::new(open_tracers.load().expect("load skel"));
@@ -510,6 +818,34 @@ impl Profiler {
 
         info!("munmap and process exit tracing BPF programs loaded");
 
+        let runqueue = if profiler_config.mode == ProfilerMode::RunQueue {
+            let mut runqueue_builder = RunqueueSkelBuilder::default();
+            runqueue_builder
+                .obj_builder
+                .debug(profiler_config.libbpf_debug);
+            let mut open_runqueue = runqueue_builder
+                .open(&mut runqueue_open_object)
+                .expect("open skel");
+            open_runqueue
+                .maps
+                .runqueue_enqueued_at
+                .reuse_fd(native_unwinder_maps.runqueue_enqueued_at.as_fd())
+                .expect("reuse runqueue_enqueued_at");
+
+            let runqueue = ManuallyDrop::new(Some(open_runqueue.load().expect("load skel")));
+            info!("run-queue tracing BPF programs loaded");
+            runqueue
+        } else {
+            ManuallyDrop::new(None)
+        };
+        // SAFETY: runqueue never outlives runqueue_open_object
+        let runqueue = unsafe {
+            std::mem::transmute::<
+                ManuallyDrop<Option<RunqueueSkel<'_>>>,
+                ManuallyDrop<Option<RunqueueSkel<'static>>>,
+            >(runqueue)
+        };
+
         let (sender, receiver) = unbounded();
         let chan_send = Arc::new(sender);
         let chan_receive = Arc::new(receiver);
@@ -531,6 +867,39 @@ impl Profiler {
         let walltime_at_system_boot =
             procfs::boot_time().unwrap().timestamp_nanos_opt().unwrap() as u64;
 
+        let mut perf_event_config = profiler_config.perf_event_config();
+        let frequency_controller = match profiler_config.adaptive_sample_freq {
+            Some(config)
+                if perf_event_config.uses_sample_freq()
+                    && matches!(
+                        profiler_config.mode,
+                        ProfilerMode::OnCpu | ProfilerMode::Wallclock
+                    ) =>
+            {
+                let controller = FrequencyController::new(config, perf_event_config.sample_freq);
+                perf_event_config.sample_freq = controller.sample_freq();
+                Some(controller)
+            }
+            Some(_) => {
+                warn!("the sampling frequency can only be adjusted for on-CPU perf events in frequency mode");
+                None
+            }
+            None => None,
+        };
+        let bpf_stats = match frequency_controller {
+            Some(_) => match BpfStats::enable() {
+                Ok(bpf_stats) => Some(bpf_stats),
+                Err(e) => {
+                    warn!(
+                        "could not enable BPF stats, the CPU usage of the BPF programs won't be measured: {:?}",
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
         Profiler {
             cache_dir,
             _links: Vec::new(),
@@ -538,6 +907,8 @@ impl Profiler {
             native_unwinder,
             tracers_open_object,
             tracers,
+            runqueue_open_object,
+            runqueue,
             procs: Arc::new(RwLock::new(HashMap::new())),
             object_files: Arc::new(RwLock::new(HashMap::new())),
             new_proc_chan_send: chan_send,
@@ -545,15 +916,30 @@ impl Profiler {
             tracers_chan_send,
             tracers_chan_receive,
             stop_chan_receive: stop_signal_receive,
+            start_chan_receive: None,
+            pre_roll: Duration::ZERO,
+            ready_chan_send: None,
             native_unwind_state,
             filter_pids: HashMap::new(),
+            per_process_perf_events: profiler_config.per_process_perf_events,
+            follow_children: profiler_config.follow_children,
+            followed_pids: HashSet::new(),
+            process_matcher: profiler_config.process_matcher,
             profile_send,
             profile_receive,
             raw_samples: Vec::new(),
             raw_sample_send: raw_sample_sender,
             raw_sample_receive: raw_sample_receiver,
             duration: profiler_config.duration,
-            sample_freq: profiler_config.sample_freq,
+            perf_event_config,
+            perf_fds: Vec::new(),
+            perf_event_links: Vec::new(),
+            frequency_controller,
+            overhead_meter: OverheadMeter::new(Instant::now(), Duration::ZERO, 0, 0),
+            _bpf_stats: bpf_stats,
+            lost_samples: Arc::new(AtomicU64::new(0)),
+            mode: profiler_config.mode,
+            probes: profiler_config.probes,
             perf_buffer_bytes: profiler_config.perf_buffer_bytes,
             session_duration: profiler_config.session_duration,
             exclude_self: profiler_config.exclude_self,
@@ -562,6 +948,7 @@ impl Profiler {
             unwind_info_manager: UnwindInfoManager::new(&unwind_cache_dir, None),
             use_ring_buffers: profiler_config.use_ring_buffers,
             aggregator: Aggregator::default(),
+            live_allocations: LiveAllocations::default(),
             metadata_provider,
             walltime_at_system_boot,
         }
@@ -573,8 +960,25 @@ impl Profiler {
         }
     }
 
+    /// Waits for a message on `start_chan_receive` before recording profiles. Until
+    /// then, the samples of the last `pre_roll` are kept so the profile shows what
+    /// led to the trigger firing. Perf events sample at a lower frequency in the
+    /// meantime, and nothing is sampled at all without a pre-roll window.
+    pub fn start_on_trigger(&mut self, start_chan_receive: Receiver<()>, pre_roll: Duration) {
+        self.start_chan_receive = Some(start_chan_receive);
+        self.pre_roll = pre_roll;
+    }
+
+    /// Sends a message on `ready_chan_send` once the BPF programs are attached and
+    /// samples are being collected.
+    pub fn notify_when_ready(&mut self, ready_chan_send: Sender<()>) {
+        self.ready_chan_send = Some(ready_chan_send);
+    }
+
     pub fn send_profile(&mut self, profile: RawAggregatedProfile) {
-        self.profile_send.send(profile).expect("handle send");
+        self.profile_send
+            .send((profile, self.perf_event_config.sample_freq))
+            .expect("handle send");
     }
 
     /// Starts a thread that polls the given ring or perf buffer, depending on the
@@ -684,6 +1088,8 @@ impl Profiler {
                                 })
                                 .collect(),
                         ),
+                        generation: 0,
+                        previous_mappings: Vec::new(),
                         last_used: Instant::now(),
                     },
                 );
@@ -712,22 +1118,72 @@ impl Profiler {
         }
     }
 
-    pub fn run(mut self, collector: ThreadSafeCollector) -> Duration {
-        self.setup_perf_events();
+    /// Attaches the BPF programs that collect the stacks of the profiling mode.
+    fn attach_samplers(&mut self) -> Result<(), anyhow::Error> {
+        match self.mode {
+            ProfilerMode::OnCpu => self.setup_perf_events()?,
+            ProfilerMode::OffCpu => self.setup_sched_switch_tracepoint(),
+            ProfilerMode::Wallclock => {
+                self.setup_perf_events()?;
+                self.setup_sched_switch_tracepoint();
+            }
+            ProfilerMode::Heap => self.setup_allocator_uprobes(),
+            ProfilerMode::Probes => self.setup_user_probes(),
+            ProfilerMode::Contention => self.setup_futex_tracepoints(),
+            ProfilerMode::RunQueue => {
+                self.setup_sched_switch_tracepoint();
+                self.runqueue
+                    .as_mut()
+                    .expect("run-queue tracers are loaded in run-queue mode")
+                    .attach()
+                    .expect("attach run-queue tracers");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run(mut self, collector: ThreadSafeCollector) -> Result<Duration, anyhow::Error> {
+        let mut start_chan_receive = self.start_chan_receive.take();
+        let mut recording = start_chan_receive.is_none();
+        // Nothing is sampled while waiting for a trigger unless the pre-roll window
+        // is kept, and then at a lower frequency.
+        let recording_sample_freq = self.perf_event_config.sample_freq;
+        if recording {
+            self.attach_samplers()?;
+        } else if !self.pre_roll.is_zero() {
+            if self.perf_event_config.uses_sample_freq() {
+                self.perf_event_config.sample_freq =
+                    recording_sample_freq.min(PRE_ROLL_SAMPLE_FREQ);
+            }
+            self.attach_samplers()?;
+        }
         self.set_bpf_map_info();
         self.add_kernel_modules();
 
+        if self.follow_children {
+            for pid in self.filter_pids.keys() {
+                self.follow_children_of(*pid);
+            }
+        }
         self.tracers.attach().expect("attach tracers");
+        if self.follow_children {
+            // Once the tracers are attached, so no fork is missed in between.
+            self.follow_existing_descendants(self.filter_pids.keys().copied().collect());
+        }
 
         let chan_send = self.new_proc_chan_send.clone();
         let raw_sample_send = self.raw_sample_send.clone();
+        let lost_samples = self.lost_samples.clone();
 
         self.start_poll_thread(
             "raw_samples",
             &self.native_unwinder.maps.stacks_rb,
             &self.native_unwinder.maps.stacks,
             move |data| Self::handle_sample(&raw_sample_send, data, self.walltime_at_system_boot),
-            Self::handle_lost_sample,
+            move |cpu, count| {
+                lost_samples.fetch_add(count, Ordering::Relaxed);
+                Self::handle_lost_sample(cpu, count);
+            },
         );
 
         self.start_poll_thread(
@@ -768,11 +1224,13 @@ impl Profiler {
 
         thread::spawn(move || loop {
             match profile_receive.recv() {
-                Ok(profile) => {
-                    collector
-                        .lock()
-                        .unwrap()
-                        .collect(profile, &procs.read(), &object_files.read());
+                Ok((profile, sample_freq)) => {
+                    collector.lock().unwrap().collect(
+                        profile,
+                        sample_freq,
+                        &procs.read(),
+                        &object_files.read(),
+                    );
                 }
                 Err(_e) => {
                     // println!("failed to receive event {:?}", e);
@@ -780,16 +1238,44 @@ impl Profiler {
             }
         });
 
-        let start = Instant::now();
-        let total_duration_tick = tick(self.duration);
+        let mut start = Instant::now();
+        self.overhead_meter = OverheadMeter::new(
+            start,
+            self.cpu_time().unwrap_or_default(),
+            self.unwinder_stats().total,
+            0,
+        );
+        if let Some(ready_chan_send) = self.ready_chan_send.take() {
+            // The processes waiting for the profiler might not run again before
+            // exiting, so their mappings are registered before they are resumed.
+            for pid in self.filter_pids.keys().copied().collect::<Vec<_>>() {
+                self.event_new_proc(pid);
+            }
+            let _ = ready_chan_send.send(());
+        }
+
+        let mut total_duration_tick = if recording {
+            tick(self.duration)
+        } else {
+            info!("waiting for a trigger to start recording profiles");
+            never()
+        };
         let session_tick = tick(self.session_duration);
 
         loop {
+            let mut triggered = false;
             select! {
+                recv(start_chan_receive.clone().unwrap_or_else(never)) -> _ => {
+                    triggered = true;
+                },
                 recv(self.stop_chan_receive) -> _ => {
                     debug!("received ctrl+c");
-                    let profile = self.collect_profile();
-                    self.send_profile(profile);
+                    if recording {
+                        let profile = self.collect_profile();
+                        self.send_profile(profile);
+                    } else {
+                        info!("stopped before any trigger fired, no profiles were recorded");
+                    }
                     break;
                 },
                 recv(total_duration_tick) -> _ => {
@@ -799,9 +1285,15 @@ impl Profiler {
                     break;
                 },
                 recv(session_tick) -> _ => {
-                    debug!("collecting profiles on schedule");
-                    let profile = self.collect_profile();
-                    self.send_profile(profile);
+                    if recording {
+                        debug!("collecting profiles on schedule");
+                        let profile = self.collect_profile();
+                        self.send_profile(profile);
+                        self.adjust_sample_freq();
+                    } else {
+                        self.discard_samples_before_pre_roll();
+                        self.clear_maps();
+                    }
                 },
                 recv(self.raw_sample_receive) -> raw_sample => {
                     if let Ok(raw_sample) = raw_sample {
@@ -816,8 +1308,24 @@ impl Profiler {
                         Ok(TracerEvent::Munmap(pid, start_address)) => {
                                 self.handle_munmap(pid, start_address);
                         },
+                        Ok(TracerEvent::Mmap(pid, start_address, length, offset, flags)) => {
+                                let anonymous = flags & MAP_ANONYMOUS as u64 != 0;
+                                self.handle_mmap(pid, start_address, length, Some(offset), anonymous);
+                        },
+                        Ok(TracerEvent::Mprotect(pid, start_address, length)) => {
+                                self.handle_mmap(pid, start_address, length, None, false);
+                        },
                         Ok(TracerEvent::ProcessExit(pid)) => {
                                 self.handle_process_exit(pid, false);
+                                self.live_allocations.remove_process(pid);
+                        },
+                        Ok(TracerEvent::ProcessFork(pid)) => {
+                                debug!("following child process {}", pid);
+                                self.followed_pids.insert(pid);
+                        },
+                        Ok(TracerEvent::ProcessExec(pid, exec_at)) => {
+                                self.handle_process_exec(pid, exec_at);
+                                self.live_allocations.remove_process(pid);
                         },
                         Err(_) => {}
                     }
@@ -842,13 +1350,70 @@ impl Profiler {
                     },
                 default(Duration::from_millis(100)) => {},
             }
+
+            if triggered {
+                info!("trigger fired, recording profiles");
+                if self.pre_roll.is_zero() {
+                    self.attach_samplers()?;
+                } else {
+                    self.set_sample_freq(recording_sample_freq);
+                }
+                self.discard_samples_before_pre_roll();
+                recording = true;
+                // The profile includes the samples of the pre-roll window, if we
+                // waited for that long.
+                let now = Instant::now();
+                start = now.checked_sub(self.pre_roll).unwrap_or(start).max(start);
+                start_chan_receive = None;
+                total_duration_tick = tick(self.duration);
+            }
         }
 
-        start.elapsed()
+        Ok(start.elapsed())
+    }
+
+    /// Drops the samples collected before the pre-roll window while waiting for a trigger.
+    fn discard_samples_before_pre_roll(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let pre_roll_start = now.saturating_sub(self.pre_roll.as_nanos() as u64);
+        self.raw_samples
+            .retain(|sample| sample.collected_at >= pre_roll_start);
     }
 
     pub fn handle_process_exit(&mut self, pid: Pid, partial_write: bool) {
         // TODO: remove ratelimits for this process.
+        // The BPF side stops following it on its own.
+        self.followed_pids.remove(&pid);
+        self.mark_process_as_exited(pid, partial_write);
+    }
+
+    /// The address space of a process that execs is replaced, so its mappings and
+    /// their unwind information are dropped, and read again as if it was a new process.
+    pub fn handle_process_exec(&mut self, pid: Pid, exec_at: u64) {
+        debug!("process {} called exec, resetting its state", pid);
+        self.mark_process_as_exited(pid, false);
+        let previous = self.procs.write().remove(&pid);
+        self.event_new_proc(pid);
+
+        // The samples taken before the exec are symbolized with the mappings of the
+        // previous executable, which are kept as an older generation.
+        let Some(previous) = previous else {
+            return;
+        };
+        let mut procs = self.procs.write();
+        match procs.get_mut(&pid) {
+            Some(proc_info) => proc_info.push_previous_generation(previous, exec_at),
+            // The new executable isn't profiled, or the process is already gone.
+            None => {
+                procs.insert(pid, previous);
+            }
+        }
+    }
+
+    fn mark_process_as_exited(&mut self, pid: Pid, partial_write: bool) {
         let mut procs = self.procs.write();
         match procs.get_mut(&pid) {
             Some(proc_info) => {
@@ -923,6 +1488,120 @@ impl Profiler {
         }
     }
 
+    /// Called when a known process makes a region executable, either mapping it, such as
+    /// when it loads a library with `dlopen`, or with `mprotect`. Only this mapping is
+    /// added, rather than reading all of the mappings of the process again. The file
+    /// offset of the region, and whether it's anonymous, are only known when it's mapped.
+    pub fn handle_mmap(
+        &mut self,
+        pid: Pid,
+        start_address: u64,
+        length: u64,
+        offset: Option<u64>,
+        anonymous: bool,
+    ) {
+        let end_address = start_address + roundup_page(length as usize) as u64;
+        let region = |pathname| procfs::process::MemoryMap {
+            address: (start_address, end_address),
+            perms: procfs::process::MMPermissions::READ | procfs::process::MMPermissions::EXECUTE,
+            offset: offset.unwrap_or_default(),
+            dev: (0, 0),
+            inode: 0,
+            pathname,
+            extension: Default::default(),
+        };
+        let map = if anonymous {
+            region(procfs::process::MMapPath::Anonymous)
+        } else if let Some(Ok(path)) = offset.map(|_| {
+            // Only file backed mappings have an entry here, named after their address range.
+            fs::read_link(format!(
+                "/proc/{pid}/map_files/{start_address:x}-{end_address:x}"
+            ))
+        }) {
+            region(procfs::process::MMapPath::Path(path))
+        } else {
+            // The file offset isn't known, or the mapping was merged with a neighbouring
+            // one, so its range doesn't name it.
+            let Ok(maps) = procfs::process::Process::new(pid).and_then(|p| p.maps()) else {
+                debug!(
+                    "could not read the mappings of pid {} while handling mmap",
+                    pid
+                );
+                return;
+            };
+            let Some(map) = maps
+                .into_iter()
+                .find(|map| (map.address.0..map.address.1).contains(&start_address))
+            else {
+                debug!(
+                    "could not find memory mapping starting at {:x} for pid {} while handling mmap",
+                    start_address, pid
+                );
+                return;
+            };
+            map
+        };
+
+        // Mappings can be replaced without being unmapped first, with `MAP_FIXED`.
+        let replaced_mappings = match self.procs.read().get(&pid) {
+            Some(proc_info) => proc_info
+                .mappings
+                .0
+                .iter()
+                .filter(|mapping| {
+                    !mapping.soft_delete
+                        && mapping.start_addr < map.address.1
+                        && map.address.0 < mapping.end_addr
+                })
+                .map(|mapping| mapping.start_addr)
+                .collect::<Vec<_>>(),
+            None => {
+                debug!("could not find pid {} while handling mmap", pid);
+                return;
+            }
+        };
+        for mapping_start_address in replaced_mappings {
+            self.handle_munmap(pid, mapping_start_address);
+        }
+
+        let Some(mapping) = self.executable_mapping(pid, &map) else {
+            return;
+        };
+        debug!(
+            "adding memory mapping {:x}-{:x} for pid {}",
+            mapping.start_addr, mapping.end_addr, pid
+        );
+
+        match self.bpf_mapping(&mapping) {
+            Ok(Some(bpf_mapping)) => {
+                if let Err(e) =
+                    Self::add_bpf_mappings(&self.native_unwinder, pid, &vec![bpf_mapping])
+                {
+                    debug!("failed to add BPF mapping due to {:?}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                debug!(
+                    "error adding unwind information for executable 0x{} due to {:?}",
+                    mapping.executable_id, e
+                );
+            }
+        }
+
+        if let Some(proc_info) = self.procs.write().get_mut(&pid) {
+            // Forget the unmapped mappings this one replaces, so it's found when
+            // symbolizing its addresses.
+            proc_info.mappings.0.retain(|known| {
+                !(known.soft_delete
+                    && known.start_addr < mapping.end_addr
+                    && mapping.start_addr < known.end_addr)
+            });
+            proc_info.mappings.0.push(mapping);
+            proc_info.mappings.0.sort_by_key(|known| known.start_addr);
+        }
+    }
+
     /// Clears a BPF map in a iterator-stable way.
     pub fn clear_map(&self, name: &str) {
         let map = self
@@ -965,6 +1644,7 @@ impl Profiler {
 
         for aggregated_sample in raw_aggregated_samples {
             let pid = aggregated_sample.sample.pid;
+            let generation = aggregated_sample.sample.generation;
             let ustack = &aggregated_sample.sample.ustack;
             {
                 let mut procs = self.procs.write();
@@ -978,7 +1658,7 @@ impl Profiler {
                 let procs = self.procs.read();
                 let proc = procs.get(&pid);
                 let Some(proc) = proc else { continue };
-                let mapping = proc.mappings.for_address(virtual_address);
+                let mapping = proc.mappings_for(generation).for_address(virtual_address);
                 if let Some(mapping) = mapping {
                     if let Some(executable) = self
                         .native_unwind_state
@@ -1004,41 +1684,46 @@ impl Profiler {
         last_used_executable_ids
     }
 
+    /// Aggregate the per CPU values of the BPF unwinder statistics.
+    fn unwinder_stats(&self) -> unwinder_stats_t {
+        self.native_unwinder
+            .maps
+            .percpu_stats
+            .keys()
+            .flat_map(|key| {
+                self.native_unwinder
+                    .maps
+                    .percpu_stats
+                    .lookup_percpu(&key, MapFlags::ANY)
+                    .expect("failed to lookup stats value")
+                    .expect("empty stats")
+            })
+            .map(|value| {
+                let stats: unwinder_stats_t =
+                    *plain::from_bytes(&value).expect("failed serde of bpf stats");
+                stats
+            })
+            .fold(unwinder_stats_t::default(), |a, b| a + b)
+    }
+
     /// Collect the BPF unwinder statistics and aggregate the per CPU values.
     pub fn collect_unwinder_stats(&self) {
-        for key in self.native_unwinder.maps.percpu_stats.keys() {
-            let per_cpu_value = self
-                .native_unwinder
-                .maps
-                .percpu_stats
-                .lookup_percpu(&key, MapFlags::ANY)
-                .expect("failed to lookup stats value")
-                .expect("empty stats");
-
-            let total_value = per_cpu_value
-                .iter()
-                .map(|value| {
-                    let stats: unwinder_stats_t =
-                        *plain::from_bytes(value).expect("failed serde of bpf stats");
-                    stats
-                })
-                .fold(unwinder_stats_t::default(), |a, b| a + b);
-
-            let mut raise_log_level = false;
-            if total_value.total != 0 {
-                let success_pct =
-                    100.0 * total_value.success_dwarf as f64 / total_value.total as f64;
-                info!("stacks successfully unwound: {:.2}%", success_pct);
-                if success_pct < 75.0 {
-                    raise_log_level = true;
-                }
-            }
-            if raise_log_level {
-                warn!("unwinder stats: {:?}", total_value);
-            } else {
-                debug!("unwinder stats: {:?}", total_value);
+        let total_value = self.unwinder_stats();
+
+        let mut raise_log_level = false;
+        if total_value.total != 0 {
+            let success = total_value.success_dwarf + total_value.success_frame_pointer;
+            let success_pct = 100.0 * success as f64 / total_value.total as f64;
+            info!("stacks successfully unwound: {:.2}%", success_pct);
+            if success_pct < 75.0 {
+                raise_log_level = true;
             }
         }
+        if raise_log_level {
+            warn!("unwinder stats: {:?}", total_value);
+        } else {
+            debug!("unwinder stats: {:?}", total_value);
+        }
     }
 
     pub fn clear_stats_map(&self) {
@@ -1071,8 +1756,22 @@ impl Profiler {
 
     pub fn collect_profile(&mut self) -> RawAggregatedProfile {
         debug!("collecting profile");
-        let result = self.aggregator.aggregate(self.raw_samples.clone());
-        self.raw_samples.clear();
+        let mut raw_samples = std::mem::take(&mut self.raw_samples);
+        // Samples can be received after the exec of their process is handled, so the
+        // executable they belong to is picked by when they were collected.
+        {
+            let procs = self.procs.read();
+            for raw_sample in &mut raw_samples {
+                raw_sample.generation = procs.get(&raw_sample.pid).map_or(0, |proc_info| {
+                    proc_info.generation_at(raw_sample.collected_at)
+                });
+            }
+        }
+        if self.mode == ProfilerMode::Heap {
+            raw_samples = self.live_allocations.update(raw_samples);
+            raw_samples.extend(self.live_allocations.in_use());
+        }
+        let result = self.aggregator.aggregate(raw_samples);
 
         self.bump_last_used(&result);
         self.collect_unwinder_stats();
@@ -1105,6 +1804,24 @@ impl Profiler {
         Ok(())
     }
 
+    /// Writes the given CFA expressions. Their ids stay valid until every executable
+    /// using them is evicted.
+    fn add_bpf_cfa_expressions(&mut self, ids: &[u16]) -> Result<(), libbpf_rs::Error> {
+        let cfa_expressions = CFA_EXPRESSIONS.lock().unwrap();
+        for id in ids {
+            let expression: cfa_expression_t = cfa_expressions
+                .get(*id)
+                .expect("expressions in use are never removed")
+                .into();
+            self.native_unwinder.maps.cfa_expressions.update(
+                &u32::from(*id).to_ne_bytes(),
+                unsafe { plain::as_bytes(&expression) },
+                MapFlags::ANY,
+            )?;
+        }
+        Ok(())
+    }
+
     fn add_bpf_pages(
         bpf: &ProfilerSkel,
         unwind_info: &[CompactUnwindRow],
@@ -1306,6 +2023,10 @@ impl Profiler {
 
         // The object file (`object_files`) is not removed here as we still need it for
         // normalization before sending the profiles.
+        CFA_EXPRESSIONS
+            .lock()
+            .unwrap()
+            .release(&entry.get().cfa_expression_ids);
         entry.remove_entry();
     }
 
@@ -1373,60 +2094,18 @@ impl Profiler {
             .0
             .iter()
         {
-            // There is no unwind information for anonymous (JIT) mappings, so let's skip them.
-            // In the future we could either try to synthetise the unwind information.
-            if mapping.kind == ExecutableMappingType::Anonymous {
-                bpf_mappings.push(mapping_t {
-                    load_address: 0,
-                    begin: mapping.start_addr,
-                    end: mapping.end_addr,
-                    executable_id: 0,
-                    type_: MAPPING_TYPE_ANON,
-                });
-                continue;
-            }
-
-            if mapping.build_id.is_none() {
-                panic!("build id should be present for file backed mappings");
-            }
-
-            let object_file = self.object_files.read();
-            // We might know about a mapping that failed to open for some reason.
-            let object_file_info = object_file.get(&mapping.executable_id);
-            if object_file_info.is_none() {
-                warn!("mapping not found");
-                continue;
-            }
-            std::mem::drop(object_file);
-
-            // Add mapping.
-            bpf_mappings.push(mapping_t {
-                load_address: mapping.load_address,
-                begin: mapping.start_addr,
-                end: mapping.end_addr,
-                executable_id: mapping.executable_id.into(),
-                type_: if mapping.kind == ExecutableMappingType::Vdso {
-                    MAPPING_TYPE_VDSO
-                } else {
-                    MAPPING_TYPE_FILE
-                },
-            });
-
-            // Fetch unwind info and store it in in BPF maps.
-            if let Err(e) = self.add_unwind_information_for_executable(
-                mapping.executable_id,
-                mapping.start_addr,
-                mapping.end_addr,
-            ) {
-                if e == AddUnwindInformationError::NoUnwindInfoKnownNaughty {
+            match self.bpf_mapping(mapping) {
+                Ok(Some(bpf_mapping)) => bpf_mappings.push(bpf_mapping),
+                Ok(None) => {}
+                Err(AddUnwindInformationError::NoUnwindInfoKnownNaughty) => return,
+                Err(e) => {
+                    warn!(
+                        "error adding unwind information for executable 0x{} due to {:?}",
+                        mapping.executable_id, e
+                    );
+                    // TODO: cleanup unwind information map in case of a partial write.
                     return;
                 }
-                warn!(
-                    "error adding unwind information for executable 0x{} due to {:?}",
-                    mapping.executable_id, e
-                );
-                // TODO: cleanup unwind information map in case of a partial write.
-                return;
             }
         }
 
@@ -1450,11 +2129,62 @@ impl Profiler {
         }
     }
 
-    /// Returns the approximate size in megabytes of _n_ rows of unwind information
-    /// in a BPF map.
-    fn unwind_info_size_mb(unwind_info_len: usize) -> u32 {
-        let overhead = 1.02; // Account for internal overhead of the BPF maps
-        ((unwind_info_len * 8 * 8) as f64 * overhead / 1e+6) as u32
+    /// Stores the unwind information of a mapping in the BPF maps, if needed, and returns
+    /// the mapping to add to the BPF maps. Mappings whose object file isn't known are skipped.
+    fn bpf_mapping(
+        &mut self,
+        mapping: &ExecutableMapping,
+    ) -> Result<Option<mapping_t>, AddUnwindInformationError> {
+        // There is no unwind information for anonymous (JIT) mappings, so let's skip them.
+        // In the future we could either try to synthetise the unwind information.
+        if mapping.kind == ExecutableMappingType::Anonymous {
+            return Ok(Some(mapping_t {
+                load_address: 0,
+                begin: mapping.start_addr,
+                end: mapping.end_addr,
+                executable_id: 0,
+                type_: MAPPING_TYPE_ANON,
+            }));
+        }
+
+        if mapping.build_id.is_none() {
+            panic!("build id should be present for file backed mappings");
+        }
+
+        let object_file = self.object_files.read();
+        // We might know about a mapping that failed to open for some reason.
+        let object_file_info = object_file.get(&mapping.executable_id);
+        if object_file_info.is_none() {
+            warn!("mapping not found");
+            return Ok(None);
+        }
+        std::mem::drop(object_file);
+
+        // Fetch unwind info and store it in in BPF maps.
+        self.add_unwind_information_for_executable(
+            mapping.executable_id,
+            mapping.start_addr,
+            mapping.end_addr,
+        )?;
+
+        Ok(Some(mapping_t {
+            load_address: mapping.load_address,
+            begin: mapping.start_addr,
+            end: mapping.end_addr,
+            executable_id: mapping.executable_id.into(),
+            type_: if mapping.kind == ExecutableMappingType::Vdso {
+                MAPPING_TYPE_VDSO
+            } else {
+                MAPPING_TYPE_FILE
+            },
+        }))
+    }
+
+    /// Returns the approximate size in megabytes of _n_ rows of unwind information
+    /// in a BPF map.
+    fn unwind_info_size_mb(unwind_info_len: usize) -> u32 {
+        let overhead = 1.02; // Account for internal overhead of the BPF maps
+        ((unwind_info_len * 8 * 8) as f64 * overhead / 1e+6) as u32
     }
 
     fn add_unwind_information_for_executable(
@@ -1555,18 +2285,35 @@ impl Profiler {
             }
         };
 
+        // Hold on to the expressions the rows use, so evicting other executables that
+        // use them too doesn't free their ids.
+        let cfa_expression_ids: Vec<u16> = unwind_info
+            .iter()
+            .filter(|row| row.cfa_type == CfaType::Expression)
+            .map(|row| row.cfa_offset)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        CFA_EXPRESSIONS.lock().unwrap().acquire(&cfa_expression_ids);
+        let release_cfa_expressions = |e: AddUnwindInformationError| {
+            CFA_EXPRESSIONS.lock().unwrap().release(&cfa_expression_ids);
+            e
+        };
+
         if !self.maybe_evict_executables(unwind_info.len(), self.max_native_unwind_info_size_mb) {
-            return Err(AddUnwindInformationError::Eviction);
+            return Err(release_cfa_expressions(AddUnwindInformationError::Eviction));
         }
 
         if unwind_info.is_empty() {
-            return Err(AddUnwindInformationError::Empty);
+            return Err(release_cfa_expressions(AddUnwindInformationError::Empty));
         }
 
         if unwind_info.len() > MAX_UNWIND_INFO_SIZE {
-            return Err(AddUnwindInformationError::TooLarge(
-                executable_path.to_string_lossy().to_string(),
-                unwind_info.len(),
+            return Err(release_cfa_expressions(
+                AddUnwindInformationError::TooLarge(
+                    executable_path.to_string_lossy().to_string(),
+                    unwind_info.len(),
+                ),
             ));
         }
 
@@ -1576,11 +2323,17 @@ impl Profiler {
             unwind_info.len(),
         );
 
-        // Add all unwind information and its pages.
-        Self::add_bpf_unwind_info(&inner_map, &unwind_info)
-            .map_err(|e| AddUnwindInformationError::BpfUnwindInfo(e.to_string()))?;
-        Self::add_bpf_pages(&self.native_unwinder, &unwind_info, executable_id.into())
-            .map_err(|e| AddUnwindInformationError::BpfPages(e.to_string()))?;
+        // Add the expressions the rows refer to, all unwind information and its pages.
+        self.add_bpf_cfa_expressions(&cfa_expression_ids)
+            .map_err(|e| {
+                release_cfa_expressions(AddUnwindInformationError::BpfCfaExpressions(e.to_string()))
+            })?;
+        Self::add_bpf_unwind_info(&inner_map, &unwind_info).map_err(|e| {
+            release_cfa_expressions(AddUnwindInformationError::BpfUnwindInfo(e.to_string()))
+        })?;
+        Self::add_bpf_pages(&self.native_unwinder, &unwind_info, executable_id.into()).map_err(
+            |e| release_cfa_expressions(AddUnwindInformationError::BpfPages(e.to_string())),
+        )?;
         let unwind_info_start_address = unwind_info.first().unwrap().pc;
         let unwind_info_end_address = unwind_info.last().unwrap().pc;
         self.native_unwind_state.known_executables.insert(
@@ -1590,6 +2343,7 @@ impl Profiler {
                 unwind_info_start_address,
                 unwind_info_end_address,
                 last_used: Instant::now(),
+                cfa_expression_ids,
             },
         );
         Ok(AddUnwindInformationResult::Success)
@@ -1674,6 +2428,10 @@ impl Profiler {
                 if ret.is_err() {
                     error!("failed to evict unwind info map with {:?}", ret);
                 }
+                CFA_EXPRESSIONS
+                    .lock()
+                    .unwrap()
+                    .release(&entry.get().cfa_expression_ids);
                 entry.remove_entry();
             }
 
@@ -1683,20 +2441,110 @@ impl Profiler {
         true
     }
 
-    fn should_profile(&self, pid: Pid) -> bool {
+    fn should_profile(&mut self, pid: Pid) -> bool {
         if self.exclude_self && pid == std::process::id() as i32 {
             return false;
         }
 
-        if self.filter_pids.is_empty() {
+        if self.filter_pids.is_empty() && self.process_matcher.is_empty() {
+            return true;
+        }
+
+        if self.filter_pids.contains_key(&pid) || self.followed_pids.contains(&pid) {
+            return true;
+        }
+
+        // The tracers follow the children right away, so they might not have
+        // been reported yet.
+        if self.follow_children && self.is_followed(pid) {
+            debug!("following child process {}", pid);
+            self.followed_pids.insert(pid);
+            return true;
+        }
+
+        if self.process_matcher.matches(pid) {
+            if self.follow_children {
+                self.follow_children_of(pid);
+                self.follow_existing_descendants(vec![pid]);
+            }
             return true;
         }
 
-        self.filter_pids.contains_key(&pid)
+        false
+    }
+
+    /// Stops the BPF programs from reporting this process until it execs or exits.
+    fn deny_process(&self, pid: Pid) {
+        let denied = [1];
+        if let Err(e) = self.native_unwinder.maps.denied_processes.update(
+            &pid.to_ne_bytes(),
+            &denied,
+            MapFlags::ANY,
+        ) {
+            debug!("could not deny process {}: {:?}", pid, e);
+        }
+    }
+
+    /// Reports the processes forked by this process from now on, and their own children.
+    fn follow_children_of(&self, pid: Pid) {
+        let followed = [1];
+        if let Err(e) = self.tracers.maps.followed_processes.update(
+            &pid.to_ne_bytes(),
+            &followed,
+            MapFlags::ANY,
+        ) {
+            warn!("could not follow the children of process {}: {:?}", pid, e);
+        }
+    }
+
+    /// Whether the tracers saw a followed process fork this one.
+    fn is_followed(&self, pid: Pid) -> bool {
+        matches!(
+            self.tracers
+                .maps
+                .followed_processes
+                .lookup(&pid.to_ne_bytes(), MapFlags::ANY),
+            Ok(Some(_))
+        )
+    }
+
+    /// Follows the descendants these processes forked before they were followed,
+    /// which the tracers didn't see. Processes whose parent exited by then have
+    /// been reparented, so they can't be found.
+    fn follow_existing_descendants(&mut self, pids: Vec<Pid>) {
+        let all_processes = match procfs::process::all_processes() {
+            Ok(all_processes) => all_processes,
+            Err(e) => {
+                warn!("could not list the existing processes: {:?}", e);
+                return;
+            }
+        };
+        let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+        for stat in all_processes.flatten().filter_map(|p| p.stat().ok()) {
+            children.entry(stat.ppid).or_default().push(stat.pid);
+        }
+
+        let mut pending = pids;
+        while let Some(pid) = pending.pop() {
+            for child in children.remove(&pid).unwrap_or_default() {
+                if self.followed_pids.insert(child) {
+                    debug!("following existing child process {}", child);
+                    self.follow_children_of(child);
+                    // It might have been seen before its parent matched.
+                    let _ = self
+                        .native_unwinder
+                        .maps
+                        .denied_processes
+                        .delete(&child.to_ne_bytes());
+                    pending.push(child);
+                }
+            }
+        }
     }
 
     fn event_new_proc(&mut self, pid: Pid) {
         if !self.should_profile(pid) {
+            self.deny_process(pid);
             return;
         }
 
@@ -1784,208 +2632,220 @@ impl Profiler {
         true
     }
 
-    pub fn add_proc(&mut self, pid: Pid) -> Result<(), AddProcessError> {
-        let proc = procfs::process::Process::new(pid).map_err(|_| AddProcessError::ProcfsRace)?;
-        let maps = proc.maps().map_err(|_| AddProcessError::ProcfsRace)?;
-        if !self.maybe_evict_process(true) {
-            return Err(AddProcessError::Eviction);
+    /// Reads the information of an executable mapping of a process, registering its
+    /// object file. Returns `None` for mappings that aren't executable or can't be read.
+    fn executable_mapping(
+        &self,
+        pid: Pid,
+        map: &procfs::process::MemoryMap,
+    ) -> Option<ExecutableMapping> {
+        if !map.perms.contains(procfs::process::MMPermissions::EXECUTE) {
+            return None;
         }
+        match &map.pathname {
+            procfs::process::MMapPath::Path(path) => {
+                let Ok(exe_path) = executable_path(pid, path) else {
+                    // Can fail due to race-conditions
+                    return None;
+                };
+
+                // We've seen debug info executables that get deleted in Rust applications.
+                if exe_path.to_string_lossy().contains("(deleted)") {
+                    return None;
+                }
 
-        let mut mappings = vec![];
-        let object_files_clone = self.object_files.clone();
-
-        for map in maps.iter() {
-            if !map.perms.contains(procfs::process::MMPermissions::EXECUTE) {
-                continue;
-            }
-            match &map.pathname {
-                procfs::process::MMapPath::Path(path) => {
-                    let Ok(exe_path) = executable_path(pid, path) else {
-                        // Can fail due to race-conditions
-                        continue;
-                    };
+                // There are probably other cases, but we'll handle them as we bump into them.
+                if exe_path.to_string_lossy().contains("(") {
+                    warn!(
+                        "absolute path ({}) contains '(', it might be special",
+                        exe_path.display()
+                    );
+                }
 
-                    // We've seen debug info executables that get deleted in Rust applications.
-                    if exe_path.to_string_lossy().contains("(deleted)") {
-                        continue;
+                // We want to open the file as quickly as possible to minimise the chances of races
+                // if the file is deleted.
+                let file = match File::open(&exe_path) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        debug!("failed to open file {} due to {:?}", exe_path.display(), e);
+                        // Rather than returning here, we prefer to be able to profile some
+                        // parts of the binary
+                        return None;
                     }
+                };
 
-                    // There are probably other cases, but we'll handle them as we bump into them.
-                    if exe_path.to_string_lossy().contains("(") {
-                        warn!(
-                            "absolute path ({}) contains '(', it might be special",
-                            exe_path.display()
-                        );
+                let object_file = match ObjectFile::new(&file) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        debug!("object_file {} failed with {}", exe_path.display(), e);
+                        // Rather than returning here, we prefer to be able to profile some
+                        // parts of the binary
+                        return None;
                     }
-
-                    // We want to open the file as quickly as possible to minimise the chances of races
-                    // if the file is deleted.
-                    let file = match File::open(&exe_path) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            debug!("failed to open file {} due to {:?}", exe_path.display(), e);
-                            // Rather than returning here, we prefer to be able to profile some
-                            // parts of the binary
-                            continue;
-                        }
+                };
+
+                let build_id = object_file.build_id();
+                let Ok(executable_id) = object_file.id() else {
+                    info!("could not get id for object file: {}", exe_path.display());
+                    return None;
+                };
+
+                debug!("Path {:?} executable_id 0x{}", path, executable_id);
+
+                // mmap'ed data is always page aligned but the load segment information might not be.
+                // As we need to account for any randomisation added by ASLR, by substracting the virtual
+                // address from the first load segment once it's been page aligned we'll get the offset
+                // at which the executable has been loaded.
+                //
+                // Note: this doesn't take into consideration the mmap'ed or load offsets.
+                let load_address = |map_start: u64, first_elf_load: &ElfLoad| {
+                    let page_mask = !(page_size() - 1) as u64;
+                    map_start.saturating_sub(first_elf_load.p_vaddr & page_mask)
+                };
+
+                let mut object_files = self.object_files.write();
+                let Ok(elf_loads) = object_file.elf_load_segments() else {
+                    warn!("no elf load segments");
+                    return None;
+                };
+
+                let Some(first_elf_load) = elf_loads.first() else {
+                    warn!("empty elf load segments");
+                    return None;
+                };
+
+                let mapping = ExecutableMapping {
+                    executable_id,
+                    build_id: Some(build_id.clone()),
+                    kind: ExecutableMappingType::FileBacked,
+                    start_addr: map.address.0,
+                    end_addr: map.address.1,
+                    offset: map.offset,
+                    load_address: load_address(map.address.0, first_elf_load),
+                    soft_delete: false,
+                };
+
+                // If the object file has debug info, add it to our store.
+                if object_file.has_debug_info() {
+                    let name = match exe_path.file_name() {
+                        Some(os_name) => os_name.to_string_lossy().to_string(),
+                        None => "error".to_string(),
                     };
-
-                    let object_file = match ObjectFile::new(&file) {
-                        Ok(f) => f,
+                    let res = self
+                        .debug_info_manager
+                        .add_if_not_present(&name, build_id, &exe_path);
+                    match res {
+                        Ok(_) => {
+                            debug!("debuginfo add_if_not_present succeded {:?}", res);
+                        }
                         Err(e) => {
-                            debug!("object_file {} failed with {}", exe_path.display(), e);
-                            // Rather than returning here, we prefer to be able to profile some
-                            // parts of the binary
-                            continue;
+                            error!(
+                                "debuginfo add_if_not_present failed with: {}",
+                                e.root_cause()
+                            );
                         }
-                    };
-
-                    let build_id = object_file.build_id();
-                    let Ok(executable_id) = object_file.id() else {
-                        info!("could not get id for object file: {}", exe_path.display());
-                        continue;
-                    };
+                    }
+                } else {
+                    debug!(
+                        "could not find debug information for {}",
+                        exe_path.display()
+                    );
+                }
 
-                    debug!("Path {:?} executable_id 0x{}", path, executable_id);
-
-                    // mmap'ed data is always page aligned but the load segment information might not be.
-                    // As we need to account for any randomisation added by ASLR, by substracting the virtual
-                    // address from the first load segment once it's been page aligned we'll get the offset
-                    // at which the executable has been loaded.
-                    //
-                    // Note: this doesn't take into consideration the mmap'ed or load offsets.
-                    let load_address = |map_start: u64, first_elf_load: &ElfLoad| {
-                        let page_mask = !(page_size() - 1) as u64;
-                        map_start.saturating_sub(first_elf_load.p_vaddr & page_mask)
-                    };
+                match object_files.entry(executable_id) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(ObjectFileInfo {
+                            path: exe_path,
+                            elf_load_segments: elf_loads,
+                            is_dyn: object_file.is_dynamic(),
+                            references: 1,
+                            native_unwind_info_size: None,
+                            is_vdso: false,
+                            runtime: object_file.runtime(),
+                        });
+                    }
+                    Entry::Occupied(mut entry) => {
+                        entry.get_mut().references += 1;
+                    }
+                }
 
-                    let mut object_files = object_files_clone.write();
-                    let Ok(elf_loads) = object_file.elf_load_segments() else {
-                        warn!("no elf load segments");
-                        continue;
+                Some(mapping)
+            }
+            procfs::process::MMapPath::Anonymous => Some(ExecutableMapping {
+                executable_id: ExecutableId(0), // Placeholder for JIT.
+                build_id: None,
+                kind: ExecutableMappingType::Anonymous,
+                start_addr: map.address.0,
+                end_addr: map.address.1,
+                offset: map.offset,
+                load_address: 0,
+                soft_delete: false,
+            }),
+            procfs::process::MMapPath::Vdso | procfs::process::MMapPath::Vsyscall => {
+                // This could be cached, but we are not doing it yet. If we want to add caching here we need to
+                // be careful, the kernel might be upgraded since last time we ran, and that cache might not be
+                // valid anymore.
+
+                if let Ok((vdso_path, object_file)) = fetch_vdso_info(
+                    pid,
+                    map.address.0,
+                    map.address.1,
+                    map.offset,
+                    &self.cache_dir,
+                ) {
+                    let mut object_files = self.object_files.write();
+                    let Ok(executable_id) = object_file.id() else {
+                        debug!("vDSO object file id failed");
+                        return None;
                     };
-
-                    let Some(first_elf_load) = elf_loads.first() else {
-                        warn!("empty elf load segments");
-                        continue;
+                    let Ok(elf_load_segments) = object_file.elf_load_segments() else {
+                        debug!("vDSO elf_load_segments failed");
+                        return None;
                     };
+                    let build_id = object_file.build_id().clone();
 
-                    mappings.push(ExecutableMapping {
+                    object_files.insert(
                         executable_id,
-                        build_id: Some(build_id.clone()),
-                        kind: ExecutableMappingType::FileBacked,
-                        start_addr: map.address.0,
-                        end_addr: map.address.1,
-                        offset: map.offset,
-                        load_address: load_address(map.address.0, first_elf_load),
-                        soft_delete: false,
-                    });
-
-                    // If the object file has debug info, add it to our store.
-                    if object_file.has_debug_info() {
-                        let name = match exe_path.file_name() {
-                            Some(os_name) => os_name.to_string_lossy().to_string(),
-                            None => "error".to_string(),
-                        };
-                        let res = self
-                            .debug_info_manager
-                            .add_if_not_present(&name, build_id, &exe_path);
-                        match res {
-                            Ok(_) => {
-                                debug!("debuginfo add_if_not_present succeded {:?}", res);
-                            }
-                            Err(e) => {
-                                error!(
-                                    "debuginfo add_if_not_present failed with: {}",
-                                    e.root_cause()
-                                );
-                            }
-                        }
-                    } else {
-                        debug!(
-                            "could not find debug information for {}",
-                            exe_path.display()
-                        );
-                    }
-
-                    match object_files.entry(executable_id) {
-                        Entry::Vacant(entry) => {
-                            entry.insert(ObjectFileInfo {
-                                path: exe_path,
-                                elf_load_segments: elf_loads,
-                                is_dyn: object_file.is_dynamic(),
-                                references: 1,
-                                native_unwind_info_size: None,
-                                is_vdso: false,
-                                runtime: object_file.runtime(),
-                            });
-                        }
-                        Entry::Occupied(mut entry) => {
-                            entry.get_mut().references += 1;
-                        }
-                    }
-                }
-                procfs::process::MMapPath::Anonymous => {
-                    mappings.push(ExecutableMapping {
-                        executable_id: ExecutableId(0), // Placeholder for JIT.
-                        build_id: None,
-                        kind: ExecutableMappingType::Anonymous,
+                        ObjectFileInfo {
+                            path: vdso_path.clone(),
+                            elf_load_segments,
+                            is_dyn: object_file.is_dynamic(),
+                            references: 1,
+                            native_unwind_info_size: None,
+                            is_vdso: true,
+                            runtime: Runtime::CLike,
+                        },
+                    );
+                    Some(ExecutableMapping {
+                        executable_id,
+                        build_id: Some(build_id),
+                        kind: ExecutableMappingType::Vdso,
                         start_addr: map.address.0,
                         end_addr: map.address.1,
                         offset: map.offset,
-                        load_address: 0,
+                        load_address: map.address.0,
                         soft_delete: false,
-                    });
+                    })
+                } else {
+                    None
                 }
-                procfs::process::MMapPath::Vdso | procfs::process::MMapPath::Vsyscall => {
-                    // This could be cached, but we are not doing it yet. If we want to add caching here we need to
-                    // be careful, the kernel might be upgraded since last time we ran, and that cache might not be
-                    // valid anymore.
+            }
+            // Skip every other mapping we don't care about: Heap, Stack, Vsys, Vvar, etc
+            _ => None,
+        }
+    }
 
-                    if let Ok((vdso_path, object_file)) = fetch_vdso_info(
-                        pid,
-                        map.address.0,
-                        map.address.1,
-                        map.offset,
-                        &self.cache_dir,
-                    ) {
-                        let mut object_files = object_files_clone.write();
-                        let Ok(executable_id) = object_file.id() else {
-                            debug!("vDSO object file id failed");
-                            continue;
-                        };
-                        let Ok(elf_load_segments) = object_file.elf_load_segments() else {
-                            debug!("vDSO elf_load_segments failed");
-                            continue;
-                        };
-                        let build_id = object_file.build_id().clone();
-
-                        object_files.insert(
-                            executable_id,
-                            ObjectFileInfo {
-                                path: vdso_path.clone(),
-                                elf_load_segments,
-                                is_dyn: object_file.is_dynamic(),
-                                references: 1,
-                                native_unwind_info_size: None,
-                                is_vdso: true,
-                                runtime: Runtime::CLike,
-                            },
-                        );
-                        mappings.push(ExecutableMapping {
-                            executable_id,
-                            build_id: Some(build_id),
-                            kind: ExecutableMappingType::Vdso,
-                            start_addr: map.address.0,
-                            end_addr: map.address.1,
-                            offset: map.offset,
-                            load_address: map.address.0,
-                            soft_delete: false,
-                        });
-                    }
-                }
-                // Skip every other mapping we don't care about: Heap, Stack, Vsys, Vvar, etc
-                _ => {}
+    pub fn add_proc(&mut self, pid: Pid) -> Result<(), AddProcessError> {
+        let proc = procfs::process::Process::new(pid).map_err(|_| AddProcessError::ProcfsRace)?;
+        let maps = proc.maps().map_err(|_| AddProcessError::ProcfsRace)?;
+        if !self.maybe_evict_process(true) {
+            return Err(AddProcessError::Eviction);
+        }
+
+        let mut mappings = vec![];
+        for map in maps.iter() {
+            if let Some(mapping) = self.executable_mapping(pid, map) {
+                mappings.push(mapping);
             }
         }
 
@@ -1993,6 +2853,8 @@ impl Profiler {
         let proc_info = ProcessInfo {
             status: ProcessStatus::Running,
             mappings: ExecutableMappings(mappings),
+            generation: 0,
+            previous_mappings: Vec::new(),
             last_used: Instant::now(),
         };
         self.procs.clone().write().insert(pid, proc_info);
@@ -2061,30 +2923,350 @@ impl Profiler {
                 MapFlags::ANY,
             )
             .expect("update map");
+
+        let native_unwinder_tracepoint_prog_fd = self
+            .native_unwinder
+            .progs
+            .dwarf_unwind_tracepoint
+            .as_fd()
+            .as_raw_fd();
+        maps.tracepoint_programs
+            .update(
+                &native_unwinder_prog_id.to_le_bytes(),
+                &native_unwinder_tracepoint_prog_fd.to_le_bytes(),
+                MapFlags::ANY,
+            )
+            .expect("update map");
+
+        let native_unwinder_uprobe_prog_fd = self
+            .native_unwinder
+            .progs
+            .dwarf_unwind_uprobe
+            .as_fd()
+            .as_raw_fd();
+        maps.uprobe_programs
+            .update(
+                &native_unwinder_prog_id.to_le_bytes(),
+                &native_unwinder_uprobe_prog_fd.to_le_bytes(),
+                MapFlags::ANY,
+            )
+            .expect("update map");
+
+        let native_unwinder_kprobe_prog_fd = self
+            .native_unwinder
+            .progs
+            .dwarf_unwind_kprobe
+            .as_fd()
+            .as_raw_fd();
+        maps.kprobe_programs
+            .update(
+                &native_unwinder_prog_id.to_le_bytes(),
+                &native_unwinder_kprobe_prog_fd.to_le_bytes(),
+                MapFlags::ANY,
+            )
+            .expect("update map");
     }
 
-    pub fn setup_perf_events(&mut self) {
+    pub fn setup_perf_events(&mut self) -> Result<(), anyhow::Error> {
         let mut perf_fds = Vec::new();
-        for i in get_online_cpus().expect("get online CPUs") {
-            let perf_fd = unsafe { setup_perf_event(i.try_into().unwrap(), self.sample_freq) }
-                .expect("setup perf event");
-            perf_fds.push(perf_fd);
+        if self.per_process_perf_events && !self.filter_pids.is_empty() {
+            // The children that are followed might have been created before the events
+            // were opened, or have lost their inherited copies when they were reopened.
+            let pids = self
+                .filter_pids
+                .keys()
+                .chain(&self.followed_pids)
+                .copied()
+                .collect::<HashSet<_>>();
+            for pid in pids {
+                let tasks = match procfs::process::Process::new(pid).and_then(|p| p.tasks()) {
+                    Ok(tasks) => tasks,
+                    Err(e) => {
+                        warn!("could not list the threads of process {}: {:?}", pid, e);
+                        continue;
+                    }
+                };
+
+                for task in tasks.flatten() {
+                    match unsafe { setup_thread_perf_event(task.tid, &self.perf_event_config) } {
+                        Ok(perf_fd) => perf_fds.push(perf_fd),
+                        Err(e) => {
+                            // The thread might have exited in the meantime.
+                            debug!(
+                                "could not set up perf event for thread {}: {:?}",
+                                task.tid, e
+                            );
+                        }
+                    }
+                }
+            }
+
+            if perf_fds.is_empty() {
+                return Err(anyhow!(
+                    "could not set up a perf event for any thread of the profiled processes"
+                ));
+            }
+        } else {
+            for i in get_online_cpus().expect("get online CPUs") {
+                let perf_fd =
+                    unsafe { setup_perf_event(i.try_into().unwrap(), &self.perf_event_config) }
+                        .map_err(|e| anyhow!("could not set up perf event on CPU {i}: {e}"))?;
+                perf_fds.push(perf_fd);
+            }
         }
 
+        // The kernel only runs programs of the same type as the event source.
+        let prog_name = match self.perf_event_config.event {
+            PerfEventType::Kprobe(_) => "on_kprobe_event",
+            ref event if event.is_trace_event() => "on_tracepoint_event",
+            _ => "on_event",
+        };
+
+        self.perf_fds.extend(&perf_fds);
         for perf_fd in perf_fds {
             let prog = self
                 .native_unwinder
                 .object_mut()
                 .progs_mut()
-                .find(|prog| prog.name() == "on_event")
+                .find(|prog| prog.name() == prog_name)
                 .expect("get prog");
-            let link = prog.attach_perf_event(perf_fd);
+            let link = prog
+                .attach_perf_event(perf_fd)
+                .map_err(|e| anyhow!("could not attach {prog_name} to perf event: {e}"))?;
+            self.perf_event_links.push(link);
+        }
+
+        Ok(())
+    }
+
+    pub fn setup_sched_switch_tracepoint(&mut self) {
+        let prog = self
+            .native_unwinder
+            .object_mut()
+            .progs_mut()
+            .find(|prog| prog.name() == "on_sched_switch")
+            .expect("get prog");
+        let link = prog.attach();
+        self._links.push(link.expect("bpf link is present"));
+    }
+
+    pub fn setup_futex_tracepoints(&mut self) {
+        for prog_name in ["on_futex_enter", "on_futex_exit"] {
+            let prog = self
+                .native_unwinder
+                .object_mut()
+                .progs_mut()
+                .find(|prog| prog.name() == prog_name)
+                .expect("get prog");
+            let link = prog.attach();
             self._links.push(link.expect("bpf link is present"));
         }
+
+        // `futex_waitv` is only available since Linux 5.16.
+        for prog_name in ["on_futex_waitv_enter", "on_futex_waitv_exit"] {
+            let prog = self
+                .native_unwinder
+                .object_mut()
+                .progs_mut()
+                .find(|prog| prog.name() == prog_name)
+                .expect("get prog");
+            match prog.attach() {
+                Ok(link) => self._links.push(link),
+                Err(e) => {
+                    warn!("could not attach {} due to {:?}", prog_name, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Attaches the allocator probes to every profiled process.
+    pub fn setup_allocator_uprobes(&mut self) {
+        let pids = self.filter_pids.keys().copied().collect::<Vec<_>>();
+        for pid in pids {
+            let Some((allocator_path, allocator)) = find_allocator(pid) else {
+                warn!("could not find the allocator of process {}", pid);
+                continue;
+            };
+            debug!(
+                "profiling allocations of process {} in {}",
+                pid,
+                allocator_path.display()
+            );
+
+            for (function, entry_prog) in [
+                ("malloc", "on_malloc"),
+                ("calloc", "on_calloc"),
+                ("realloc", "on_realloc"),
+                ("free", "on_free"),
+            ] {
+                let Some(offset) = allocator.symbol_file_offset(function) else {
+                    warn!("`{}' not found in {}", function, allocator_path.display());
+                    continue;
+                };
+
+                self.attach_uprobe(
+                    entry_prog,
+                    pid,
+                    &allocator_path,
+                    offset,
+                    UprobeOpts::default(),
+                );
+                if function != "free" {
+                    self.attach_uprobe(
+                        "on_allocator_return",
+                        pid,
+                        &allocator_path,
+                        offset,
+                        UprobeOpts {
+                            retprobe: true,
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Attaches the stack collecting uprobe to every user-defined probe, only in the
+    /// profiled processes if any were given.
+    pub fn setup_user_probes(&mut self) {
+        let pids = if self.filter_pids.is_empty() {
+            // Any process.
+            vec![-1]
+        } else {
+            self.filter_pids.keys().copied().collect::<Vec<_>>()
+        };
+
+        for probe in self.probes.clone() {
+            let resolved_probe = match probe.resolve() {
+                Ok(resolved_probe) => resolved_probe,
+                Err(e) => {
+                    error!("could not resolve probe {} due to {:?}", probe, e);
+                    continue;
+                }
+            };
+            debug!("attaching to {} at {:?}", probe, resolved_probe);
+
+            for pid in &pids {
+                self.attach_uprobe(
+                    "on_user_probe",
+                    *pid,
+                    &resolved_probe.binary,
+                    resolved_probe.file_offset,
+                    UprobeOpts {
+                        ref_ctr_offset: resolved_probe.semaphore_file_offset.unwrap_or(0) as usize,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+    }
+
+    fn attach_uprobe(
+        &mut self,
+        prog_name: &str,
+        pid: Pid,
+        path: &Path,
+        offset: u64,
+        opts: UprobeOpts,
+    ) {
+        let prog = self
+            .native_unwinder
+            .object_mut()
+            .progs_mut()
+            .find(|prog| prog.name() == prog_name)
+            .expect("get prog");
+        match prog.attach_uprobe_with_opts(pid, path, offset as usize, opts) {
+            Ok(link) => self._links.push(link),
+            Err(e) => {
+                warn!(
+                    "attaching {} to process {} failed with {:?}",
+                    prog_name, pid, e
+                );
+            }
+        }
     }
 
     pub fn teardown_perf_events(&mut self) {
         self._links = vec![];
+        self.perf_event_links = vec![];
+        self.perf_fds = vec![];
+    }
+
+    /// CPU time used by the profiler so far, including the run time of the BPF
+    /// programs if BPF stats are enabled.
+    fn cpu_time(&self) -> Result<Duration, procfs::ProcError> {
+        let progs: Vec<_> = self
+            .native_unwinder
+            .object()
+            .progs()
+            .chain(self.tracers.object().progs())
+            .collect();
+        Ok(profiler_cpu_time()? + bpf_run_time(progs.iter().map(|prog| prog.as_fd())))
+    }
+
+    /// Measures the overhead of the last session and changes the frequency of the
+    /// perf events if it's not within the budget.
+    fn adjust_sample_freq(&mut self) {
+        if self.frequency_controller.is_none() {
+            return;
+        }
+
+        let cpu_time = match self.cpu_time() {
+            Ok(cpu_time) => cpu_time,
+            Err(e) => {
+                warn!("could not read the CPU time of the profiler: {:?}", e);
+                return;
+            }
+        };
+        let stats = self.unwinder_stats();
+        // Ring buffers don't report lost samples to userspace, but the unwinder
+        // counts the stacks it failed to send.
+        let lost_samples =
+            self.lost_samples.load(Ordering::Relaxed) + stats.error_failure_sending_stack;
+        let overhead =
+            self.overhead_meter
+                .measure(Instant::now(), cpu_time, stats.total, lost_samples);
+
+        let Some(controller) = &mut self.frequency_controller else {
+            return;
+        };
+        let previous_sample_freq = controller.sample_freq();
+        let sample_freq = controller.update(&overhead);
+        if sample_freq == previous_sample_freq {
+            debug!("keeping the sampling frequency, overhead: {:?}", overhead);
+            return;
+        }
+
+        info!(
+            "changing the sampling frequency from {} Hz to {} Hz, overhead: {:?}",
+            previous_sample_freq, sample_freq, overhead
+        );
+        self.set_sample_freq(sample_freq);
+    }
+
+    /// Changes the frequency of the perf events that are already open.
+    fn set_sample_freq(&mut self, sample_freq: u64) {
+        if sample_freq == self.perf_event_config.sample_freq {
+            return;
+        }
+        self.perf_event_config.sample_freq = sample_freq;
+        if self.per_process_perf_events && !self.filter_pids.is_empty() {
+            // The copies of inherited events in the threads created after they were
+            // opened don't see period changes, so the events are opened again.
+            self.perf_event_links = vec![];
+            self.perf_fds = vec![];
+            if let Err(e) = self.setup_perf_events() {
+                warn!("could not reopen the perf events: {:?}", e);
+            }
+            return;
+        }
+        for perf_fd in &self.perf_fds {
+            if let Err(e) = unsafe { set_sample_freq(*perf_fd, sample_freq) } {
+                warn!("could not change the sampling frequency: {:?}", e);
+            }
+        }
     }
 }
 
@@ -1,10 +1,15 @@
+use std::borrow::Cow;
+use std::ffi::OsStr;
 use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use gimli::{
-    CfaRule, CieOrFde, EhFrame, Encoding, Format,
+    BaseAddresses, CfaRule, CieOrFde, DebugFrame, EhFrame, Encoding, EndianSlice, Expression,
+    Format, Operation,
     Operation::{Deref, PlusConstant, RegisterOffset},
-    UnwindContext, UnwindSection,
+    Register, RunTimeEndian, UnwindContext, UnwindSection,
 };
 use memmap2::Mmap;
 use object::Architecture;
@@ -15,11 +20,12 @@ use tracing::{debug, error, span, Level};
 use crate::unwind_info::optimize::remove_redundant;
 use crate::unwind_info::optimize::remove_unnecesary_markers;
 use crate::unwind_info::types::*;
+use crate::util::split_procfs_root;
 
 #[derive(Debug, Error)]
 pub enum UnwindInfoError {
-    #[error("no eh_frame section found")]
-    NoEhFrameSection,
+    #[error("no .eh_frame or .debug_frame section found")]
+    NoUnwindInfoSection,
     #[error("object file could not be parsed due to {0}")]
     ParsingObjectFile(String),
     #[error("no text section found")]
@@ -28,6 +34,237 @@ pub enum UnwindInfoError {
     NoFunctionsFoundInEhFrameData,
 }
 
+const DEBUG_DIRECTORY: &str = "/usr/lib/debug";
+
+const EXPRESSION_ENCODING: Encoding = Encoding {
+    format: Format::Dwarf64,
+    version: 4,
+    address_size: 8,
+};
+
+/// Converts a DWARF expression to the compact bytecode evaluated by the BPF unwinder,
+/// if all its operations are supported.
+fn compact_expression<R: gimli::Reader>(
+    expression: Expression<R>,
+    frame_pointer: Register,
+    stack_pointer: Register,
+    instruction_pointer: Option<Register>,
+) -> Option<CfaExpression> {
+    let mut ops = Vec::new();
+    let mut operations = expression.operations(EXPRESSION_ENCODING);
+
+    while let Some(operation) = operations.next().ok()? {
+        let (opcode, operand) = match operation {
+            RegisterOffset {
+                register, offset, ..
+            } => {
+                let opcode = if register == stack_pointer {
+                    CfaExpressionOpcode::BregSp
+                } else if register == frame_pointer {
+                    CfaExpressionOpcode::BregFp
+                } else if Some(register) == instruction_pointer {
+                    CfaExpressionOpcode::BregIp
+                } else {
+                    return None;
+                };
+                (opcode, i32::try_from(offset).ok()?)
+            }
+            Deref {
+                size: 8,
+                space: false,
+                ..
+            } => (CfaExpressionOpcode::Deref, 0),
+            PlusConstant { value } => (CfaExpressionOpcode::PlusUconst, i32::try_from(value).ok()?),
+            // Also used for the `DW_OP_lit*` operations.
+            Operation::UnsignedConstant { value } => {
+                (CfaExpressionOpcode::Lit, i32::try_from(value).ok()?)
+            }
+            Operation::And => (CfaExpressionOpcode::And, 0),
+            Operation::Shl => (CfaExpressionOpcode::Shl, 0),
+            _ => return None,
+        };
+        ops.push(CfaExpressionOp { opcode, operand });
+    }
+
+    CfaExpression::new(&ops)
+}
+
+/// Whether the CRC of a file is the one recorded in a `.gnu_debuglink` section.
+fn debuglink_crc_matches(path: &Path, crc: u32) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let Ok(mmap) = (unsafe { memmap2::Mmap::map(&file) }) else {
+        return false;
+    };
+    crc32fast::hash(&mmap[..]) == crc
+}
+
+/// Finds the separate debug file of an executable, either by build id or by the name
+/// in its `.gnu_debuglink` section, looking in the same places GDB does. `path` is
+/// the path of the executable within the mount namespace whose root is `root`.
+fn find_debug_file(root: &Path, path: &Path, object_file: &object::File) -> Option<PathBuf> {
+    let in_root = |path: &Path| match path.strip_prefix("/") {
+        Ok(relative_path) => root.join(relative_path),
+        Err(_) => path.to_path_buf(),
+    };
+    let debug_directory = in_root(Path::new(DEBUG_DIRECTORY));
+
+    if let Ok(Some(build_id)) = object_file.build_id() {
+        if build_id.len() > 1 {
+            let build_id: String = build_id.iter().map(|byte| format!("{byte:02x}")).collect();
+            let debug_file = debug_directory
+                .join(".build-id")
+                .join(&build_id[..2])
+                .join(format!("{}.debug", &build_id[2..]));
+            if debug_file.is_file() {
+                return Some(debug_file);
+            }
+        }
+    }
+
+    let Ok(Some((debuglink, crc))) = object_file.gnu_debuglink() else {
+        return None;
+    };
+    let debuglink = Path::new(OsStr::from_bytes(debuglink));
+    let directory = path.parent()?;
+    let executable = in_root(path);
+    [
+        in_root(directory).join(debuglink),
+        in_root(directory).join(".debug").join(debuglink),
+        debug_directory
+            .join(directory.strip_prefix("/").unwrap_or(directory))
+            .join(debuglink),
+    ]
+    .into_iter()
+    .find(|debug_file| {
+        *debug_file != executable && debug_file.is_file() && debuglink_crc_matches(debug_file, crc)
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FrameSectionKind {
+    EhFrame,
+    DebugFrame,
+}
+
+/// Address and data of the sections with unwind information, in order of preference.
+/// The data might have been decompressed.
+fn frame_sections<'data>(
+    object_file: &object::File<'data>,
+) -> Result<Vec<(FrameSectionKind, u64, Cow<'data, [u8]>)>, object::Error> {
+    [
+        (FrameSectionKind::EhFrame, ".eh_frame"),
+        // Also finds `.zdebug_frame`.
+        (FrameSectionKind::DebugFrame, ".debug_frame"),
+    ]
+    .into_iter()
+    .filter_map(|(kind, name)| {
+        let section = object_file.section_by_name(name)?;
+        // Debug files keep the headers of the sections they don't have the data of.
+        section.file_range()?;
+        Some(
+            section
+                .uncompressed_data()
+                .map(|data| (kind, section.address(), data)),
+        )
+    })
+    .collect()
+}
+
+enum FrameSection<'data> {
+    EhFrame(EhFrame<EndianSlice<'data, RunTimeEndian>>, BaseAddresses),
+    DebugFrame(DebugFrame<EndianSlice<'data, RunTimeEndian>>, BaseAddresses),
+}
+
+/// Returns the start address, end address and offset of the FDEs of a section.
+fn function_ranges<'data, S: UnwindSection<EndianSlice<'data, RunTimeEndian>>>(
+    section: &S,
+    bases: &BaseAddresses,
+) -> Vec<(u64, u64, usize)> {
+    let mut entries_iter = section.entries(bases);
+    let mut cur_cie = None;
+    let mut function_ranges = Vec::new();
+
+    while let Ok(Some(entry)) = entries_iter.next() {
+        match entry {
+            CieOrFde::Cie(cie) => {
+                cur_cie = Some(cie);
+            }
+            CieOrFde::Fde(partial_fde) => {
+                let fde = partial_fde.parse(|section, bases, cie_offset| {
+                    if let Some(cie) = &cur_cie {
+                        if S::Offset::from(cie.offset()) == cie_offset {
+                            return Ok(cie.clone());
+                        }
+                    }
+                    let cie = section.cie_from_offset(bases, cie_offset);
+                    if let Ok(cie) = &cie {
+                        cur_cie = Some(cie.clone());
+                    }
+                    cie
+                });
+
+                if let Ok(fde) = fde {
+                    function_ranges.push((
+                        fde.initial_address(),
+                        fde.initial_address() + fde.len(),
+                        fde.offset(),
+                    ));
+                }
+            }
+        }
+    }
+
+    function_ranges
+}
+
+/// Picks the FDE describing each function. The FDEs of a section, given in order of
+/// preference, are dropped if they overlap a function described by a preferred
+/// section. Returns the start address, end address, section index and offset of the
+/// picked FDEs, sorted by start address.
+fn merge_fdes(fdes_by_source: Vec<Vec<(u64, u64, usize)>>) -> Vec<(u64, u64, usize, usize)> {
+    let mut merged_fdes = Vec::new();
+    // Sorted and disjoint address ranges described by the sections merged so far.
+    let mut covered_ranges: Vec<(u64, u64)> = Vec::new();
+
+    for (source_index, fdes) in fdes_by_source.into_iter().enumerate() {
+        let mut source_ranges = Vec::with_capacity(fdes.len());
+        for (start, end, offset) in fdes {
+            let preceding =
+                covered_ranges.partition_point(|(covered_start, _)| *covered_start < end);
+            if preceding > 0 && covered_ranges[preceding - 1].1 > start {
+                continue;
+            }
+            source_ranges.push((start, end));
+            merged_fdes.push((start, end, source_index, offset));
+        }
+
+        covered_ranges.extend(source_ranges);
+        covered_ranges.sort_unstable();
+        covered_ranges = covered_ranges.into_iter().fold(
+            Vec::new(),
+            |mut ranges: Vec<(u64, u64)>, (start, end)| {
+                match ranges.last_mut() {
+                    Some((_, last_end)) if start <= *last_end => *last_end = end.max(*last_end),
+                    _ => ranges.push((start, end)),
+                }
+                ranges
+            },
+        );
+    }
+
+    merged_fdes.sort_by_key(|(start, _, source_index, _)| (*start, *source_index));
+    merged_fdes
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers {
+    frame_pointer: Register,
+    stack_pointer: Register,
+    instruction_pointer: Option<Register>,
+}
+
 pub enum UnwindData {
     // Initial, end addresses
     Function(u64, u64),
@@ -37,6 +274,7 @@ pub enum UnwindData {
 // Ideally this interface should do most of the preparatory work in the
 // constructor but this is complicated by the various lifetimes.
 pub struct CompactUnwindInfoBuilder<'a> {
+    path: PathBuf,
     mmap: Mmap,
     callback: Box<dyn FnMut(&UnwindData) + 'a>,
     first_frame_override: Option<(u64, u64)>,
@@ -52,6 +290,7 @@ impl<'a> CompactUnwindInfoBuilder<'a> {
         let mmap = unsafe { memmap2::Mmap::map(&in_file)? };
 
         Ok(Self {
+            path: PathBuf::from(path),
             mmap,
             callback: Box::new(callback),
             first_frame_override,
@@ -64,92 +303,151 @@ impl<'a> CompactUnwindInfoBuilder<'a> {
         let object_file = object::File::parse(&self.mmap[..])
             .map_err(|e| UnwindInfoError::ParsingObjectFile(e.to_string()))?;
 
-        let eh_frame_section = object_file
-            .section_by_name(".eh_frame")
-            .ok_or(UnwindInfoError::NoEhFrameSection)?;
-
         let text = object_file
             .section_by_name(".text")
             .ok_or(UnwindInfoError::NoTextSection)?;
 
-        let bases = gimli::BaseAddresses::default()
-            .set_eh_frame(eh_frame_section.address())
-            .set_text(text.address());
-
         let endian = if object_file.is_little_endian() {
             gimli::RunTimeEndian::Little
         } else {
             gimli::RunTimeEndian::Big
         };
 
-        let eh_frame_data = &eh_frame_section.uncompressed_data()?;
-
-        let mut eh_frame = EhFrame::new(eh_frame_data, endian);
-        if object_file.architecture() == Architecture::Aarch64 {
-            eh_frame.set_vendor(gimli::Vendor::AArch64);
-        }
-        let mut entries_iter = eh_frame.entries(&bases);
-
-        let mut cur_cie = None;
-        let mut pc_and_fde_offset = Vec::new();
-
-        let frame_pointer = if object_file.architecture() == Architecture::Aarch64 {
-            ARM64_FP
-        } else {
-            X86_FP
-        };
-        let stack_pointer = if object_file.architecture() == Architecture::Aarch64 {
-            ARM64_SP
+        let registers = if object_file.architecture() == Architecture::Aarch64 {
+            Registers {
+                frame_pointer: ARM64_FP,
+                stack_pointer: ARM64_SP,
+                instruction_pointer: None,
+            }
         } else {
-            X86_SP
+            Registers {
+                frame_pointer: X86_FP,
+                stack_pointer: X86_SP,
+                instruction_pointer: Some(X86_IP),
+            }
         };
 
-        while let Ok(Some(entry)) = entries_iter.next() {
-            match entry {
-                CieOrFde::Cie(cie) => {
-                    cur_cie = Some(cie);
-                }
-                CieOrFde::Fde(partial_fde) => {
-                    let fde = partial_fde.parse(|eh_frame, bases, cie_offset| {
-                        if let Some(cie) = &cur_cie {
-                            if cie.offset() == cie_offset.0 {
-                                return Ok(cie.clone());
-                            }
-                        }
-                        let cie = eh_frame.cie_from_offset(bases, cie_offset);
-                        if let Ok(cie) = &cie {
-                            cur_cie = Some(cie.clone());
-                        }
-                        cie
-                    });
+        // Distro executables often have their unwind information stripped into a
+        // separate debug file, and `.eh_frame` might not describe every function.
+        let (root, path) = split_procfs_root(&self.path);
+        let debug_file_mmap = find_debug_file(&root, &path, &object_file).and_then(|path| {
+            debug!("using separate debug file {}", path.display());
+            let file = File::open(path).ok()?;
+            unsafe { memmap2::Mmap::map(&file) }.ok()
+        });
+        let debug_object_file = debug_file_mmap
+            .as_ref()
+            .and_then(|mmap| object::File::parse(&mmap[..]).ok());
+
+        // The data has to outlive the sources, as it might have been decompressed.
+        let mut sections = frame_sections(&object_file)?;
+        if let Some(debug_object_file) = &debug_object_file {
+            match frame_sections(debug_object_file) {
+                Ok(debug_sections) => sections.extend(debug_sections),
+                Err(e) => debug!("could not read the separate debug file sections: {e}"),
+            }
+        }
 
-                    if let Ok(fde) = fde {
-                        pc_and_fde_offset.push((fde.initial_address(), fde.offset()));
+        let mut sources = Vec::with_capacity(sections.len());
+        for (kind, address, data) in &sections {
+            let bases = gimli::BaseAddresses::default().set_text(text.address());
+            let source = match kind {
+                FrameSectionKind::EhFrame => {
+                    let mut eh_frame = EhFrame::new(data, endian);
+                    if object_file.architecture() == Architecture::Aarch64 {
+                        eh_frame.set_vendor(gimli::Vendor::AArch64);
                     }
+                    FrameSection::EhFrame(eh_frame, bases.set_eh_frame(*address))
                 }
-            }
+                FrameSectionKind::DebugFrame => {
+                    let mut debug_frame = DebugFrame::new(data, endian);
+                    if object_file.architecture() == Architecture::Aarch64 {
+                        debug_frame.set_vendor(gimli::Vendor::AArch64);
+                    }
+                    FrameSection::DebugFrame(debug_frame, bases)
+                }
+            };
+            sources.push(source);
+        }
+
+        if sources.is_empty() {
+            return Err(UnwindInfoError::NoUnwindInfoSection.into());
         }
 
-        {
+        let fdes_by_source = sources
+            .iter()
+            .map(|source| match source {
+                FrameSection::EhFrame(eh_frame, bases) => function_ranges(eh_frame, bases),
+                FrameSection::DebugFrame(debug_frame, bases) => function_ranges(debug_frame, bases),
+            })
+            .collect();
+
+        // Functions described by more than one section are only added once, using the
+        // information of the preferred section.
+        let merged_fdes = {
             let _span = span!(Level::DEBUG, "sort pc and fdes").entered();
-            pc_and_fde_offset.sort_by_key(|(pc, _)| *pc);
+            merge_fdes(fdes_by_source)
+        };
+
+        // Process the consecutive functions of each section together.
+        let mut remaining = &merged_fdes[..];
+        while let Some((_, _, source_index, _)) = remaining.first() {
+            let run_len = remaining
+                .iter()
+                .take_while(|(_, _, other_source_index, _)| other_source_index == source_index)
+                .count();
+            let fde_offsets: Vec<usize> = remaining[..run_len]
+                .iter()
+                .map(|(_, _, _, offset)| *offset)
+                .collect();
+            match &sources[*source_index] {
+                FrameSection::EhFrame(eh_frame, bases) => Self::process_fdes(
+                    self.callback.as_mut(),
+                    self.first_frame_override,
+                    eh_frame,
+                    bases,
+                    &fde_offsets,
+                    &registers,
+                )?,
+                FrameSection::DebugFrame(debug_frame, bases) => Self::process_fdes(
+                    self.callback.as_mut(),
+                    self.first_frame_override,
+                    debug_frame,
+                    bases,
+                    &fde_offsets,
+                    &registers,
+                )?,
+            }
+            remaining = &remaining[run_len..];
         }
 
+        Ok(())
+    }
+
+    fn process_fdes<'data, S: UnwindSection<EndianSlice<'data, RunTimeEndian>>>(
+        callback: &mut dyn FnMut(&UnwindData),
+        first_frame_override: Option<(u64, u64)>,
+        section: &S,
+        bases: &BaseAddresses,
+        fde_offsets: &[usize],
+        registers: &Registers,
+    ) -> Result<(), anyhow::Error> {
+        let Registers {
+            frame_pointer,
+            stack_pointer,
+            instruction_pointer,
+        } = *registers;
+
         let mut ctx = Box::new(UnwindContext::new());
-        for (_, fde_offset) in pc_and_fde_offset {
-            let fde = eh_frame.fde_from_offset(
-                &bases,
-                gimli::EhFrameOffset(fde_offset),
-                EhFrame::cie_from_offset,
-            )?;
-
-            (self.callback)(&UnwindData::Function(
+        for fde_offset in fde_offsets {
+            let fde = section.fde_from_offset(bases, (*fde_offset).into(), S::cie_from_offset)?;
+
+            callback(&UnwindData::Function(
                 fde.initial_address(),
                 fde.initial_address() + fde.len(),
             ));
 
-            let mut table = fde.rows(&eh_frame, &bases, &mut ctx)?;
-
+            let mut table = fde.rows(section, bases, &mut ctx)?;
             loop {
                 let mut compact_row = CompactUnwindRow::default();
 
@@ -179,18 +477,15 @@ impl<'a> CompactUnwindInfoBuilder<'a> {
                             CfaRule::Expression(exp) => {
                                 compact_row.cfa_type = CfaType::UnsupportedExpression;
 
-                                if let Ok(expression) = exp.get(&eh_frame) {
+                                if let Ok(expression) = exp.get(section) {
                                     let expression_data = expression.0.slice();
                                     if expression_data == *PLT1 {
                                         compact_row.cfa_type = CfaType::Plt1;
                                     } else if expression_data == *PLT2 {
                                         compact_row.cfa_type = CfaType::Plt2;
                                     } else {
-                                        let mut ops = expression.operations(Encoding {
-                                            format: Format::Dwarf64,
-                                            version: 4,
-                                            address_size: 8,
-                                        });
+                                        let mut ops =
+                                            expression.clone().operations(EXPRESSION_ENCODING);
 
                                         match (ops.next(), ops.next(), ops.next(), ops.next()) {
                                             (
@@ -209,7 +504,24 @@ impl<'a> CompactUnwindInfoBuilder<'a> {
                                                 compact_row.cfa_offset =
                                                     ((offset as u16) << 8) | (addition as u16);
                                             }
-                                            _ => {}
+                                            _ => {
+                                                let id = compact_expression(
+                                                    expression,
+                                                    frame_pointer,
+                                                    stack_pointer,
+                                                    instruction_pointer,
+                                                )
+                                                .and_then(|expression| {
+                                                    CFA_EXPRESSIONS
+                                                        .lock()
+                                                        .unwrap()
+                                                        .intern(expression)
+                                                });
+                                                if let Some(id) = id {
+                                                    compact_row.cfa_type = CfaType::Expression;
+                                                    compact_row.cfa_offset = id;
+                                                }
+                                            }
                                         }
                                     }
                                 }
@@ -250,13 +562,13 @@ impl<'a> CompactUnwindInfoBuilder<'a> {
                     _ => continue,
                 }
 
-                if let Some(first_frame_override) = self.first_frame_override {
+                if let Some(first_frame_override) = first_frame_override {
                     if compact_row.pc == first_frame_override.0 {
                         compact_row = CompactUnwindRow::stop_unwinding(compact_row.pc);
                     }
                 }
 
-                (self.callback)(&UnwindData::Instruction(compact_row));
+                callback(&UnwindData::Instruction(compact_row));
             }
         }
         Ok(())
@@ -325,3 +637,141 @@ pub fn compact_unwind_info(
 
     Ok(unwind_info)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use gimli::{constants::*, EndianSlice, LittleEndian};
+
+    use super::*;
+
+    fn op(opcode: CfaExpressionOpcode, operand: i32) -> CfaExpressionOp {
+        CfaExpressionOp { opcode, operand }
+    }
+
+    /// Start and end addresses of the functions with unwind information.
+    fn described_functions(path: &str) -> Vec<(u64, u64)> {
+        let mut functions = Vec::new();
+        CompactUnwindInfoBuilder::with_callback(path, None, |unwind_data| {
+            if let UnwindData::Function(start, end) = unwind_data {
+                functions.push((*start, *end));
+            }
+        })
+        .unwrap()
+        .process()
+        .unwrap();
+        functions
+    }
+
+    // `middle`, `_start` and `leaf`, see tests/testprogs/src/debug_frame.
+    const DEBUG_FRAME_FUNCTIONS: [(u64, u64); 3] = [
+        (0x401000, 0x401011),
+        (0x401011, 0x401028),
+        (0x401028, 0x401031),
+    ];
+
+    #[test]
+    fn test_debug_frame_only() {
+        assert_eq!(
+            described_functions("tests/testdata/debug_frame_c_gcc_O1"),
+            DEBUG_FRAME_FUNCTIONS
+        );
+    }
+
+    #[test]
+    fn test_separate_debug_file() {
+        // `leaf` is only described by the `.debug_frame` section of the debug file.
+        assert_eq!(
+            described_functions("tests/testdata/split_debug_c_gcc_O1"),
+            DEBUG_FRAME_FUNCTIONS
+        );
+    }
+
+    #[test]
+    fn test_find_debug_file() {
+        let executable = fs::read("tests/testdata/split_debug_c_gcc_O1").unwrap();
+        let object_file = object::File::parse(&executable[..]).unwrap();
+        let root = tempfile::TempDir::new().unwrap();
+        let path = Path::new("/usr/bin/split_debug_c_gcc_O1");
+        assert_eq!(find_debug_file(root.path(), path, &object_file), None);
+
+        // By build id.
+        let build_id_directory = root.path().join("usr/lib/debug/.build-id/01");
+        fs::create_dir_all(&build_id_directory).unwrap();
+        let debug_file = build_id_directory.join("e319ef2077eb692ad91c65b991e3002f9f5ad8.debug");
+        fs::copy("tests/testdata/split_debug_c_gcc_O1.debug", &debug_file).unwrap();
+        assert_eq!(
+            find_debug_file(root.path(), path, &object_file),
+            Some(debug_file.clone())
+        );
+        fs::remove_file(&debug_file).unwrap();
+
+        // By `.gnu_debuglink`, if its CRC matches.
+        let directory = root.path().join("usr/bin");
+        fs::create_dir_all(&directory).unwrap();
+        let debug_file = directory.join("split_debug_c_gcc_O1.debug");
+        fs::copy("tests/testdata/debug_frame_c_gcc_O1", &debug_file).unwrap();
+        assert_eq!(find_debug_file(root.path(), path, &object_file), None);
+        fs::copy("tests/testdata/split_debug_c_gcc_O1.debug", &debug_file).unwrap();
+        assert_eq!(
+            find_debug_file(root.path(), path, &object_file),
+            Some(debug_file)
+        );
+    }
+
+    #[test]
+    fn test_merge_fdes() {
+        let eh_frame = vec![(0x100, 0x200, 1), (0x300, 0x400, 2)];
+        let debug_frame = vec![
+            (0x50, 0x150, 3),
+            (0x80, 0x90, 4),
+            (0x200, 0x300, 5),
+            (0x350, 0x360, 6),
+        ];
+        // The `.debug_frame` FDEs overlapping an `.eh_frame` one are dropped, even
+        // if they start earlier.
+        assert_eq!(
+            merge_fdes(vec![eh_frame, debug_frame]),
+            vec![
+                (0x80, 0x90, 1, 4),
+                (0x100, 0x200, 0, 1),
+                (0x200, 0x300, 1, 5),
+                (0x300, 0x400, 0, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compact_expression() {
+        // CFA = (*(rbp - 8) & 15) + 16
+        let bytes = [
+            DW_OP_breg6.0,
+            0x78, // -8
+            DW_OP_deref.0,
+            DW_OP_lit15.0,
+            DW_OP_and.0,
+            DW_OP_plus_uconst.0,
+            16,
+        ];
+        let expression = Expression(EndianSlice::new(&bytes, LittleEndian));
+        assert_eq!(
+            compact_expression(expression, X86_FP, X86_SP, Some(X86_IP)),
+            CfaExpression::new(&[
+                op(CfaExpressionOpcode::BregFp, -8),
+                op(CfaExpressionOpcode::Deref, 0),
+                op(CfaExpressionOpcode::Lit, 15),
+                op(CfaExpressionOpcode::And, 0),
+                op(CfaExpressionOpcode::PlusUconst, 16),
+            ])
+        );
+
+        // Comparisons aren't supported.
+        let bytes = [DW_OP_breg7.0, 8, DW_OP_lit1.0, DW_OP_ge.0];
+        let expression = Expression(EndianSlice::new(&bytes, LittleEndian));
+        assert_eq!(
+            compact_expression(expression, X86_FP, X86_SP, Some(X86_IP)),
+            None
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{BTreeSet, HashMap};
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
@@ -10,12 +11,12 @@ use ring::digest::{Context, SHA256};
 use thiserror::Error;
 
 use crate::unwind_info::compact_unwind_info;
-use crate::unwind_info::types::CompactUnwindRow;
+use crate::unwind_info::types::{CfaExpression, CfaType, CompactUnwindRow, CFA_EXPRESSIONS};
 
 // To identify this binary file type.
 const MAGIC_NUMBER: u32 = 0x1357531;
 // Any changes to the ABI / digest must bump the version.
-const VERSION: u32 = 2;
+const VERSION: u32 = 3;
 
 type UnwindInformationDigest = u64;
 
@@ -37,6 +38,16 @@ struct Header {
     // that is checked on the read path.
     unwind_info_digest: UnwindInformationDigest,
     unwind_info_len: u64,
+    cfa_expressions_len: u64,
+}
+
+/// Expression ids are only valid within a run, so the expressions referenced by
+/// the unwind information are stored after it, along with the id they had.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C, packed)]
+struct PersistedCfaExpression {
+    id: u16,
+    expression: CfaExpression,
 }
 
 /// SAFETY: Using packed C representation, which plain needs, and there is
@@ -47,6 +58,10 @@ unsafe impl Plain for Header {}
 /// the extra safety layer of the unwind information digest checked in the
 /// read path, in case the data is corrupted.
 unsafe impl Plain for CompactUnwindRow {}
+/// SAFETY: Using packed C representation, which plain needs, and there is
+/// the extra safety layer of the unwind information digest checked in the
+/// read path, in case the data is corrupted.
+unsafe impl Plain for PersistedCfaExpression {}
 
 /// Writes compact information to a given writer.
 pub struct Writer {
@@ -67,12 +82,18 @@ impl Writer {
         writer: &mut W,
     ) -> Result<Vec<CompactUnwindRow>, WriterError> {
         let unwind_info = self.read_unwind_info(self.first_frame_override)?;
+        let cfa_expressions = Self::cfa_expressions(&unwind_info)?;
         // Write dummy header.
-        self.write_header(writer, 0, None)?;
-        let digest = self.write_unwind_info(writer, &unwind_info)?;
+        self.write_header(writer, 0, 0, None)?;
+        let digest = self.write_unwind_info(writer, &unwind_info, &cfa_expressions)?;
         // Write real header.
         writer.seek(SeekFrom::Start(0))?;
-        self.write_header(writer, unwind_info.len(), Some(digest))?;
+        self.write_header(
+            writer,
+            unwind_info.len(),
+            cfa_expressions.len(),
+            Some(digest),
+        )?;
         Ok(unwind_info)
     }
 
@@ -87,19 +108,48 @@ impl Writer {
         .map_err(|e| WriterError::UnwindInfoGeneric(e.to_string()))
     }
 
+    /// The expressions referenced by the unwind information.
+    fn cfa_expressions(
+        unwind_info: &[CompactUnwindRow],
+    ) -> Result<Vec<PersistedCfaExpression>, WriterError> {
+        let ids: BTreeSet<u16> = unwind_info
+            .iter()
+            .filter(|row| row.cfa_type == CfaType::Expression)
+            .map(|row| row.cfa_offset)
+            .collect();
+
+        let cfa_expressions = CFA_EXPRESSIONS.lock().unwrap();
+        ids.into_iter()
+            .map(|id| {
+                let expression = cfa_expressions.get(id).ok_or_else(|| {
+                    WriterError::UnwindInfoGeneric(format!("unknown cfa expression {id}"))
+                })?;
+                Ok(PersistedCfaExpression {
+                    id,
+                    expression: *expression,
+                })
+            })
+            .collect()
+    }
+
     fn write_header(
         &self,
         writer: &mut impl Write,
         unwind_info_len: usize,
+        cfa_expressions_len: usize,
         digest: Option<UnwindInformationDigest>,
     ) -> Result<(), WriterError> {
+        let to_u64 = |len: usize| {
+            u64::try_from(len).map_err(|e: std::num::TryFromIntError| {
+                WriterError::UnwindInfoGeneric(e.to_string())
+            })
+        };
         let header = Header {
             magic: MAGIC_NUMBER,
             version: VERSION,
             unwind_info_digest: digest.unwrap_or(0),
-            unwind_info_len: unwind_info_len.try_into().map_err(
-                |e: std::num::TryFromIntError| WriterError::UnwindInfoGeneric(e.to_string()),
-            )?,
+            unwind_info_len: to_u64(unwind_info_len)?,
+            cfa_expressions_len: to_u64(cfa_expressions_len)?,
         };
         writer.write_all(unsafe { plain::as_bytes(&header) })?;
         Ok(())
@@ -109,6 +159,7 @@ impl Writer {
         &self,
         writer: &mut impl Write,
         unwind_info: &[CompactUnwindRow],
+        cfa_expressions: &[PersistedCfaExpression],
     ) -> Result<UnwindInformationDigest, WriterError> {
         let mut context = Context::new(&SHA256);
 
@@ -118,6 +169,12 @@ impl Writer {
             writer.write_all(unwind_row_data)?;
         }
 
+        for cfa_expression in cfa_expressions {
+            let cfa_expression_data = unsafe { plain::as_bytes(cfa_expression) };
+            context.update(cfa_expression_data);
+            writer.write_all(cfa_expression_data)?;
+        }
+
         let mut buffer = [0; 8];
         let _ = context.finish().as_ref().read(&mut buffer)?;
 
@@ -203,6 +260,28 @@ impl<'a> Reader<'a> {
             unwind_info.push(unwind_row);
         }
 
+        let cfa_expressions_len: usize = self
+            .header
+            .cfa_expressions_len
+            .try_into()
+            .map_err(|_| ReaderError::SizeConversion)?;
+        let cfa_expression_size = std::mem::size_of::<PersistedCfaExpression>();
+        let cfa_expressions_data = &unwind_info_data[unwind_info_len * unwind_row_size..];
+        let mut cfa_expressions = Vec::with_capacity(cfa_expressions_len);
+        let mut cfa_expression = PersistedCfaExpression::default();
+        for i in 0..cfa_expressions_len {
+            let step = i * cfa_expression_size;
+            let cfa_expression_data = cfa_expressions_data
+                .get(step..step + cfa_expression_size)
+                .ok_or(ReaderError::OutOfRange)?;
+            if self.check_digest {
+                context.update(cfa_expression_data);
+            }
+            plain::copy_from_bytes(&mut cfa_expression, cfa_expression_data)
+                .map_err(|e| ReaderError::Generic(format!("{e:?}")))?;
+            cfa_expressions.push(cfa_expression);
+        }
+
         if self.check_digest {
             let mut buffer = [0; 8];
             let _ = context
@@ -217,6 +296,32 @@ impl<'a> Reader<'a> {
             }
         }
 
+        // Ids found in the file to the ones of this run.
+        let cfa_expression_ids: HashMap<u16, Option<u16>> = {
+            let mut registry = CFA_EXPRESSIONS.lock().unwrap();
+            cfa_expressions
+                .iter()
+                .map(|cfa_expression| {
+                    (
+                        cfa_expression.id,
+                        registry.intern(cfa_expression.expression),
+                    )
+                })
+                .collect()
+        };
+
+        for unwind_row in unwind_info
+            .iter_mut()
+            .filter(|row| row.cfa_type == CfaType::Expression)
+        {
+            match cfa_expression_ids.get(&unwind_row.cfa_offset) {
+                Some(Some(id)) => unwind_row.cfa_offset = *id,
+                // There's no space left for more expressions.
+                Some(None) => unwind_row.cfa_type = CfaType::UnsupportedExpression,
+                None => return Err(ReaderError::OutOfRange),
+            }
+        }
+
         Ok(unwind_info)
     }
 }
@@ -315,6 +420,7 @@ mod tests {
             magic: MAGIC_NUMBER,
             unwind_info_len: 4,
             unwind_info_digest: 0x0,
+            cfa_expressions_len: 0,
         };
         buffer
             .write_all(unsafe { plain::as_bytes(&header) })
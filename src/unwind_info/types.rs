@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
 use lazy_static::lazy_static;
+use tracing::warn;
 
 // Important: Any changes to the structures below must bump the file
 // version in unwind_info/persist.rs
@@ -17,6 +21,8 @@ pub enum CfaType {
     EndFdeMarker = 7,
     UnsupportedRegisterOffset = 8,
     OffsetDidNotFit = 9,
+    /// The CFA is computed by the [`CfaExpression`] whose id is stored in the offset.
+    Expression = 10,
 }
 
 #[repr(u8)]
@@ -61,7 +67,150 @@ impl CompactUnwindRow {
     }
 }
 
+/// Maximum number of operations in a [`CfaExpression`].
+pub const MAX_CFA_EXPRESSION_OPS: usize = 8;
+/// Maximum number of distinct [`CfaExpression`]s.
+pub const MAX_CFA_EXPRESSIONS: usize = 1024;
+
+/// Operations of the compact bytecode the BPF unwinder evaluates. Each pops its
+/// operands from, and pushes its result to, a stack, like DWARF expressions do.
+#[repr(u8)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CfaExpressionOpcode {
+    #[default]
+    Invalid = 0,
+    /// Pushes the stack pointer plus the operand.
+    BregSp = 1,
+    /// Pushes the frame pointer plus the operand.
+    BregFp = 2,
+    /// Pushes the instruction pointer plus the operand.
+    BregIp = 3,
+    /// Replaces the top of the stack with the 8 bytes it points to.
+    Deref = 4,
+    /// Adds the operand to the top of the stack.
+    PlusUconst = 5,
+    And = 6,
+    /// Pushes the operand.
+    Lit = 7,
+    /// Shifts the second value of the stack to the left by the top one.
+    Shl = 8,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C, packed)]
+pub struct CfaExpressionOp {
+    pub opcode: CfaExpressionOpcode,
+    pub operand: i32,
+}
+
+/// A DWARF expression that computes the CFA, converted to a compact bytecode.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C, packed)]
+pub struct CfaExpression {
+    pub len: u8,
+    pub ops: [CfaExpressionOp; MAX_CFA_EXPRESSION_OPS],
+}
+
+impl CfaExpression {
+    /// Returns `None` if there are too many operations.
+    pub fn new(ops: &[CfaExpressionOp]) -> Option<Self> {
+        if ops.is_empty() || ops.len() > MAX_CFA_EXPRESSION_OPS {
+            return None;
+        }
+
+        let mut expression = CfaExpression {
+            len: ops.len() as u8,
+            ..Default::default()
+        };
+        expression.ops[..ops.len()].copy_from_slice(ops);
+        Some(expression)
+    }
+}
+
+/// The expressions of all the executables, deduplicated. The id of an expression is
+/// its position, which is what [`CfaType::Expression`] rows store as their offset.
+///
+/// Loaded executables [`CfaExpressions::acquire`] the expressions their rows use and
+/// [`CfaExpressions::release`] them once evicted, so that the ids of the expressions
+/// no executable uses anymore can be given to new ones.
+#[derive(Debug, Default)]
+pub struct CfaExpressions {
+    expressions: Vec<CfaExpression>,
+    ids: HashMap<CfaExpression, u16>,
+    /// Number of loaded executables using each expression.
+    users: Vec<usize>,
+    /// Ids that can be reused.
+    free_ids: Vec<u16>,
+    /// Whether running out of space was logged since the last time an id was freed.
+    warned_full: bool,
+}
+
+impl CfaExpressions {
+    /// Returns the id of the expression, or `None` if there's no space left for it.
+    pub fn intern(&mut self, expression: CfaExpression) -> Option<u16> {
+        if let Some(id) = self.ids.get(&expression) {
+            return Some(*id);
+        }
+
+        let id = match self.free_ids.pop() {
+            Some(id) => {
+                self.expressions[id as usize] = expression;
+                id
+            }
+            None => {
+                if self.expressions.len() >= MAX_CFA_EXPRESSIONS {
+                    if !self.warned_full {
+                        warn!(
+                            "no space left for more than {} CFA expressions, the rows using new ones won't be unwound",
+                            MAX_CFA_EXPRESSIONS
+                        );
+                    }
+                    self.warned_full = true;
+                    return None;
+                }
+                self.expressions.push(expression);
+                self.users.push(0);
+                (self.expressions.len() - 1) as u16
+            }
+        };
+        self.ids.insert(expression, id);
+        Some(id)
+    }
+
+    pub fn get(&self, id: u16) -> Option<&CfaExpression> {
+        self.expressions.get(id as usize)
+    }
+
+    /// Marks the expressions as used by one more loaded executable.
+    pub fn acquire(&mut self, ids: &[u16]) {
+        for id in ids {
+            self.users[*id as usize] += 1;
+        }
+    }
+
+    /// Marks the expressions as no longer used by an executable, freeing the ids of
+    /// the ones that aren't used anymore.
+    pub fn release(&mut self, ids: &[u16]) {
+        for id in ids {
+            let users = &mut self.users[*id as usize];
+            if *users == 0 {
+                continue;
+            }
+            *users -= 1;
+            if *users == 0 {
+                self.ids.remove(&self.expressions[*id as usize]);
+                self.free_ids.push(*id);
+                self.warned_full = false;
+            }
+        }
+    }
+}
+
 lazy_static! {
+    /// Expression ids aren't stable across runs, so the unwind information persisted
+    /// to disk stores the expressions themselves.
+    pub static ref CFA_EXPRESSIONS: Mutex<CfaExpressions> = Mutex::new(CfaExpressions::default());
+
     pub static ref PLT1: [u8; 11] = [
         gimli::constants::DW_OP_breg7,
         gimli::constants::DW_OP_const1u,
@@ -95,7 +244,51 @@ lazy_static! {
 // > Figure 3.36: DWARF Register Number Mapping
 pub const X86_FP: gimli::Register = gimli::Register(6); // Frame Pointer ($rbp)
 pub const X86_SP: gimli::Register = gimli::Register(7); // Stack Pointer ($rsp)
+pub const X86_IP: gimli::Register = gimli::Register(16); // Return Address ($rip)
 
 // Source: https://github.com/ARM-software/abi-aa/blob/05abf4f7/aadwarf64/aadwarf64.rst#41dwarf-register-names
 pub const ARM64_FP: gimli::Register = gimli::Register(29); // Frame Pointer (x29)
 pub const ARM64_SP: gimli::Register = gimli::Register(31); // Stack Pointer (sp)
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expression(operand: i32) -> CfaExpression {
+        CfaExpression::new(&[CfaExpressionOp {
+            opcode: CfaExpressionOpcode::Lit,
+            operand,
+        }])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cfa_expressions_reuse_released_ids() {
+        let mut cfa_expressions = CfaExpressions::default();
+
+        let first = cfa_expressions.intern(expression(1)).unwrap();
+        let second = cfa_expressions.intern(expression(2)).unwrap();
+        assert_eq!(cfa_expressions.intern(expression(1)), Some(first));
+        cfa_expressions.acquire(&[first, second]);
+        cfa_expressions.acquire(&[first]);
+
+        // Still used by another executable.
+        cfa_expressions.release(&[first, second]);
+        assert_eq!(cfa_expressions.intern(expression(1)), Some(first));
+        assert_eq!(cfa_expressions.intern(expression(3)), Some(second));
+        assert_eq!(cfa_expressions.get(second), Some(&expression(3)));
+
+        cfa_expressions.release(&[first]);
+        assert_eq!(cfa_expressions.intern(expression(4)), Some(first));
+    }
+
+    #[test]
+    fn test_cfa_expressions_full() {
+        let mut cfa_expressions = CfaExpressions::default();
+
+        for operand in 0..MAX_CFA_EXPRESSIONS {
+            assert!(cfa_expressions.intern(expression(operand as i32)).is_some());
+        }
+        assert_eq!(cfa_expressions.intern(expression(-1)), None);
+    }
+}
@@ -2,6 +2,7 @@ use crate::process::Pid;
 use std::fs;
 use std::io;
 use std::os::unix::fs::MetadataExt;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -52,6 +53,34 @@ pub fn executable_path(pid: Pid, path: &Path) -> io::Result<PathBuf> {
     Ok(procfs_path)
 }
 
+/// Splits a path returned by [`executable_path`] into the root directory of the mount
+/// namespace the executable is in and its path within it, so that other files can be
+/// looked up in the same mount namespace.
+pub fn split_procfs_root(path: &Path) -> (PathBuf, PathBuf) {
+    let mut components = path.components();
+    if let (
+        Some(Component::RootDir),
+        Some(Component::Normal(proc)),
+        Some(Component::Normal(pid)),
+        Some(Component::Normal(root)),
+    ) = (
+        components.next(),
+        components.next(),
+        components.next(),
+        components.next(),
+    ) {
+        let is_pid = pid.to_str().is_some_and(|pid| pid.parse::<Pid>().is_ok());
+        if proc == "proc" && is_pid && root == "root" {
+            return (
+                Path::new("/").join(proc).join(pid).join(root),
+                Path::new("/").join(components.as_path()),
+            );
+        }
+    }
+
+    (PathBuf::from("/"), path.to_path_buf())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -63,4 +92,23 @@ mod tests {
             FileId::new(&PathBuf::from("/")).unwrap()
         );
     }
+
+    #[test]
+    fn test_split_procfs_root() {
+        assert_eq!(
+            split_procfs_root(Path::new("/proc/1234/root/usr/bin/ls")),
+            (
+                PathBuf::from("/proc/1234/root"),
+                PathBuf::from("/usr/bin/ls")
+            )
+        );
+        assert_eq!(
+            split_procfs_root(Path::new("/usr/bin/ls")),
+            (PathBuf::from("/"), PathBuf::from("/usr/bin/ls"))
+        );
+        assert_eq!(
+            split_procfs_root(Path::new("/proc/self/exe")),
+            (PathBuf::from("/"), PathBuf::from("/proc/self/exe"))
+        );
+    }
 }
@@ -6,6 +6,6 @@ mod page;
 
 pub use arch::{architecture, Architecture};
 pub use cpu::get_online_cpus;
-pub use file::executable_path;
+pub use file::{executable_path, split_procfs_root};
 pub use lpm::{summarize_address_range, AddressBlockRange};
 pub use page::{page_size, roundup_page};
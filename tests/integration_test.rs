@@ -2,15 +2,19 @@ use std::io;
 use std::io::Write;
 use std::process::{Child, Command, Stdio};
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 
 use crossbeam_channel::bounded;
 
 use lightswitch::collector::{AggregatorCollector, Collector};
+use lightswitch::probes::UserProbe;
 use lightswitch::profile::symbolize_profile;
-use lightswitch::profile::AggregatedProfile;
-use lightswitch::profiler::{Profiler, ProfilerConfig};
+use lightswitch::profile::{AggregatedProfile, AggregatedSample, SampleKind};
+use lightswitch::profiler::{Profiler, ProfilerConfig, ProfilerMode};
 use lightswitch_metadata::metadata_provider::GlobalMetadataProvider;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 
 /// Find the `nix` binary either in the $PATH or in the below hardcoded location.
 fn nix_bin() -> String {
@@ -23,15 +27,15 @@ fn nix_bin() -> String {
     panic!("`nix` could not be found in $PATH or /nix/var/nix/profiles/default/bin/nix");
 }
 
-/// Builds the given test program and writes the resulting binaries under `target/nix` to prevent
-/// clobbering artifacts from manual builds.
+/// Builds the given test program and writes the resulting binaries under `target/nix-<target>` to
+/// prevent clobbering artifacts from manual builds or from other tests running concurrently.
 fn build_test_binary(target: &str) {
     let output = Command::new(nix_bin())
         .args([
             "build",
             &format!("./tests/testprogs#{target}"),
             "--out-link",
-            "target/nix",
+            &format!("target/nix-{target}"),
         ])
         .output()
         .expect("failed to execute process");
@@ -49,9 +53,9 @@ struct TestProcess {
 
 /// Runs a test program and terminates it when the scope exits.
 impl TestProcess {
-    fn new(target: &str) -> Self {
+    fn new(target: &str, binary: &str) -> Self {
         Self {
-            child: Command::new(format!("./target/nix/bin/{target}"))
+            child: Command::new(format!("./target/nix-{target}/bin/{binary}"))
                 .stdout(Stdio::null())
                 .stderr(Stdio::null())
                 .spawn()
@@ -95,7 +99,7 @@ fn test_integration() {
     let bpf_test_debug = std::env::var("TEST_DEBUG_BPF").is_ok();
 
     build_test_binary("cpp-progs");
-    let cpp_proc = TestProcess::new("main_cpp_clang_O1");
+    let cpp_proc = TestProcess::new("cpp-progs", "main_cpp_clang_O1");
 
     let collector = Arc::new(Mutex::new(
         Box::new(AggregatorCollector::new()) as Box<dyn Collector + Send>
@@ -112,7 +116,7 @@ fn test_integration() {
     let metadata_provider = Arc::new(Mutex::new(GlobalMetadataProvider::default()));
     let mut p = Profiler::new(profiler_config, stop_signal_receive, metadata_provider);
     p.profile_pids(vec![cpp_proc.pid()]);
-    p.run(collector.clone());
+    p.run(collector.clone()).unwrap();
     let collector = collector.lock().unwrap();
     let (raw_profile, procs, objs) = collector.finish();
     let symbolized_profile = symbolize_profile(&raw_profile, procs, objs);
@@ -129,3 +133,161 @@ fn test_integration() {
         ],
     ));
 }
+
+#[test]
+fn test_heap_profiling() {
+    let bpf_test_debug = std::env::var("TEST_DEBUG_BPF").is_ok();
+
+    build_test_binary("heap-progs");
+    let heap_proc = TestProcess::new("heap-progs", "heap_c_gcc_O1");
+    // The allocator is looked up in the process mappings, which requires the
+    // dynamic loader to be done.
+
+    let collector = Arc::new(Mutex::new(
+        Box::new(AggregatorCollector::new()) as Box<dyn Collector + Send>
+    ));
+
+    let profiler_config = ProfilerConfig {
+        libbpf_debug: bpf_test_debug,
+        bpf_logging: bpf_test_debug,
+        duration: Duration::from_secs(5),
+        mode: ProfilerMode::Heap,
+        ..Default::default()
+    };
+    let (_stop_signal_send, stop_signal_receive) = bounded(1);
+    let metadata_provider = Arc::new(Mutex::new(GlobalMetadataProvider::default()));
+    let mut p = Profiler::new(profiler_config, stop_signal_receive, metadata_provider);
+    p.profile_pids(vec![heap_proc.pid()]);
+    p.run(collector.clone()).unwrap();
+    let collector = collector.lock().unwrap();
+    let (raw_profile, procs, objs) = collector.finish();
+    let symbolized_profile = symbolize_profile(&raw_profile, procs, objs);
+    let (in_use, allocated): (Vec<AggregatedSample>, Vec<AggregatedSample>) = symbolized_profile
+        .into_iter()
+        .partition(|sample| sample.kind == SampleKind::InUse);
+
+    assert!(assert_any_stack_contains(&allocated, &["leak", "main"]));
+    assert!(assert_any_stack_contains(&allocated, &["churn", "main"]));
+    assert!(assert_any_stack_contains(&in_use, &["leak", "main"]));
+}
+
+#[test]
+fn test_user_probe_profiling() {
+    let bpf_test_debug = std::env::var("TEST_DEBUG_BPF").is_ok();
+
+    build_test_binary("heap-progs");
+    let heap_proc = TestProcess::new("heap-progs", "heap_c_gcc_O1");
+    let binary = std::fs::canonicalize("./target/nix-heap-progs/bin/heap_c_gcc_O1").unwrap();
+
+    let collector = Arc::new(Mutex::new(
+        Box::new(AggregatorCollector::new()) as Box<dyn Collector + Send>
+    ));
+
+    let profiler_config = ProfilerConfig {
+        libbpf_debug: bpf_test_debug,
+        bpf_logging: bpf_test_debug,
+        duration: Duration::from_secs(5),
+        mode: ProfilerMode::Probes,
+        probes: vec![UserProbe::Function {
+            binary,
+            symbol: "churn".into(),
+        }],
+        ..Default::default()
+    };
+    let (_stop_signal_send, stop_signal_receive) = bounded(1);
+    let metadata_provider = Arc::new(Mutex::new(GlobalMetadataProvider::default()));
+    let mut p = Profiler::new(profiler_config, stop_signal_receive, metadata_provider);
+    p.profile_pids(vec![heap_proc.pid()]);
+    p.run(collector.clone()).unwrap();
+    let collector = collector.lock().unwrap();
+    let (raw_profile, procs, objs) = collector.finish();
+    let symbolized_profile = symbolize_profile(&raw_profile, procs, objs);
+
+    assert!(assert_any_stack_contains(
+        &symbolized_profile,
+        &["churn", "main"]
+    ));
+    assert!(!assert_any_stack_contains(&symbolized_profile, &["leak"]));
+}
+
+#[test]
+fn test_contention_profiling() {
+    let bpf_test_debug = std::env::var("TEST_DEBUG_BPF").is_ok();
+
+    build_test_binary("contention-progs");
+    let contention_proc = TestProcess::new("contention-progs", "contention_c_gcc_O1");
+
+    let collector = Arc::new(Mutex::new(
+        Box::new(AggregatorCollector::new()) as Box<dyn Collector + Send>
+    ));
+
+    let profiler_config = ProfilerConfig {
+        libbpf_debug: bpf_test_debug,
+        bpf_logging: bpf_test_debug,
+        duration: Duration::from_secs(5),
+        mode: ProfilerMode::Contention,
+        ..Default::default()
+    };
+    let (_stop_signal_send, stop_signal_receive) = bounded(1);
+    let metadata_provider = Arc::new(Mutex::new(GlobalMetadataProvider::default()));
+    let mut p = Profiler::new(profiler_config, stop_signal_receive, metadata_provider);
+    p.profile_pids(vec![contention_proc.pid()]);
+    p.run(collector.clone()).unwrap();
+    let collector = collector.lock().unwrap();
+    let (raw_profile, procs, objs) = collector.finish();
+    let symbolized_profile = symbolize_profile(&raw_profile, procs, objs);
+
+    assert!(assert_any_stack_contains(
+        &symbolized_profile,
+        &["contend", "worker"]
+    ));
+    assert!(symbolized_profile
+        .iter()
+        .all(|sample| sample.kind == SampleKind::Contention
+            && sample.lock_address.is_some()
+            && sample.futex_wait.is_some()));
+}
+
+#[test]
+fn test_dlopen_after_startup() {
+    let bpf_test_debug = std::env::var("TEST_DEBUG_BPF").is_ok();
+
+    build_test_binary("dlopen-progs");
+    let dlopen_proc = TestProcess::new("dlopen-progs", "dlopen_c_gcc_O1");
+
+    let collector = Arc::new(Mutex::new(
+        Box::new(AggregatorCollector::new()) as Box<dyn Collector + Send>
+    ));
+
+    let profiler_config = ProfilerConfig {
+        libbpf_debug: bpf_test_debug,
+        bpf_logging: bpf_test_debug,
+        duration: Duration::from_secs(5),
+        sample_freq: 999,
+        ..Default::default()
+    };
+    let (_stop_signal_send, stop_signal_receive) = bounded(1);
+    let (ready_send, ready_receive) = bounded(1);
+    let metadata_provider = Arc::new(Mutex::new(GlobalMetadataProvider::default()));
+    let mut p = Profiler::new(profiler_config, stop_signal_receive, metadata_provider);
+    p.profile_pids(vec![dlopen_proc.pid()]);
+    p.notify_when_ready(ready_send);
+
+    // The library is only loaded once the profiler has read the mappings of the
+    // process, so it has to be added when it's mapped.
+    let pid = dlopen_proc.pid();
+    let load_library = thread::spawn(move || {
+        ready_receive.recv().unwrap();
+        kill(Pid::from_raw(pid), Signal::SIGUSR1).unwrap();
+    });
+    p.run(collector.clone()).unwrap();
+    load_library.join().unwrap();
+    let collector = collector.lock().unwrap();
+    let (raw_profile, procs, objs) = collector.finish();
+    let symbolized_profile = symbolize_profile(&raw_profile, procs, objs);
+
+    assert!(assert_any_stack_contains(
+        &symbolized_profile,
+        &["hot", "main"]
+    ));
+}
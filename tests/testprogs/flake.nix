@@ -110,6 +110,90 @@
             ];
           };
 
+          test-heap-progs = pkgs.stdenv.mkDerivation {
+            dontStrip = true;
+            name = "build-test-heap-prog";
+            src = ./.;
+            buildPhase = ''
+              cd src/
+
+              gcc -O1 heap.c -o heap_c_gcc_O1
+            '';
+            installPhase = ''
+              mkdir -p $out/bin
+
+              cp heap_c_gcc_O1 $out/bin
+            '';
+            buildInputs = [
+              pkgs.gcc
+            ];
+          };
+
+          test-contention-progs = pkgs.stdenv.mkDerivation {
+            dontStrip = true;
+            name = "build-test-contention-prog";
+            src = ./.;
+            buildPhase = ''
+              cd src/
+
+              gcc -O1 -pthread contention.c -o contention_c_gcc_O1
+            '';
+            installPhase = ''
+              mkdir -p $out/bin
+
+              cp contention_c_gcc_O1 $out/bin
+            '';
+            buildInputs = [
+              pkgs.gcc
+            ];
+          };
+
+          test-debug-frame-progs = pkgs.stdenv.mkDerivation {
+            dontStrip = true;
+            name = "build-test-debug-frame-prog";
+            src = ./.;
+            buildPhase = ''
+              cd src/debug_frame/
+
+              gcc -O1 -g -nostdlib -static -Wl,--build-id -fno-asynchronous-unwind-tables -fno-unwind-tables main.c leaf.c -o debug_frame_c_gcc_O1
+
+              # Only `leaf` is left without `.eh_frame` information.
+              gcc -O1 -g -c main.c -o main.o
+              gcc -O1 -g -fno-asynchronous-unwind-tables -fno-unwind-tables -c leaf.c -o leaf.o
+              gcc -nostdlib -static -Wl,--build-id main.o leaf.o -o split_debug_c_gcc_O1
+              objcopy --only-keep-debug split_debug_c_gcc_O1 split_debug_c_gcc_O1.debug
+              objcopy --strip-debug --add-gnu-debuglink=split_debug_c_gcc_O1.debug split_debug_c_gcc_O1
+            '';
+            installPhase = ''
+              mkdir -p $out/bin
+
+              cp debug_frame_c_gcc_O1 split_debug_c_gcc_O1 split_debug_c_gcc_O1.debug $out/bin
+            '';
+            buildInputs = [
+              pkgs.gcc
+            ];
+          };
+
+          test-dlopen-progs = pkgs.stdenv.mkDerivation {
+            dontStrip = true;
+            name = "build-test-dlopen-prog";
+            src = ./.;
+            buildPhase = ''
+              cd src/dlopen/
+
+              gcc -O1 -shared -fPIC hot.c -o libhot.so
+              gcc -O1 main.c -o dlopen_c_gcc_O1 -ldl
+            '';
+            installPhase = ''
+              mkdir -p $out/bin
+
+              cp dlopen_c_gcc_O1 libhot.so $out/bin
+            '';
+            buildInputs = [
+              pkgs.gcc
+            ];
+          };
+
           test-go-progs = pkgs.stdenv.mkDerivation {
             name = "build-test-go-prog";
             src = ./.;
@@ -161,6 +245,10 @@
             go-progs = test-go-progs;
             cgo-progs = test-cgo-progs;
             cpp-progs-static-musl = test-static-musl-cpp-progs;
+            heap-progs = test-heap-progs;
+            contention-progs = test-contention-progs;
+            debug-frame-progs = test-debug-frame-progs;
+            dlopen-progs = test-dlopen-progs;
           };
         }
       );
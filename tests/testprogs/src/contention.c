@@ -0,0 +1,34 @@
+#include <pthread.h>
+#include <unistd.h>
+
+#define THREADS 4
+
+static pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+static volatile unsigned long counter;
+
+// Holds the lock for a while so that the other threads have to wait for it.
+void __attribute__((noinline)) contend() {
+  pthread_mutex_lock(&lock);
+  for (int i = 0; i < 1000000; i++) {
+    counter++;
+  }
+  pthread_mutex_unlock(&lock);
+}
+
+void *__attribute__((noinline)) worker(void *arg) {
+  while (1) {
+    contend();
+  }
+  return NULL;
+}
+
+int main() {
+  pthread_t threads[THREADS];
+  for (int i = 0; i < THREADS; i++) {
+    pthread_create(&threads[i], NULL, worker, NULL);
+  }
+  for (int i = 0; i < THREADS; i++) {
+    pthread_join(threads[i], NULL);
+  }
+  return 0;
+}
@@ -0,0 +1,4 @@
+__attribute__((noinline)) int leaf(int n) {
+  volatile int result = n;
+  return result;
+}
@@ -0,0 +1,10 @@
+// Freestanding so that no unwind information comes from the C runtime.
+int leaf(int n);
+
+__attribute__((noinline)) int middle(int n) { return leaf(n) + 1; }
+
+void _start(void) {
+  int status = middle(41);
+  __asm__ volatile("syscall" : : "a"(60), "D"(status) : "rcx", "r11", "memory");
+  __builtin_unreachable();
+}
@@ -0,0 +1,7 @@
+static volatile unsigned long counter;
+
+void __attribute__((noinline)) hot() {
+  for (int i = 0; i < 1000000; i++) {
+    counter++;
+  }
+}
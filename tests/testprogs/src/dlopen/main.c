@@ -0,0 +1,41 @@
+#include <dlfcn.h>
+#include <libgen.h>
+#include <limits.h>
+#include <signal.h>
+#include <stdio.h>
+#include <unistd.h>
+
+static volatile sig_atomic_t should_load;
+
+static void on_signal(int signal) { should_load = 1; }
+
+// Only loads `libhot.so`, which sits next to this executable, once it gets a
+// SIGUSR1, so that it's mapped after the profiler has read the mappings of the
+// process.
+int main() {
+  signal(SIGUSR1, on_signal);
+  while (!should_load) {
+    pause();
+  }
+
+  char exe[PATH_MAX] = {0};
+  if (readlink("/proc/self/exe", exe, sizeof(exe) - 1) < 0) {
+    return 1;
+  }
+  char library[PATH_MAX + 16];
+  snprintf(library, sizeof(library), "%s/libhot.so", dirname(exe));
+
+  void *handle = dlopen(library, RTLD_NOW);
+  if (handle == NULL) {
+    return 1;
+  }
+  void (*hot)() = dlsym(handle, "hot");
+  if (hot == NULL) {
+    return 1;
+  }
+
+  while (1) {
+    hot();
+  }
+  return 0;
+}
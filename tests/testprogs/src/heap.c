@@ -0,0 +1,30 @@
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+// Prevents the compiler from optimising allocations away.
+static void *volatile sink;
+
+// Memory that's never freed, so it should be reported as in use.
+void __attribute__((noinline)) leak(size_t size) {
+  sink = malloc(size);
+  memset(sink, 0, size);
+}
+
+// Memory that's freed straight away, so it should only be reported as allocated.
+void __attribute__((noinline)) churn(size_t size) {
+  sink = calloc(1, size);
+  sink = realloc(sink, size * 2);
+  free(sink);
+}
+
+int main() {
+  while (1) {
+    leak(64);
+    for (int i = 0; i < 10; i++) {
+      churn(128);
+    }
+    usleep(10000);
+  }
+  return 0;
+}